@@ -1,63 +1,187 @@
 use crate::senres::{Senres, SenresMut};
 
+/// The names of every unlocked basis.
 pub struct BasisList {
-    data: crate::senres::Stack<4096>,
-    count: usize,
+    names: Vec<String>,
 }
 
 impl BasisList {
-    pub fn new(connection: u32) -> Option<Self> {
+    pub fn new(connection: u32) -> std::io::Result<Self> {
         let mut request = crate::senres::Stack::<4096>::new();
 
-        request.writer(*b"basQ")?;
+        request
+            .writer(*b"basQ")
+            .ok_or_else(|| std::io::Error::other("unable to create writer"))?;
         request
             .lend_mut(connection, crate::Opcodes::ListBasisStd as usize)
-            .unwrap();
+            .or(Err(std::io::Error::other("unable to list bases")))?;
 
-        let reader = request.reader(*b"basR").expect("unable to get reader");
-        let count = reader.try_get_from::<u32>().unwrap() as usize;
-        Some(BasisList {
-            data: request,
-            count,
-        })
+        let reader = request.reader(*b"basR").ok_or_else(invalid_response)?;
+        let count = reader.try_get_from::<u32>().or(Err(invalid_response()))?;
+        let names = (0..count)
+            .map(|_| reader.try_get_from::<String>().or(Err(invalid_response())))
+            .collect::<std::io::Result<_>>()?;
+        Ok(BasisList { names })
     }
 
     pub fn len(&self) -> usize {
-        self.count
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
     }
 
-    pub fn iter(&self) -> BasisListIter {
+    pub fn iter(&self) -> BasisListIter<'_> {
         BasisListIter::new(self)
     }
 }
 
 pub struct BasisListIter<'a> {
-    index: core::cell::Cell<usize>,
-    length: usize,
-    reader: crate::senres::Reader<'a, crate::senres::Stack<4096>>,
+    names: std::slice::Iter<'a, String>,
 }
 
 impl<'a> BasisListIter<'a> {
     pub fn new(list: &'a BasisList) -> Self {
-        let reader = list.data.reader(*b"basR").unwrap();
-        reader.try_get_from::<u32>().unwrap();
-        let length = reader.try_get_from::<u32>().unwrap() as usize;
         BasisListIter {
-            reader,
-            index: core::cell::Cell::new(0),
-            length,
+            names: list.names.iter(),
         }
     }
 }
 
-impl<'a> Iterator for &'a BasisListIter<'_> {
+impl<'a> Iterator for BasisListIter<'a> {
     type Item = &'a str;
     fn next(&mut self) -> Option<&'a str> {
-        if self.index.get() >= self.length {
-            return None;
-        }
+        self.names.next().map(|name| name.as_str())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.names.size_hint()
+    }
+}
+
+impl ExactSizeIterator for BasisListIter<'_> {}
+
+impl<'a> IntoIterator for &'a BasisList {
+    type Item = &'a str;
+    type IntoIter = BasisListIter<'a>;
+    fn into_iter(self) -> BasisListIter<'a> {
+        self.iter()
+    }
+}
+
+/// Information about a single basis, as returned by `Pddb::basis_info()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasisInfo {
+    pub(crate) name: String,
+    pub(crate) is_system: bool,
+    pub(crate) unlocked: bool,
+    pub(crate) priority: Option<u32>,
+    pub(crate) key_count: Option<u64>,
+    pub(crate) dict_count: Option<u64>,
+}
+
+impl BasisInfo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns `true` for the system basis, which is always unlocked.
+    pub fn is_system(&self) -> bool {
+        self.is_system
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.unlocked
+    }
+
+    /// The position of this basis in the union, where `0` is consulted
+    /// first. Locked bases are not part of the union and have no priority.
+    pub fn priority(&self) -> Option<u32> {
+        self.priority
+    }
+
+    /// The number of keys in this basis, if the server was able to count them.
+    pub fn key_count(&self) -> Option<u64> {
+        self.key_count
+    }
+
+    /// The number of dicts in this basis, if the server was able to count them.
+    pub fn dict_count(&self) -> Option<u64> {
+        self.dict_count
+    }
+
+    pub(crate) fn read_from(
+        reader: &crate::senres::Reader<'_, crate::senres::Stack<4096>>,
+    ) -> Option<Self> {
+        Some(BasisInfo {
+            name: reader.try_get_from::<String>().ok()?,
+            is_system: reader.try_get_from::<bool>().ok()?,
+            unlocked: reader.try_get_from::<bool>().ok()?,
+            priority: reader.try_get_from::<Option<u32>>().ok()?,
+            key_count: reader.try_get_from::<Option<u64>>().ok()?,
+            dict_count: reader.try_get_from::<Option<u64>>().ok()?,
+        })
+    }
+}
+
+/// Every basis known to the server, both locked and unlocked, in
+/// union priority order with locked bases last.
+pub struct BasisInfoList {
+    entries: Vec<BasisInfo>,
+}
 
-        self.index.set(self.index.get() + 1);
-        self.reader.try_get_ref_from().ok()
+impl BasisInfoList {
+    pub fn new(connection: u32) -> std::io::Result<Self> {
+        let mut request = crate::senres::Stack::<4096>::new();
+
+        request
+            .writer(*b"BinQ")
+            .ok_or_else(|| std::io::Error::other("unable to create writer"))?;
+        request
+            .lend_mut(connection, crate::Opcodes::ListBasisInfo as usize)
+            .or(Err(std::io::Error::other("unable to list bases")))?;
+
+        let reader = request.reader(*b"BinR").ok_or_else(invalid_response)?;
+        let count = reader.try_get_from::<u32>().or(Err(invalid_response()))?;
+        let entries = (0..count)
+            .map(|_| BasisInfo::read_from(&reader).ok_or_else(invalid_response))
+            .collect::<std::io::Result<_>>()?;
+        Ok(BasisInfoList { entries })
     }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, BasisInfo> {
+        self.entries.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a BasisInfoList {
+    type Item = &'a BasisInfo;
+    type IntoIter = std::slice::Iter<'a, BasisInfo>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+impl IntoIterator for BasisInfoList {
+    type Item = BasisInfo;
+    type IntoIter = std::vec::IntoIter<BasisInfo>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+fn invalid_response() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "invalid response from server",
+    )
 }
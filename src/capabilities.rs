@@ -0,0 +1,176 @@
+//! Discovering what the PDDB server supports.
+//!
+//! Newer servers answer a `Capabilities` query with the versions of the
+//! messages they understand and a set of optional features. `Pddb::connect()`
+//! asks once and the answer is used to pick which messages to send.
+//!
+//! Servers that predate the query don't answer it. In that case nothing is
+//! known, and the client only sends the messages the firmware understands.
+//! Operations that need a feature the server didn't report return
+//! `ErrorKind::Unsupported` without sending anything.
+
+use crate::senres::{Senres, SenresMut};
+
+#[cfg(test)]
+mod tests;
+
+/// The version of the capability query itself.
+pub(crate) const CAPABILITIES_VERSION: u32 = 1;
+
+/// Optional features a server may support.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Features(u32);
+
+impl Features {
+    /// Keys can be listed a page at a time with `LskQ`.
+    pub const PAGED_KEY_LIST: Features = Features(1 << 0);
+    /// Several pages can be read or written with one message.
+    pub const MULTI_PAGE_IO: Features = Features(1 << 1);
+    /// Keys, dicts and bases can be described with `stat()` and `Key::metadata()`.
+    pub const METADATA: Features = Features(1 << 2);
+    /// Path listings can include entry flags and sizes.
+    pub const PATH_FLAGS: Features = Features(1 << 3);
+    /// Keys can be opened with a senres `OpkQ` message.
+    pub const SENRES_OPEN_KEY: Features = Features(1 << 4);
+    /// Keys can be grown or shrunk with `Key::set_len()`.
+    pub const SET_LEN: Features = Features(1 << 5);
+    /// Keys can be renamed and copied on the server.
+    pub const RENAME_COPY_KEY: Features = Features(1 << 6);
+    /// Dicts can be renamed on the server.
+    pub const RENAME_DICT: Features = Features(1 << 7);
+    /// Bases can be created, unlocked, locked, deleted, described and reordered.
+    pub const BASIS_MANAGEMENT: Features = Features(1 << 8);
+    /// Dicts can be listed a page at a time with `LsdQ`.
+    pub const PAGED_DICT_LIST: Features = Features(1 << 9);
+
+    /// Every feature this client knows about.
+    pub const ALL: Features = Features(0x3ff);
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Convert the features sent by the server. Features that this client
+    /// doesn't know about are dropped.
+    pub fn from_bits_truncate(bits: u32) -> Self {
+        Features(bits & Self::ALL.0)
+    }
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl core::ops::BitOr for Features {
+    type Output = Features;
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitOrAssign for Features {
+    fn bitor_assign(&mut self, rhs: Features) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl core::fmt::Debug for Features {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let names = [
+            (Features::PAGED_KEY_LIST, "PagedKeyList"),
+            (Features::MULTI_PAGE_IO, "MultiPageIo"),
+            (Features::METADATA, "Metadata"),
+            (Features::PATH_FLAGS, "PathFlags"),
+            (Features::SENRES_OPEN_KEY, "SenresOpenKey"),
+            (Features::SET_LEN, "SetLen"),
+            (Features::RENAME_COPY_KEY, "RenameCopyKey"),
+            (Features::RENAME_DICT, "RenameDict"),
+            (Features::BASIS_MANAGEMENT, "BasisManagement"),
+            (Features::PAGED_DICT_LIST, "PagedDictList"),
+        ];
+        let mut first = true;
+        for (feature, name) in names {
+            if self.contains(feature) {
+                if !first {
+                    write!(f, " | ")?;
+                }
+                write!(f, "{}", name)?;
+                first = false;
+            }
+        }
+        if first {
+            write!(f, "(empty)")?;
+        }
+        Ok(())
+    }
+}
+
+/// What a server reported in reply to the capability query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    version: u32,
+    open_key_version: u32,
+    features: Features,
+}
+
+impl Capabilities {
+    pub fn new(version: u32, open_key_version: u32, features: Features) -> Self {
+        Capabilities {
+            version,
+            open_key_version,
+            features,
+        }
+    }
+
+    /// The version of the capability query the server answered with.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The newest version of `OpenKeyRequest` the server understands.
+    pub fn open_key_version(&self) -> u32 {
+        self.open_key_version
+    }
+
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    pub fn supports(&self, feature: Features) -> bool {
+        self.features.contains(feature)
+    }
+
+    /// Ask the server what it supports. Returns `None` if the server
+    /// doesn't understand the query.
+    pub(crate) fn query(connection: u32) -> Option<Self> {
+        let mut request = crate::senres::Stack::<4096>::new();
+        {
+            let mut writer = request.writer(*b"CapQ")?;
+            writer.append(CAPABILITIES_VERSION);
+        }
+        request
+            .lend_mut(connection, crate::Opcodes::Capabilities as usize)
+            .ok()?;
+        let reader = request.reader(*b"CapR")?;
+        Self::read_from(&reader)
+    }
+
+    pub(crate) fn read_from<B: Senres>(reader: &crate::senres::Reader<'_, B>) -> Option<Self> {
+        Some(Capabilities {
+            version: reader.try_get_from().ok()?,
+            open_key_version: reader.try_get_from().ok()?,
+            features: Features::from_bits_truncate(reader.try_get_from().ok()?),
+        })
+    }
+
+    #[cfg(not(target_os = "xous"))]
+    pub(crate) fn write_to<B: SenresMut>(&self, writer: &mut crate::senres::Writer<'_, B>) {
+        writer.append(self.version);
+        writer.append(self.open_key_version);
+        writer.append(self.features.bits());
+    }
+}
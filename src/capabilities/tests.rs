@@ -0,0 +1,295 @@
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+use super::{Capabilities, Features, CAPABILITIES_VERSION};
+use crate::key::{KeyOptions, OPEN_KEY_VERSION, PAGE_DATA_LEN};
+use crate::mock::{MockPddb, SYSTEM_BASIS};
+
+fn mock() -> MockPddb {
+    let mock = MockPddb::new();
+    mock.insert_key(None, "sys.rtc", "tz_offset", &[1, 2, 3, 4]);
+    mock
+}
+
+fn without(feature: Features) -> Capabilities {
+    let features = Features::from_bits_truncate(Features::ALL.bits() & !feature.bits());
+    Capabilities::new(CAPABILITIES_VERSION, OPEN_KEY_VERSION, features)
+}
+
+#[test]
+fn features() {
+    assert_eq!(Features::from_bits_truncate(u32::MAX), Features::ALL);
+    assert!(Features::from_bits_truncate(1 << 31).is_empty());
+    assert_eq!(
+        format!("{:?}", Features::METADATA | Features::PATH_FLAGS),
+        "Metadata | PathFlags"
+    );
+    assert_eq!(format!("{:?}", Features::default()), "(empty)");
+}
+
+#[test]
+fn negotiated_on_connect() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let capabilities = pddb.capabilities().unwrap();
+    assert_eq!(capabilities.version(), CAPABILITIES_VERSION);
+    assert_eq!(capabilities.open_key_version(), OPEN_KEY_VERSION);
+    assert_eq!(capabilities.features(), Features::ALL);
+
+    // Connections that haven't asked know nothing
+    assert!(crate::Pddb::from_connection(pddb.cid())
+        .capabilities()
+        .is_none());
+}
+
+#[test]
+fn older_server() {
+    let mock = mock();
+    mock.firmware_only();
+    let pddb = mock.connect();
+    assert!(pddb.capabilities().is_none());
+
+    // The mock refuses anything the firmware doesn't understand, so each of
+    // these only works if it is sent in the original format.
+    let before = mock.message_count();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    assert_eq!(mock.message_count() - before, 1);
+    assert_eq!(key.len(), 4);
+
+    let data: Vec<u8> = (0..PAGE_DATA_LEN * 2).map(|i| i as u8).collect();
+    let before = mock.message_count();
+    key.write_all(&data).unwrap();
+    // One `WriteKeyStd` per page
+    assert_eq!(mock.message_count() - before, 2);
+    let mut read = vec![0u8; data.len()];
+    key.rewind().unwrap();
+    key.read_exact(&mut read).unwrap();
+    assert_eq!(read, data);
+    assert_eq!(key.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+    drop(key);
+
+    let before = mock.message_count();
+    let keys = pddb.list_keys(None, "sys.rtc").unwrap();
+    assert_eq!(keys.iter().collect::<Vec<_>>(), ["tz_offset"]);
+    assert_eq!(pddb.list_dicts(None).unwrap(), ["sys.rtc"]);
+    assert_eq!(pddb.list_path("sys.rtc").unwrap().len(), 1);
+    assert_eq!(mock.message_count() - before, 3);
+
+    let before = mock.message_count();
+    let metadata = pddb.stat("sys.rtc:tz_offset").unwrap();
+    assert!(metadata.is_key());
+    assert_eq!(mock.message_count() - before, 1);
+
+    // Requests that only return a status come back untouched
+    pddb.create_dict(None, "sys.clock").unwrap();
+    assert_eq!(pddb.list_dicts(None).unwrap(), ["sys.clock", "sys.rtc"]);
+    // The firmware doesn't say that a dict already exists, so that's checked first
+    assert_eq!(
+        pddb.create_dict(None, "sys.rtc").unwrap_err().kind(),
+        ErrorKind::AlreadyExists
+    );
+    pddb.delete_key(None, "sys.rtc", "tz_offset").unwrap();
+    assert!(mock.key_contents(None, "sys.rtc", "tz_offset").is_none());
+
+    // Newer operations aren't sent at all
+    let mut key = pddb
+        .open_key_with(
+            None,
+            "sys.rtc",
+            "new",
+            KeyOptions::new().read(true).write(true).create(true),
+        )
+        .unwrap();
+    let before = mock.message_count();
+    assert_eq!(key.metadata().unwrap_err().kind(), ErrorKind::Unsupported);
+    assert_eq!(key.set_len(0).unwrap_err().kind(), ErrorKind::Unsupported);
+    for result in [
+        pddb.rename_key(None, "sys.rtc", "new", None, "sys.rtc", "newer"),
+        pddb.rename_dict(None, "sys.rtc", "sys.clock"),
+        pddb.basis_order().map(drop),
+    ] {
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+    assert_eq!(mock.message_count(), before);
+    drop(key);
+    assert_eq!(mock.open_handles(), 0);
+}
+
+#[test]
+fn unsupported_features_are_not_tried() {
+    let mock = mock();
+    mock.report_capabilities(without(Features::SENRES_OPEN_KEY));
+    let pddb = mock.connect();
+    let before = mock.message_count();
+    let _key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    assert_eq!(mock.message_count() - before, 1);
+
+    mock.report_capabilities(without(Features::PAGED_KEY_LIST));
+    let pddb = mock.connect();
+    let before = mock.message_count();
+    assert_eq!(pddb.list_keys(None, "sys.rtc").unwrap().len(), 1);
+    assert_eq!(mock.message_count() - before, 1);
+
+    mock.report_capabilities(without(Features::PAGED_DICT_LIST));
+    let pddb = mock.connect();
+    let before = mock.message_count();
+    assert_eq!(pddb.list_dicts(None).unwrap(), ["sys.rtc"]);
+    assert_eq!(mock.message_count() - before, 1);
+
+    mock.report_capabilities(without(Features::MULTI_PAGE_IO));
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    let before = mock.message_count();
+    key.write_all(&[0u8; PAGE_DATA_LEN * 3]).unwrap();
+    // One message per page, without a refused multi-page attempt
+    assert_eq!(mock.message_count() - before, 3);
+
+    mock.report_capabilities(without(Features::METADATA));
+    let pddb = mock.connect();
+    let before = mock.message_count();
+    // Only the kind of entry is read from the reply
+    let metadata = pddb.stat("sys.rtc:tz_offset").unwrap();
+    assert!(metadata.is_key());
+    assert_eq!(metadata.len(), 0);
+    assert_eq!(mock.message_count() - before, 1);
+
+    mock.report_capabilities(without(Features::RENAME_COPY_KEY));
+    let pddb = mock.connect();
+    let before = mock.message_count();
+    for result in [
+        pddb.rename_key(None, "sys.rtc", "tz_offset", None, "sys.rtc", "renamed"),
+        pddb.copy_key(None, "sys.rtc", "tz_offset", None, "sys.rtc", "copied"),
+    ] {
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+    assert_eq!(mock.message_count(), before);
+
+    mock.report_capabilities(without(Features::RENAME_DICT));
+    let pddb = mock.connect();
+    let before = mock.message_count();
+    assert_eq!(
+        pddb.rename_dict(None, "sys.rtc", "sys.clock")
+            .unwrap_err()
+            .kind(),
+        ErrorKind::Unsupported
+    );
+    assert_eq!(mock.message_count(), before);
+
+    mock.report_capabilities(without(Features::BASIS_MANAGEMENT));
+    let pddb = mock.connect();
+    let before = mock.message_count();
+    for result in [
+        pddb.create_basis("home", "pw"),
+        pddb.unlock_basis("home", "pw"),
+        pddb.lock_basis("home"),
+        pddb.delete_basis("home"),
+        pddb.set_basis_order(&["home"]),
+        pddb.basis_order().map(drop),
+        pddb.basis_info().map(drop),
+        pddb.resolve_key(None, "sys.rtc", "tz_offset").map(drop),
+    ] {
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+    assert_eq!(mock.message_count(), before);
+
+    // Bases are still listed for searches, without the union order
+    let found: Vec<_> = pddb.glob(":*").map(|entry| entry.unwrap()).collect();
+    assert_eq!(found.len(), 1);
+    assert!(pddb
+        .name_collisions(&crate::path::NamePolicy::default())
+        .is_ok());
+}
+
+#[test]
+fn older_open_key_version() {
+    let mock = mock();
+    mock.report_capabilities(Capabilities::new(CAPABILITIES_VERSION, 1, Features::ALL));
+    let pddb = mock.connect();
+
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    key.write_all(&[9]).unwrap();
+    drop(key);
+
+    // Exclusive creation and truncation are done on the client side
+    let create_new = KeyOptions::new().write(true).create_new(true).clone();
+    assert_eq!(
+        pddb.open_key_with(None, "sys.rtc", "tz_offset", &create_new)
+            .map(drop)
+            .unwrap_err()
+            .kind(),
+        ErrorKind::AlreadyExists
+    );
+    pddb.open_key_with(None, "sys.rtc", "new", &create_new)
+        .unwrap()
+        .write_all(&[1, 2])
+        .unwrap();
+    assert_eq!(mock.key_contents(None, "sys.rtc", "new"), Some(vec![1, 2]));
+
+    let mut key = pddb
+        .open_key_with(
+            None,
+            "sys.rtc",
+            "new",
+            KeyOptions::new().write(true).truncate(true),
+        )
+        .unwrap();
+    assert_eq!(key.len(), 0);
+    key.write_all(&[3]).unwrap();
+    assert_eq!(mock.key_contents(None, "sys.rtc", "new"), Some(vec![3]));
+    drop(key);
+    assert_eq!(mock.open_handles(), 0);
+}
+
+#[test]
+fn older_open_key_version_truncates_in_place() {
+    let mock = mock();
+    mock.report_capabilities(Capabilities::new(CAPABILITIES_VERSION, 1, Features::ALL));
+    mock.add_basis("secret");
+    mock.insert_key(Some("secret"), "app", "only", &[1, 2, 3]);
+    mock.insert_key(Some("secret"), "app", "both", &[1, 2, 3]);
+    mock.insert_key(Some(SYSTEM_BASIS), "app", "both", &[4, 5, 6]);
+    let pddb = mock.connect();
+    let truncate = KeyOptions::new().write(true).truncate(true).clone();
+
+    // The key is created again in the basis it was found in
+    let mut key = pddb.open_key_with(None, "app", "only", &truncate).unwrap();
+    assert_eq!(key.len(), 0);
+    key.write_all(&[7]).unwrap();
+    drop(key);
+    assert_eq!(
+        mock.key_contents(Some("secret"), "app", "only"),
+        Some(vec![7])
+    );
+    assert_eq!(mock.key_contents(Some(SYSTEM_BASIS), "app", "only"), None);
+
+    // With a copy in each basis there's no telling which one was opened
+    assert_eq!(
+        pddb.open_key_with(None, "app", "both", &truncate)
+            .map(drop)
+            .unwrap_err()
+            .kind(),
+        ErrorKind::Unsupported
+    );
+    assert_eq!(
+        mock.key_contents(Some("secret"), "app", "both"),
+        Some(vec![1, 2, 3])
+    );
+    assert_eq!(
+        mock.key_contents(Some(SYSTEM_BASIS), "app", "both"),
+        Some(vec![4, 5, 6])
+    );
+
+    // Naming the basis settles it
+    drop(
+        pddb.open_key_with(Some("secret"), "app", "both", &truncate)
+            .unwrap(),
+    );
+    assert_eq!(
+        mock.key_contents(Some("secret"), "app", "both"),
+        Some(vec![])
+    );
+    assert_eq!(
+        mock.key_contents(Some(SYSTEM_BASIS), "app", "both"),
+        Some(vec![4, 5, 6])
+    );
+    assert_eq!(mock.open_handles(), 0);
+}
@@ -0,0 +1,478 @@
+//! A conformance suite for the filesystem semantics of the PDDB.
+//!
+//! These scenarios started life as experiments in `main.rs` that were run
+//! by hand when adding libstd support. Each scenario is run against a
+//! [`Backend`], which may be `std::fs` on a real device or the raw client
+//! talking to either the real PDDB or the `mock` server. Operations that a
+//! backend does not support cause the scenario to be skipped rather than
+//! failed.
+
+use std::io::{self, Read, Seek, Write};
+
+#[cfg(test)]
+mod tests;
+
+/// How a key should be opened. This mirrors `std::fs::OpenOptions`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OpenMode {
+    pub write: bool,
+    pub create: bool,
+    pub create_new: bool,
+    pub truncate: bool,
+    pub append: bool,
+}
+
+impl OpenMode {
+    /// Open an existing key for reading.
+    pub const READ: OpenMode = OpenMode {
+        write: false,
+        create: false,
+        create_new: false,
+        truncate: false,
+        append: false,
+    };
+
+    /// Open a key for writing, creating it if necessary and truncating it.
+    /// This is what `File::create()` does.
+    pub const CREATE: OpenMode = OpenMode {
+        write: true,
+        create: true,
+        create_new: false,
+        truncate: true,
+        append: false,
+    };
+}
+
+/// Something that provides access to the PDDB. Paths are PDDB paths such
+/// as `sys.rtc:tz_offset`.
+pub trait Backend {
+    type Key: Read + Write + Seek;
+
+    /// A human-readable name for the report
+    fn name(&self) -> &str;
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Self::Key>;
+    fn remove_key(&self, path: &str) -> io::Result<()>;
+    fn create_dict(&self, path: &str) -> io::Result<()>;
+    fn remove_dict(&self, path: &str) -> io::Result<()>;
+    /// List the names of the direct children of `path`.
+    fn list(&self, path: &str) -> io::Result<Vec<String>>;
+}
+
+/// The result of running a single scenario.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    Skipped(String),
+}
+
+/// The outcome of every scenario that was run.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub backend: String,
+    pub results: Vec<(&'static str, Outcome)>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.count(|o| *o == Outcome::Pass)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Fail(_)))
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Skipped(_)))
+    }
+
+    fn count(&self, f: impl Fn(&Outcome) -> bool) -> usize {
+        self.results.iter().filter(|(_, o)| f(o)).count()
+    }
+}
+
+impl core::fmt::Display for Report {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "Conformance results for {}:", self.backend)?;
+        for (name, outcome) in &self.results {
+            match outcome {
+                Outcome::Pass => writeln!(f, "  [PASS] {}", name)?,
+                Outcome::Fail(why) => writeln!(f, "  [FAIL] {}: {}", name, why)?,
+                Outcome::Skipped(why) => writeln!(f, "  [SKIP] {}: {}", name, why)?,
+            }
+        }
+        write!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.passed(),
+            self.failed(),
+            self.skipped()
+        )
+    }
+}
+
+/// Why a scenario stopped early.
+enum Stop {
+    Fail(String),
+    Skip(String),
+}
+
+type Step<T> = Result<T, Stop>;
+
+/// Unwrap the result of an operation that should succeed.
+fn ok<T>(what: &str, result: io::Result<T>) -> Step<T> {
+    result.map_err(|e| {
+        if e.kind() == io::ErrorKind::Unsupported {
+            Stop::Skip(format!("{}: {}", what, e))
+        } else {
+            Stop::Fail(format!("{}: unexpected error {}", what, e))
+        }
+    })
+}
+
+/// Check that an operation failed.
+fn err<T>(what: &str, result: io::Result<T>) -> Step<io::Error> {
+    match result {
+        Ok(_) => Err(Stop::Fail(format!(
+            "{}: succeeded but should have failed",
+            what
+        ))),
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            Err(Stop::Skip(format!("{}: {}", what, e)))
+        }
+        Err(e) => Ok(e),
+    }
+}
+
+fn ensure_eq<T: PartialEq + core::fmt::Debug>(what: &str, actual: T, expected: T) -> Step<()> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(Stop::Fail(format!(
+            "{}: expected {:?}, got {:?}",
+            what, expected, actual
+        )))
+    }
+}
+
+fn read_all<K: Read + Seek>(key: &mut K) -> Step<Vec<u8>> {
+    let mut v = vec![];
+    ok("read key", key.read_to_end(&mut v))?;
+    Ok(v)
+}
+
+/// Write `data` to a newly-created key.
+fn create_with<B: Backend>(backend: &B, path: &str, data: &[u8]) -> Step<()> {
+    let mut key = ok("create key", backend.open(path, OpenMode::CREATE))?;
+    ok("write key", key.write_all(data))
+}
+
+fn contents<B: Backend>(backend: &B, path: &str) -> Step<Vec<u8>> {
+    let mut key = ok("open key", backend.open(path, OpenMode::READ))?;
+    read_all(&mut key)
+}
+
+fn create_and_read_back<B: Backend>(backend: &B, dict: &str) -> Step<()> {
+    let path = format!("{}:foobar", dict);
+    backend.remove_key(&path).ok();
+    err("open missing key", backend.open(&path, OpenMode::READ))?;
+
+    create_with(backend, &path, &[1, 2, 3, 4])?;
+    ensure_eq("contents", contents(backend, &path)?, vec![1, 2, 3, 4])?;
+
+    let mode = OpenMode {
+        write: true,
+        ..OpenMode::READ
+    };
+    let mut key = ok("open key", backend.open(&path, mode))?;
+    let mut v = read_all(&mut key)?;
+    ok("rewind", key.rewind())?;
+    v.push(42);
+    ok("rewrite key", key.write_all(&v))?;
+    drop(key);
+
+    ensure_eq("contents", contents(backend, &path)?, vec![1, 2, 3, 4, 42])?;
+    ok("remove key", backend.remove_key(&path))
+}
+
+fn create_new_fails_on_existing<B: Backend>(backend: &B, dict: &str) -> Step<()> {
+    let path = format!("{}:foobar", dict);
+    create_with(backend, &path, &[1])?;
+    let mode = OpenMode {
+        write: true,
+        create_new: true,
+        ..OpenMode::READ
+    };
+    let e = err("create_new on existing key", backend.open(&path, mode))?;
+    ensure_eq("error kind", e.kind(), io::ErrorKind::AlreadyExists)?;
+    ok("remove key", backend.remove_key(&path))
+}
+
+fn truncate_empties_key<B: Backend>(backend: &B, dict: &str) -> Step<()> {
+    let path = format!("{}:foobar", dict);
+    create_with(backend, &path, &[1, 2, 3, 4, 42])?;
+    let mode = OpenMode {
+        write: true,
+        truncate: true,
+        ..OpenMode::READ
+    };
+    let mut key = ok("open key for truncating", backend.open(&path, mode))?;
+    ensure_eq("contents after truncate", read_all(&mut key)?, vec![])?;
+    ok("write key", key.write_all(&[42]))?;
+    drop(key);
+
+    ensure_eq("contents", contents(backend, &path)?, vec![42])?;
+    ok("remove key", backend.remove_key(&path))
+}
+
+fn append_writes_at_end<B: Backend>(backend: &B, dict: &str) -> Step<()> {
+    let path = format!("{}:foobar", dict);
+    create_with(backend, &path, &[42])?;
+    let mode = OpenMode {
+        write: true,
+        append: true,
+        ..OpenMode::READ
+    };
+    let mut key = ok("open key for appending", backend.open(&path, mode))?;
+    ok("rewind", key.rewind())?;
+    ensure_eq("contents", read_all(&mut key)?, vec![42])?;
+    ok("rewind", key.rewind())?;
+    ok("append to key", key.write_all(&[43]))?;
+    drop(key);
+
+    ensure_eq("contents", contents(backend, &path)?, vec![42, 43])?;
+    ok("remove key", backend.remove_key(&path))
+}
+
+fn read_at_end<B: Backend>(backend: &B, dict: &str) -> Step<()> {
+    let path = format!("{}:foobar", dict);
+    create_with(backend, &path, &[1, 2, 3, 4])?;
+    let mut key = ok("open key", backend.open(&path, OpenMode::READ))?;
+    ok("seek to end", key.seek(io::SeekFrom::End(0)))?;
+    ensure_eq("bytes read", ok("read key", key.read(&mut [0u8; 16]))?, 0)?;
+    ensure_eq("bytes read", ok("read key", key.read(&mut [0u8; 16]))?, 0)?;
+    drop(key);
+    ok("remove key", backend.remove_key(&path))
+}
+
+fn read_past_end<B: Backend>(backend: &B, dict: &str) -> Step<()> {
+    let path = format!("{}:foobar", dict);
+    create_with(backend, &path, &[1, 2, 3, 4])?;
+    let mut key = ok("open key", backend.open(&path, OpenMode::READ))?;
+    ok("seek past end", key.seek(io::SeekFrom::Start(100)))?;
+    ensure_eq("bytes read", ok("read key", key.read(&mut [0u8; 16]))?, 0)?;
+    let e = err("read_exact past end", key.read_exact(&mut [0u8; 1]))?;
+    ensure_eq("error kind", e.kind(), io::ErrorKind::UnexpectedEof)?;
+    drop(key);
+    ok("remove key", backend.remove_key(&path))
+}
+
+fn read_straddling_end<B: Backend>(backend: &B, dict: &str) -> Step<()> {
+    let path = format!("{}:foobar", dict);
+    create_with(backend, &path, &[1, 2, 3, 4])?;
+    let mut key = ok("open key", backend.open(&path, OpenMode::READ))?;
+    ok("seek", key.seek(io::SeekFrom::Start(2)))?;
+    let mut buf = [0u8; 16];
+    ensure_eq("bytes read", ok("read key", key.read(&mut buf))?, 2)?;
+    ensure_eq("contents", &buf[..2], &[3, 4])?;
+    ensure_eq("position", ok("stream position", key.stream_position())?, 4)?;
+    ensure_eq("bytes read", ok("read key", key.read(&mut buf))?, 0)?;
+    drop(key);
+    ok("remove key", backend.remove_key(&path))
+}
+
+fn delete_twice_fails<B: Backend>(backend: &B, dict: &str) -> Step<()> {
+    let path = format!("{}:foobar", dict);
+    create_with(backend, &path, &[1])?;
+    ok("remove key", backend.remove_key(&path))?;
+    let e = err("remove key a second time", backend.remove_key(&path))?;
+    ensure_eq("error kind", e.kind(), io::ErrorKind::NotFound)?;
+    err("open removed key", backend.open(&path, OpenMode::READ))?;
+    Ok(())
+}
+
+fn nested_dict<B: Backend>(backend: &B, dict: &str) -> Step<()> {
+    let subdict = format!("{}:baz", dict);
+    let path = format!("{}:quux", subdict);
+    backend.remove_key(&path).ok();
+    backend.remove_dict(&subdict).ok();
+
+    ok("create dict", backend.create_dict(&subdict))?;
+    create_with(backend, &path, &[5, 4, 3, 2])?;
+    let entries = ok("list dict", backend.list(dict))?;
+    if !entries.iter().any(|e| e == "baz") {
+        return Err(Stop::Fail(format!(
+            "baz missing from listing {:?}",
+            entries
+        )));
+    }
+    ensure_eq(
+        "listing",
+        ok("list subdict", backend.list(&subdict))?,
+        vec!["quux".to_owned()],
+    )?;
+
+    err("remove non-empty dict", backend.remove_dict(&subdict))?;
+    ok("remove key", backend.remove_key(&path))?;
+    ok("remove empty dict", backend.remove_dict(&subdict))
+}
+
+fn create_dict_twice<B: Backend>(backend: &B, dict: &str) -> Step<()> {
+    let subdict = format!("{}:test-create", dict);
+    ok("create dict", backend.create_dict(&subdict))?;
+    ok("create dict again", backend.create_dict(&subdict))?;
+    ok("remove dict", backend.remove_dict(&subdict))
+}
+
+fn missing_dict<B: Backend>(backend: &B, dict: &str) -> Step<()> {
+    let subdict = format!("{}:nonexistent-dict-name", dict);
+    let path = format!("{}:nonexistent-key", subdict);
+    let e = err(
+        "open key in missing dict",
+        backend.open(&path, OpenMode::READ),
+    )?;
+    ensure_eq("error kind", e.kind(), io::ErrorKind::NotFound)?;
+    err("list missing dict", backend.list(&subdict))?;
+    Ok(())
+}
+
+type Scenario<B> = fn(&B, &str) -> Step<()>;
+
+/// Run every scenario against `backend`. Scenarios create and delete
+/// entries inside `dict`, which must already exist.
+pub fn run<B: Backend>(backend: &B, dict: &str) -> Report {
+    let scenarios: [(&'static str, Scenario<B>); 11] = [
+        ("create_and_read_back", create_and_read_back),
+        ("create_new_fails_on_existing", create_new_fails_on_existing),
+        ("truncate_empties_key", truncate_empties_key),
+        ("append_writes_at_end", append_writes_at_end),
+        ("read_at_end", read_at_end),
+        ("read_past_end", read_past_end),
+        ("read_straddling_end", read_straddling_end),
+        ("delete_twice_fails", delete_twice_fails),
+        ("nested_dict", nested_dict),
+        ("create_dict_twice", create_dict_twice),
+        ("missing_dict", missing_dict),
+    ];
+
+    let mut report = Report {
+        backend: backend.name().to_owned(),
+        ..Default::default()
+    };
+    for (name, scenario) in scenarios {
+        let outcome = match scenario(backend, dict) {
+            Ok(()) => Outcome::Pass,
+            Err(Stop::Fail(why)) => Outcome::Fail(why),
+            Err(Stop::Skip(why)) => Outcome::Skipped(why),
+        };
+        report.results.push((name, outcome));
+    }
+    report
+}
+
+/// Access the PDDB through libstd. This is only meaningful on Xous, where
+/// `std::fs` is backed by the PDDB.
+pub struct StdFs;
+
+impl Backend for StdFs {
+    type Key = std::fs::File;
+
+    fn name(&self) -> &str {
+        "std::fs"
+    }
+
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Self::Key> {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(mode.write)
+            .create(mode.create)
+            .create_new(mode.create_new)
+            .truncate(mode.truncate)
+            .append(mode.append)
+            .open(path)
+    }
+
+    fn remove_key(&self, path: &str) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn create_dict(&self, path: &str) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn remove_dict(&self, path: &str) -> io::Result<()> {
+        std::fs::remove_dir(path)
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        std::fs::read_dir(path)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+}
+
+/// Access the PDDB using the raw client in this crate.
+pub struct Raw<'a> {
+    pddb: &'a crate::Pddb,
+}
+
+impl<'a> Raw<'a> {
+    pub fn new(pddb: &'a crate::Pddb) -> Self {
+        Raw { pddb }
+    }
+}
+
+fn split_key(path: &str) -> io::Result<(&str, &str)> {
+    path.rsplit_once(crate::path::MAIN_SEP)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no key"))
+}
+
+impl Backend for Raw<'_> {
+    type Key = crate::Key;
+
+    fn name(&self) -> &str {
+        "raw client"
+    }
+
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Self::Key> {
+        let (dict, key) = split_key(path)?;
+        self.pddb.open_key_with(
+            None,
+            dict,
+            key,
+            crate::KeyOptions::new()
+                .read(true)
+                .write(mode.write)
+                .create(mode.create)
+                .create_new(mode.create_new)
+                .truncate(mode.truncate)
+                .append(mode.append),
+        )
+    }
+
+    fn remove_key(&self, path: &str) -> io::Result<()> {
+        let (dict, key) = split_key(path)?;
+        self.pddb.delete_key(None, dict, key)
+    }
+
+    fn create_dict(&self, path: &str) -> io::Result<()> {
+        // Match `create_dir_all()`, which succeeds if the dict exists
+        match self.pddb.create_dict(None, path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            result => result,
+        }
+    }
+
+    fn remove_dict(&self, path: &str) -> io::Result<()> {
+        self.pddb.delete_dict(None, path, false)
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .pddb
+            .list_path(path)?
+            .iter()
+            .map(|entry| entry.name().to_owned())
+            .collect())
+    }
+}
@@ -0,0 +1,49 @@
+use super::{run, Outcome, Raw, Report};
+use crate::mock::MockPddb;
+
+/// Check that every scenario passed except the ones named in `differ`,
+/// which must not have.
+fn assert_outcomes(report: &Report, differ: &[&str]) {
+    for (name, outcome) in &report.results {
+        if differ.contains(name) {
+            assert_ne!(
+                *outcome,
+                Outcome::Pass,
+                "{} should differ\n{}",
+                name,
+                report
+            );
+        } else {
+            assert_eq!(*outcome, Outcome::Pass, "{}\n{}", name, report);
+        }
+    }
+}
+
+#[test]
+fn raw_client_against_mock() {
+    let mock = MockPddb::new();
+    mock.add_dict(None, "conformance");
+    let pddb = mock.connect();
+
+    let report = run(&Raw::new(&pddb), "conformance");
+    assert_eq!(report.failed(), 0, "{}", report);
+    assert_eq!(report.skipped(), 0, "{}", report);
+    assert_outcomes(&report, &[]);
+}
+
+/// Scenarios that don't pass against the shipping firmware. It reports
+/// missing entries as `BasisLost`, which reads back as `NotFound`, so the
+/// scenarios relying on that still pass there.
+const FIRMWARE_DIFFERENCES: &[&str] = &[];
+
+#[test]
+fn raw_client_against_firmware() {
+    let mock = MockPddb::new();
+    mock.firmware_only();
+    mock.add_dict(None, "conformance");
+    let pddb = mock.connect();
+
+    let report = run(&Raw::new(&pddb), "conformance");
+    assert_eq!(report.failed(), 0, "{}", report);
+    assert_outcomes(&report, FIRMWARE_DIFFERENCES);
+}
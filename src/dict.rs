@@ -1,51 +1,332 @@
+#[cfg(test)]
+mod tests;
+
 use crate::senres::{Senres, SenresMut};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EntryKind {
     Basis = 0,
     Dict = 1,
     Key = 2,
 }
 
-#[derive(Debug)]
+/// The kinds of thing a name in a path listing refers to. A name in a
+/// dict may refer to both a nested dict and a key.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct EntryFlags(u8);
+
+impl EntryFlags {
+    pub const BASIS: EntryFlags = EntryFlags(1 << 0);
+    pub const DICT: EntryFlags = EntryFlags(1 << 1);
+    pub const KEY: EntryFlags = EntryFlags(1 << 2);
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Convert the flags sent by the server, returning `None` if there are
+    /// no flags or if any flags are unknown.
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        let all = Self::BASIS | Self::DICT | Self::KEY;
+        if bits == 0 || bits & !all.0 != 0 {
+            return None;
+        }
+        Some(EntryFlags(bits))
+    }
+
+    pub fn contains(self, other: EntryFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_basis(self) -> bool {
+        self.contains(Self::BASIS)
+    }
+
+    pub fn is_dict(self) -> bool {
+        self.contains(Self::DICT)
+    }
+
+    pub fn is_key(self) -> bool {
+        self.contains(Self::KEY)
+    }
+}
+
+impl core::ops::BitOr for EntryFlags {
+    type Output = EntryFlags;
+    fn bitor(self, rhs: EntryFlags) -> EntryFlags {
+        EntryFlags(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitOrAssign for EntryFlags {
+    fn bitor_assign(&mut self, rhs: EntryFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl From<EntryKind> for EntryFlags {
+    fn from(kind: EntryKind) -> Self {
+        match kind {
+            EntryKind::Basis => EntryFlags::BASIS,
+            EntryKind::Dict => EntryFlags::DICT,
+            EntryKind::Key => EntryFlags::KEY,
+        }
+    }
+}
+
+impl core::fmt::Debug for EntryFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let names = [
+            (EntryFlags::BASIS, "Basis"),
+            (EntryFlags::DICT, "Dict"),
+            (EntryFlags::KEY, "Key"),
+        ];
+        let mut first = true;
+        for (flag, name) in names {
+            if self.contains(flag) {
+                if !first {
+                    write!(f, " | ")?;
+                }
+                write!(f, "{}", name)?;
+                first = false;
+            }
+        }
+        if first {
+            write!(f, "(empty)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Entry {
     name: String,
-    kind: EntryKind,
+    flags: EntryFlags,
+    size: Option<u64>,
+}
+
+impl Entry {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+
+    pub fn is_basis(&self) -> bool {
+        self.flags.is_basis()
+    }
+
+    pub fn is_dict(&self) -> bool {
+        self.flags.is_dict()
+    }
+
+    pub fn is_key(&self) -> bool {
+        self.flags.is_key()
+    }
+
+    /// The length of the key, if this is a key and the server reported it.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
 }
+
+/// The contents of a path. Newer servers reply with `PtfR`, giving the
+/// flags and size of each entry. Older servers reply with `PthR`, giving a
+/// single kind per entry and listing names that are both a dict and a key
+/// twice. Both are reported the same way, with one entry per name.
 pub struct PathList {
     entries: Vec<Entry>,
 }
 
 impl PathList {
-    pub fn new(connection: u32, path: &str) -> Option<Self> {
+    pub fn new(connection: u32, path: &str) -> std::io::Result<Self> {
+        Self::request(connection, path, false)
+    }
+
+    /// List `path`, asking for flags and sizes if `with_flags` is set. Only
+    /// servers that report `Features::PATH_FLAGS` should be asked.
+    pub(crate) fn request(connection: u32, path: &str, with_flags: bool) -> std::io::Result<Self> {
+        crate::path::PddbPath::parse(path)?.validate()?;
         let mut request = crate::senres::Stack::<4096>::new();
 
         {
-            let mut writer = request.writer(*b"PthQ")?;
+            let mut writer = request
+                .writer(*b"PthQ")
+                .ok_or_else(|| std::io::Error::other("unable to create writer"))?;
             writer.append(path);
+            if with_flags {
+                writer.append(true);
+            }
         }
 
         request
             .lend_mut(connection, crate::Opcodes::ListPathStd as usize)
-            .unwrap();
+            .or(Err(std::io::Error::other("unable to list path")))?;
 
-        let reader = request.reader(*b"PthR").expect("unable to get reader");
-        let mut entries = vec![];
-        let count = reader.try_get_from::<u32>().unwrap() as usize;
+        if let Some(reader) = request.reader(*b"PthE") {
+            let code = reader.try_get_from::<u8>().or(Err(invalid_response()))?;
+            return Err(std::io::Error::new(
+                crate::key::PddbRetcode::from(code as usize).into(),
+                "unable to list path",
+            ));
+        }
+        Ok(PathList {
+            entries: Self::parse(&request)?,
+        })
+    }
+
+    pub(crate) fn parse(reply: &crate::senres::Stack) -> std::io::Result<Vec<Entry>> {
+        let (reader, with_flags) = match reply.reader(*b"PtfR") {
+            Some(reader) => (reader, true),
+            None => (reply.reader(*b"PthR").ok_or_else(invalid_response)?, false),
+        };
+        let mut entries: Vec<Entry> = vec![];
+        let count = reader.try_get_from::<u32>().or(Err(invalid_response()))? as usize;
         for _ in 0..count {
-            let name = reader.try_get_ref_from::<str>().unwrap().to_owned();
-            let kind = match reader.try_get_from::<u8>() {
-                Ok(0) => EntryKind::Basis,
-                Ok(1) => EntryKind::Dict,
-                Ok(2) => EntryKind::Key,
-                v => panic!("unexpected entrykind {:?}", v), //return None,
+            let name = reader
+                .try_get_ref_from::<str>()
+                .or(Err(invalid_response()))?
+                .to_owned();
+            let (flags, size) = if with_flags {
+                let flags = reader.try_get_from::<u8>().or(Err(invalid_response()))?;
+                let flags = EntryFlags::from_bits(flags).ok_or_else(unknown_kind)?;
+                let size = reader
+                    .try_get_from::<Option<u64>>()
+                    .or(Err(invalid_response()))?;
+                (flags, size)
+            } else {
+                let kind = match reader.try_get_from::<u8>() {
+                    Ok(0) => EntryKind::Basis,
+                    Ok(1) => EntryKind::Dict,
+                    Ok(2) => EntryKind::Key,
+                    Ok(_) => return Err(unknown_kind()),
+                    Err(_) => return Err(invalid_response()),
+                };
+                (kind.into(), None)
             };
-            entries.push(Entry { name, kind });
+            match entries.iter_mut().find(|e| e.name == name) {
+                Some(existing) => {
+                    existing.flags |= flags;
+                    existing.size = existing.size.or(size);
+                }
+                None => entries.push(Entry { name, flags, size }),
+            }
         }
-        Some(PathList { entries })
+        Ok(entries)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
 
     pub fn iter(&self) -> std::slice::Iter<'_, Entry> {
         self.entries.iter()
     }
 }
+
+impl<'a> IntoIterator for &'a PathList {
+    type Item = &'a Entry;
+    type IntoIter = std::slice::Iter<'a, Entry>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+fn unknown_kind() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "unknown entry kind in response from server",
+    )
+}
+
+fn invalid_response() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "invalid response from server",
+    )
+}
+
+/// The list of dicts uses the same layout as a list of keys.
+pub type DictList = crate::key::KeyList;
+
+#[repr(C, align(4096))]
+pub struct ListDictRequest {
+    data: [u8; 4096],
+}
+
+impl ListDictRequest {
+    /// Build a request for the dicts in `basis`. Fails if the basis name
+    /// can't be stored in the PDDB, which also guarantees that it fits.
+    pub fn new(basis: Option<&str>) -> std::io::Result<ListDictRequest> {
+        if let Some(basis) = basis {
+            crate::path::validate_name(EntryKind::Basis, basis)?;
+        }
+        let mut this = ListDictRequest { data: [0u8; 4096] };
+
+        // Version number of the request
+        for (src, dest) in 1u32.to_le_bytes().iter().zip(this.data[0..4].iter_mut()) {
+            *dest = *src;
+        }
+
+        // If there's a name, add that. Otherwise the length stays at 0.
+        if let Some(basis) = basis {
+            let name_length = basis.len() as u32;
+            for (src, dest) in name_length
+                .to_le_bytes()
+                .iter()
+                .zip(this.data[4..8].iter_mut())
+            {
+                *dest = *src;
+            }
+            for (src, dest) in basis.as_bytes().iter().zip(this.data[8..].iter_mut()) {
+                *dest = *src;
+            }
+        }
+
+        Ok(this)
+    }
+
+    pub fn invoke(mut self, connection: u32) -> std::io::Result<DictList> {
+        let memory_range = unsafe {
+            xous::MemoryRange::new(
+                &mut self.data as *mut _ as usize,
+                core::mem::size_of::<ListDictRequest>(),
+            )
+            .unwrap()
+        };
+
+        let result = crate::transport::send_message(
+            connection,
+            xous::Message::new_lend_mut(
+                crate::Opcodes::ListDictStd as usize,
+                memory_range,
+                None,
+                core::num::NonZeroUsize::new(4096),
+            ),
+        );
+
+        if let Ok(xous::Result::MemoryReturned(_, _)) = result {
+            DictList::new(self.data).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "unsupported dict list version",
+                )
+            })
+        } else {
+            Err(std::io::Error::other(format!(
+                "unexpected return: {:?}",
+                result
+            )))
+        }
+    }
+}
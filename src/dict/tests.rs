@@ -0,0 +1,97 @@
+use super::{EntryFlags, ListDictRequest, PathList};
+use crate::senres::{SenresMut, Stack};
+
+#[test]
+fn flags_from_bits() {
+    assert_eq!(EntryFlags::from_bits(0), None);
+    assert_eq!(EntryFlags::from_bits(8), None);
+    assert_eq!(EntryFlags::from_bits(0xff), None);
+    assert_eq!(
+        EntryFlags::from_bits(6),
+        Some(EntryFlags::DICT | EntryFlags::KEY)
+    );
+    assert_eq!(format!("{:?}", EntryFlags::BASIS), "Basis");
+    assert_eq!(format!("{:?}", EntryFlags::default()), "(empty)");
+}
+
+#[test]
+fn legacy_listing_merges_duplicates() {
+    let mut reply = Stack::<4096>::new();
+    {
+        let mut writer = reply.writer(*b"PthR").unwrap();
+        writer.append(3u32);
+        writer.append("a");
+        writer.append(2u8);
+        writer.append("b");
+        writer.append(1u8);
+        writer.append("a");
+        writer.append(1u8);
+    }
+    let entries = PathList::parse(&reply).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].name(), "a");
+    assert_eq!(entries[0].flags(), EntryFlags::DICT | EntryFlags::KEY);
+    assert_eq!(entries[0].size(), None);
+    assert_eq!(entries[1].flags(), EntryFlags::DICT);
+}
+
+#[test]
+fn legacy_listing_unknown_kind() {
+    let mut reply = Stack::<4096>::new();
+    {
+        let mut writer = reply.writer(*b"PthR").unwrap();
+        writer.append(1u32);
+        writer.append("a");
+        writer.append(3u8);
+    }
+    let err = PathList::parse(&reply).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn flag_listing() {
+    let mut reply = Stack::<4096>::new();
+    {
+        let mut writer = reply.writer(*b"PtfR").unwrap();
+        writer.append(2u32);
+        writer.append("a");
+        writer.append(6u8);
+        writer.append(Some(10u64));
+        writer.append("b");
+        writer.append(2u8);
+        writer.append(None::<u64>);
+    }
+    let entries = PathList::parse(&reply).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].is_dict() && entries[0].is_key());
+    assert_eq!(entries[0].size(), Some(10));
+    assert!(entries[1].is_dict() && !entries[1].is_key());
+    assert_eq!(entries[1].size(), None);
+
+    let mut reply = Stack::<4096>::new();
+    {
+        let mut writer = reply.writer(*b"PtfR").unwrap();
+        writer.append(1u32);
+        writer.append("a");
+        writer.append(0x10u8);
+        writer.append(None::<u64>);
+    }
+    let err = PathList::parse(&reply).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn list_request_checks_basis_name() {
+    assert!(ListDictRequest::new(None).is_ok());
+    assert!(ListDictRequest::new(Some(".System")).is_ok());
+    let long = "b".repeat(crate::path::BASIS_NAME_LEN + 1);
+    for basis in [long.as_str(), "", "a:b"] {
+        assert_eq!(
+            ListDictRequest::new(Some(basis))
+                .map(drop)
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+}
@@ -0,0 +1,264 @@
+//! Matching PDDB paths against glob patterns.
+//!
+//! A pattern uses the same grammar as a path, as described in the `path`
+//! module, with three special sequences:
+//!
+//! * `?` matches any single character other than a separator
+//! * `*` matches any number of characters, not including a separator
+//! * `**` matches any number of characters, including separators, so it
+//!   can match names in nested dicts
+//!
+//! A pattern that starts with a basis, such as `:*:wlan.networks:*`,
+//! matches against each unlocked basis separately, and the basis part of
+//! the pattern only ever matches basis names. Patterns without a basis,
+//! such as `vault.**`, match against the union basis. There is no way to
+//! escape the special characters.
+
+#[cfg(test)]
+mod tests;
+
+use std::collections::VecDeque;
+use std::io;
+
+use crate::dict::EntryFlags;
+use crate::path::{PddbPathBuf, MAIN_SEP};
+use crate::Pddb;
+
+/// A compiled pattern for a single part of a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    chars: Vec<char>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        Pattern {
+            chars: pattern.chars().collect(),
+        }
+    }
+
+    /// Returns `true` if `name` matches this pattern.
+    pub fn matches(&self, name: &str) -> bool {
+        let name: Vec<char> = name.chars().collect();
+        match_chars(&self.chars, &name, false)
+    }
+
+    /// Returns `true` if something nested inside the dict `name` could
+    /// match this pattern, meaning the dict is worth listing.
+    pub fn matches_below(&self, name: &str) -> bool {
+        let mut name: Vec<char> = name.chars().collect();
+        name.push(MAIN_SEP);
+        match_chars(&self.chars, &name, true)
+    }
+}
+
+/// Match `text` against `pattern`. If `partial` is set, `text` is only the
+/// start of a path, and running out of text before the pattern counts as a match.
+fn match_chars(pattern: &[char], text: &[char], partial: bool) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', rest @ ..] => {
+            (0..=text.len()).any(|skip| match_chars(rest, &text[skip..], partial))
+        }
+        ['*', rest @ ..] => {
+            let max = text
+                .iter()
+                .position(|&c| c == MAIN_SEP)
+                .unwrap_or(text.len());
+            (0..=max).any(|skip| match_chars(rest, &text[skip..], partial))
+        }
+        _ if text.is_empty() => partial,
+        ['?', rest @ ..] => text[0] != MAIN_SEP && match_chars(rest, &text[1..], partial),
+        [c, rest @ ..] => *c == text[0] && match_chars(rest, &text[1..], partial),
+    }
+}
+
+/// A single match returned by `Glob`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobEntry {
+    path: PddbPathBuf,
+    flags: EntryFlags,
+    size: Option<u64>,
+}
+
+impl GlobEntry {
+    /// The full path of the match, including the basis if the pattern named one.
+    pub fn path(&self) -> &PddbPathBuf {
+        &self.path
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+
+    pub fn is_basis(&self) -> bool {
+        self.flags.is_basis()
+    }
+
+    pub fn is_dict(&self) -> bool {
+        self.flags.is_dict()
+    }
+
+    pub fn is_key(&self) -> bool {
+        self.flags.is_key()
+    }
+
+    /// The length of the key, if the server reported it.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+}
+
+/// A dict (or the root of a basis) that still needs to be listed.
+struct Pending {
+    basis: Option<String>,
+    /// The dict, relative to the basis. Empty for the root of the basis.
+    dict: String,
+}
+
+impl Pending {
+    /// The path passed to `Pddb::list_path()` to list this dict.
+    fn list_path(&self) -> String {
+        match &self.basis {
+            Some(basis) => format!(":{}:{}", basis, self.dict),
+            None => self.dict.clone(),
+        }
+    }
+
+    fn child(&self, name: &str) -> String {
+        if self.dict.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}{}{}", self.dict, MAIN_SEP, name)
+        }
+    }
+
+    fn full_path(&self, relative: &str) -> io::Result<PddbPathBuf> {
+        let path = match &self.basis {
+            Some(basis) => format!(":{}:{}", basis, relative),
+            None => relative.to_owned(),
+        };
+        PddbPathBuf::parse(path).map_err(|_| invalid_name())
+    }
+}
+
+/// An iterator over every path matching a pattern, as returned by
+/// `Pddb::glob()`. Dicts are listed lazily, breadth-first, and only when
+/// something inside them could match. Errors listing a dict are returned
+/// in place of its entries, and the walk carries on afterwards.
+pub struct Glob {
+    pddb: Pddb,
+    basis: Option<Pattern>,
+    pattern: Pattern,
+    bases_listed: bool,
+    pending: VecDeque<Pending>,
+    ready: VecDeque<GlobEntry>,
+}
+
+impl Glob {
+    /// Start matching `pattern`. Names are matched exactly as they are
+    /// stored, so `pddb` should not apply a name policy.
+    pub(crate) fn new(pddb: Pddb, pattern: &str) -> Self {
+        let (basis, rest) = match pattern.strip_prefix(MAIN_SEP) {
+            Some(rest) => match rest.split_once(MAIN_SEP) {
+                Some(("", rest)) => (None, rest),
+                Some((basis, rest)) => (Some(basis), rest),
+                None => (Some(rest), ""),
+            },
+            None => (None, pattern),
+        };
+        let rest = rest.strip_suffix(MAIN_SEP).unwrap_or(rest);
+        let mut pending = VecDeque::new();
+        if basis.is_none() {
+            pending.push_back(Pending {
+                basis: None,
+                dict: String::new(),
+            });
+        }
+        Glob {
+            pddb,
+            basis: basis.map(Pattern::new),
+            pattern: Pattern::new(rest),
+            bases_listed: basis.is_none(),
+            pending,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Find the bases that match, either as results or as places to search.
+    fn list_bases(&mut self, pattern: &Pattern) -> io::Result<()> {
+        let bases = self.pddb.unlocked_bases()?;
+        let mut ready = vec![];
+        let mut pending = vec![];
+        for basis in bases.into_iter().filter(|b| pattern.matches(b)) {
+            if self.pattern.chars.is_empty() {
+                ready.push(GlobEntry {
+                    path: PddbPathBuf::parse(format!(":{}", basis)).map_err(|_| invalid_name())?,
+                    flags: EntryFlags::BASIS,
+                    size: None,
+                });
+            } else {
+                pending.push(Pending {
+                    basis: Some(basis),
+                    dict: String::new(),
+                });
+            }
+        }
+        self.ready.extend(ready);
+        self.pending.extend(pending);
+        Ok(())
+    }
+
+    fn list_dict(&mut self, dict: Pending) -> io::Result<()> {
+        let entries = self.pddb.list_path(&dict.list_path())?;
+        // Nothing from this dict is kept unless every name in it is valid
+        let mut ready = vec![];
+        let mut pending = vec![];
+        for entry in entries.iter() {
+            let relative = dict.child(entry.name());
+            if !entry.is_basis() && self.pattern.matches(&relative) {
+                ready.push(GlobEntry {
+                    path: dict.full_path(&relative)?,
+                    flags: entry.flags(),
+                    size: entry.size(),
+                });
+            }
+            if entry.is_dict() && self.pattern.matches_below(&relative) {
+                pending.push(Pending {
+                    basis: dict.basis.clone(),
+                    dict: relative,
+                });
+            }
+        }
+        self.ready.extend(ready);
+        self.pending.extend(pending);
+        Ok(())
+    }
+}
+
+impl Iterator for Glob {
+    type Item = io::Result<GlobEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.ready.pop_front() {
+                return Some(Ok(entry));
+            }
+            let result = if !self.bases_listed {
+                self.bases_listed = true;
+                let pattern = self.basis.clone().unwrap();
+                self.list_bases(&pattern)
+            } else {
+                let dict = self.pending.pop_front()?;
+                self.list_dict(dict)
+            };
+            if let Err(e) = result {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+fn invalid_name() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid name in listing")
+}
@@ -0,0 +1,54 @@
+use super::Pattern;
+
+#[test]
+fn literal() {
+    let pattern = Pattern::new("wlan.networks");
+    assert!(pattern.matches("wlan.networks"));
+    assert!(!pattern.matches("wlan.network"));
+    assert!(!pattern.matches("wlan.networks:recent"));
+}
+
+#[test]
+fn question_mark() {
+    let pattern = Pattern::new("sys.rt?");
+    assert!(pattern.matches("sys.rtc"));
+    assert!(!pattern.matches("sys.rt"));
+    assert!(!Pattern::new("a?b").matches("a:b"));
+    assert!(Pattern::new("one:?").matches("one:\u{1f978}"));
+}
+
+#[test]
+fn star_stays_in_component() {
+    let pattern = Pattern::new("wlan.networks:*");
+    assert!(pattern.matches("wlan.networks:Home Wifi"));
+    assert!(pattern.matches("wlan.networks:"));
+    assert!(!pattern.matches("wlan.networks:recent:Cafe"));
+    assert!(Pattern::new("*.rtc").matches("sys.rtc"));
+    assert!(!Pattern::new("*").matches("sys.rtc:tz_offset"));
+}
+
+#[test]
+fn double_star_crosses_components() {
+    let pattern = Pattern::new("vault.**");
+    assert!(pattern.matches("vault.passwords"));
+    assert!(pattern.matches("vault.passwords:bank"));
+    assert!(pattern.matches("vault.totp:nested:key"));
+    assert!(!pattern.matches("fido.cfg"));
+    assert!(Pattern::new("**:tz_offset").matches("sys.rtc:tz_offset"));
+}
+
+#[test]
+fn matches_below() {
+    let pattern = Pattern::new("wlan.networks:*");
+    assert!(pattern.matches_below("wlan.networks"));
+    assert!(!pattern.matches_below("wlan.networks:recent"));
+    assert!(!pattern.matches_below("wlan"));
+    assert!(!pattern.matches_below("sys.rtc"));
+    assert!(!Pattern::new("sys.rtc").matches_below("sys.rtc"));
+
+    let pattern = Pattern::new("vault.**");
+    assert!(pattern.matches_below("vault.passwords"));
+    assert!(pattern.matches_below("vault.passwords:deep"));
+    assert!(!pattern.matches_below("fido.cfg"));
+    assert!(Pattern::new("**").matches_below("anything"));
+}
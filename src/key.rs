@@ -1,3 +1,13 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::capabilities::{Capabilities, Features};
+use crate::dict::EntryKind;
+use crate::senres::{Senres, SenresMut};
+
+#[cfg(test)]
+mod tests;
+
 #[repr(C, align(4096))]
 pub struct ListKeyRequest {
     data: [u8; 4096],
@@ -6,7 +16,7 @@ pub struct ListKeyRequest {
 /// Return codes for Read/Write API calls to the main server
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-enum PddbRetcode {
+pub(crate) enum PddbRetcode {
     Uninit = 0,
     Ok = 1,
     BasisLost = 2,
@@ -14,6 +24,11 @@ enum PddbRetcode {
     UnexpectedEof = 4,
     InternalError = 5,
     DiskFull = 6,
+    // The codes below are only sent by servers that answer the capability
+    // query. The firmware reports these cases with the codes above.
+    NotFound = 7,
+    AlreadyExists = 8,
+    NotEmpty = 9,
     Invalid = u8::MAX,
 }
 
@@ -21,13 +36,36 @@ impl From<PddbRetcode> for std::io::ErrorKind {
     fn from(other: PddbRetcode) -> Self {
         match other {
             PddbRetcode::BasisLost => std::io::ErrorKind::NotFound,
+            PddbRetcode::AccessDenied => std::io::ErrorKind::PermissionDenied,
             PddbRetcode::UnexpectedEof => std::io::ErrorKind::UnexpectedEof,
             PddbRetcode::DiskFull => std::io::ErrorKind::OutOfMemory,
+            PddbRetcode::NotFound => std::io::ErrorKind::NotFound,
+            PddbRetcode::AlreadyExists => std::io::ErrorKind::AlreadyExists,
+            PddbRetcode::NotEmpty => std::io::ErrorKind::DirectoryNotEmpty,
             _ => std::io::ErrorKind::Other,
         }
     }
 }
 
+impl PddbRetcode {
+    /// A short description of the error, for use in error messages.
+    pub(crate) fn describe(self) -> &'static str {
+        match self {
+            PddbRetcode::Uninit => "no response from server",
+            PddbRetcode::Ok => "success",
+            PddbRetcode::BasisLost => "basis is locked or does not exist",
+            PddbRetcode::AccessDenied => "access denied",
+            PddbRetcode::UnexpectedEof => "unexpected end of key",
+            PddbRetcode::InternalError => "internal error",
+            PddbRetcode::DiskFull => "disk full",
+            PddbRetcode::NotFound => "not found",
+            PddbRetcode::AlreadyExists => "already exists",
+            PddbRetcode::NotEmpty => "dict is not empty",
+            PddbRetcode::Invalid => "invalid request or response",
+        }
+    }
+}
+
 impl From<usize> for PddbRetcode {
     fn from(val: usize) -> Self {
         use PddbRetcode::*;
@@ -39,14 +77,17 @@ impl From<usize> for PddbRetcode {
             4 => UnexpectedEof,
             5 => InternalError,
             6 => DiskFull,
+            7 => NotFound,
+            8 => AlreadyExists,
+            9 => NotEmpty,
             _ => Invalid,
         }
     }
 }
 
 #[repr(C)]
-#[derive(Debug, Default, Clone, Copy)]
-struct ApiToken([u32; 3]);
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ApiToken(pub(crate) [u32; 3]);
 
 /// PddbBuf is a C-representation of a page of memory that's used
 /// to shuttle data for streaming channels. It must be exactly one
@@ -54,319 +95,1315 @@ struct ApiToken([u32; 3]);
 /// at the top, and the remainder available for shuttling data.
 #[repr(C, align(4096))]
 #[derive(Debug)]
-struct PddbBuf {
+pub(crate) struct PddbBuf {
     /// api token for the given buffer
-    token: ApiToken,
-    /// a field reserved for the return code
-    retcode: PddbRetcode,
-    reserved: u8,
+    pub(crate) token: ApiToken,
+    /// a field reserved for the return code. This is kept as a `u8` because
+    /// the server may place any value here; use `retcode()` to decode it.
+    pub(crate) retcode: u8,
+    pub(crate) reserved: u8,
     /// length of the data field
-    len: u16,
+    pub(crate) len: u16,
     /// point in the key stream. 64-bit for future-compatibility; but, can't be larger than 32 bits on a 32-bit target.
-    position: u64,
-    data: [u8; 4072],
+    pub(crate) position: u64,
+    pub(crate) data: [u8; PAGE_DATA_LEN],
+}
+
+/// Number of data bytes that fit in a single `PddbBuf`
+pub(crate) const PAGE_DATA_LEN: usize = 4072;
+
+/// Largest number of pages that will be sent in one multi-page transfer
+pub(crate) const MAX_PAGES: usize = 16;
+
+impl PddbBuf {
+    pub(crate) fn new(token: ApiToken, position: u64, len: usize) -> Self {
+        PddbBuf {
+            token,
+            retcode: PddbRetcode::Uninit as u8,
+            reserved: 0,
+            len: len.min(PAGE_DATA_LEN) as u16,
+            position,
+            data: [0u8; PAGE_DATA_LEN],
+        }
+    }
+
+    pub(crate) fn retcode(&self) -> PddbRetcode {
+        PddbRetcode::from(self.retcode as usize)
+    }
 }
 
 #[allow(dead_code)]
 const fn _assert_pddbbuf_is_4096_bytes() {
     unsafe {
-        core::mem::transmute::<_, PddbBuf>([0u8; 4096]);
+        core::mem::transmute::<[u8; 4096], PddbBuf>([0u8; 4096]);
     }
 }
 
-#[repr(C, align(4096))]
+/// The request sent to open a key. Version 1 always opens the key for
+/// reading and writing, and version 2 adds the open flags. Version 1 is
+/// what the firmware understands, so version 2 is only sent to servers that
+/// report it.
+///
+/// Servers that report `Features::SENRES_OPEN_KEY` are sent this as an
+/// `OpkQ` senres message. Everyone else is sent the original packed layout,
+/// which has the same fields in the same order, without any alignment.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OpenKeyRequest {
-    data: [u8; 4096],
+    pub(crate) version: u32,
+    pub(crate) basis: Option<String>,
+    pub(crate) dict: String,
+    pub(crate) key: String,
+    pub(crate) create_dict: bool,
+    pub(crate) create_key: bool,
+    pub(crate) alloc_hint: u64,
+    pub(crate) cb_sid: Option<[u32; 4]>,
+    pub(crate) flags: u8,
+}
+
+/// The newest version of `OpenKeyRequest`.
+pub(crate) const OPEN_KEY_VERSION: u32 = 2;
+
+/// The version of `OpenKeyRequest` every server understands.
+pub(crate) const BASELINE_OPEN_KEY_VERSION: u32 = 1;
+
+/// Bits in the `flags` byte of a version 2 `OpenKeyRequest`
+pub(crate) mod open_flags {
+    pub const READ: u8 = 1 << 0;
+    pub const WRITE: u8 = 1 << 1;
+    pub const TRUNCATE: u8 = 1 << 2;
+    pub const APPEND: u8 = 1 << 3;
+    pub const CREATE_NEW: u8 = 1 << 4;
+}
+
+impl OpenKeyRequest {
+    pub(crate) fn new(basis: Option<&str>, dict: &str, key: &str, opts: &KeyOptions) -> Self {
+        OpenKeyRequest {
+            version: BASELINE_OPEN_KEY_VERSION,
+            basis: basis.map(str::to_owned),
+            dict: dict.to_owned(),
+            key: key.to_owned(),
+            create_dict: opts.create_dict,
+            create_key: opts.create || opts.create_new,
+            alloc_hint: opts.alloc_hint,
+            cb_sid: opts.cb_sid.map(|sid| sid.to_array()),
+            flags: opts.flags(),
+        }
+    }
+
+    /// Flags that version 1 requests imply.
+    #[cfg(not(target_os = "xous"))]
+    const V1_FLAGS: u8 = open_flags::READ | open_flags::WRITE;
+
+    pub(crate) fn write_to<B: SenresMut>(&self, writer: &mut crate::senres::Writer<'_, B>) {
+        writer.append(self.version);
+        writer.append(self.basis.as_deref());
+        writer.append(self.dict.as_str());
+        writer.append(self.key.as_str());
+        writer.append(self.create_dict);
+        writer.append(self.create_key);
+        writer.append(self.alloc_hint);
+        writer.append(self.cb_sid);
+        if self.version >= 2 {
+            writer.append(self.flags);
+        }
+    }
+
+    #[cfg(not(target_os = "xous"))]
+    pub(crate) fn read_from<B: Senres>(reader: &crate::senres::Reader<'_, B>) -> Option<Self> {
+        let version = reader.try_get_from::<u32>().ok()?;
+        if version == 0 || version > OPEN_KEY_VERSION {
+            return None;
+        }
+        Some(OpenKeyRequest {
+            version,
+            basis: reader.try_get_from().ok()?,
+            dict: reader.try_get_from().ok()?,
+            key: reader.try_get_from().ok()?,
+            create_dict: reader.try_get_from().ok()?,
+            create_key: reader.try_get_from().ok()?,
+            alloc_hint: reader.try_get_from().ok()?,
+            cb_sid: reader.try_get_from().ok()?,
+            flags: if version >= 2 {
+                reader.try_get_from().ok()?
+            } else {
+                Self::V1_FLAGS
+            },
+        })
+    }
+
+    /// Write the request in the original packed layout. Fails if the names
+    /// don't fit in `data`.
+    pub(crate) fn encode_packed(&self, data: &mut [u8]) -> std::io::Result<()> {
+        let mut packer = Packer { data, offset: 0 };
+        packer.put(&self.version.to_le_bytes())?;
+        packer.put_name(self.basis.as_deref().unwrap_or_default())?;
+        packer.put_name(&self.dict)?;
+        packer.put_name(&self.key)?;
+        packer.put(&[self.create_dict as u8, self.create_key as u8])?;
+        packer.put(&self.alloc_hint.to_le_bytes())?;
+        packer.put(&[self.cb_sid.is_some() as u8])?;
+        for word in self.cb_sid.unwrap_or_default() {
+            packer.put(&word.to_le_bytes())?;
+        }
+        if self.version >= 2 {
+            packer.put(&[self.flags])?;
+        }
+        Ok(())
+    }
+
+    /// Read a request in the original packed layout, as sent by `encode_packed()`.
+    #[cfg(not(target_os = "xous"))]
+    pub(crate) fn decode_packed(data: &[u8]) -> Option<Self> {
+        let mut offset = 0;
+        let mut take = |len: usize| {
+            let bytes = data.get(offset..offset + len)?;
+            offset += len;
+            Some(bytes)
+        };
+        let version = u32::from_le_bytes(take(4)?.try_into().ok()?);
+        if version == 0 || version > OPEN_KEY_VERSION {
+            return None;
+        }
+        let mut name = || {
+            let len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+            core::str::from_utf8(take(len)?).ok().map(str::to_owned)
+        };
+        let basis = name()?;
+        let dict = name()?;
+        let key = name()?;
+        let [create_dict, create_key] = take(2)?.try_into().ok()?;
+        let alloc_hint = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        let has_cb_sid = take(1)?[0] != 0;
+        let mut cb_sid = [0u32; 4];
+        for word in cb_sid.iter_mut() {
+            *word = u32::from_le_bytes(take(4)?.try_into().ok()?);
+        }
+        let flags = if version >= 2 {
+            take(1)?[0]
+        } else {
+            Self::V1_FLAGS
+        };
+        Some(OpenKeyRequest {
+            version,
+            basis: if basis.is_empty() { None } else { Some(basis) },
+            dict,
+            key,
+            create_dict: create_dict != 0,
+            create_key: create_key != 0,
+            alloc_hint,
+            cb_sid: has_cb_sid.then_some(cb_sid),
+            flags,
+        })
+    }
+}
+
+/// The reply to an `OpenKeyRequest`, sent as `OpkR`. In the original
+/// packed layout this is a u32 status, the token and then the length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OpenKeyResponse {
+    pub(crate) token: ApiToken,
+    pub(crate) len: u64,
+}
+
+impl OpenKeyResponse {
+    #[cfg(not(target_os = "xous"))]
+    pub(crate) fn write_to<B: SenresMut>(&self, writer: &mut crate::senres::Writer<'_, B>) {
+        writer.append(self.token.0);
+        writer.append(self.len);
+    }
+
+    pub(crate) fn read_from<B: Senres>(reader: &crate::senres::Reader<'_, B>) -> Option<Self> {
+        Some(OpenKeyResponse {
+            token: ApiToken(reader.try_get_from().ok()?),
+            len: reader.try_get_from().ok()?,
+        })
+    }
+
+    #[cfg(not(target_os = "xous"))]
+    pub(crate) fn encode_packed(result: Result<Self, PddbRetcode>, data: &mut [u8]) {
+        data[..24].fill(0);
+        match result {
+            Ok(response) => {
+                for (chunk, word) in data[4..16].chunks_mut(4).zip(response.token.0.iter()) {
+                    chunk.copy_from_slice(&word.to_le_bytes());
+                }
+                data[16..24].copy_from_slice(&response.len.to_le_bytes());
+            }
+            Err(code) => data[0..4].copy_from_slice(&(code as u32).to_le_bytes()),
+        }
+    }
+
+    pub(crate) fn decode_packed(data: &[u8]) -> Result<Self, PddbRetcode> {
+        let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let result = word(0) as usize;
+        if result != 0 {
+            return Err(PddbRetcode::from(result));
+        }
+        Ok(OpenKeyResponse {
+            token: ApiToken([word(4), word(8), word(12)]),
+            len: u64::from_le_bytes(data[16..24].try_into().unwrap()),
+        })
+    }
+}
+
+/// Writes the packed layout of an `OpenKeyRequest`, checking that each
+/// field fits.
+struct Packer<'a> {
+    data: &'a mut [u8],
+    offset: usize,
+}
+
+impl Packer<'_> {
+    fn put(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let dest = self
+            .data
+            .get_mut(self.offset..self.offset + bytes.len())
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "names are too long")
+            })?;
+        dest.copy_from_slice(bytes);
+        self.offset += bytes.len();
+        Ok(())
+    }
+
+    fn put_name(&mut self, name: &str) -> std::io::Result<()> {
+        let len = u32::try_from(name.len()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "name is too long")
+        })?;
+        self.put(&len.to_le_bytes())?;
+        self.put(name.as_bytes())
+    }
+}
+
+/// Options that control how a key is opened. This works the same way
+/// as `std::fs::OpenOptions`, with the addition of PDDB-specific
+/// options such as `create_dict()` and `alloc_hint()`.
+#[derive(Debug, Clone, Default)]
+pub struct KeyOptions {
+    read: bool,
+    write: bool,
+    create: bool,
+    create_new: bool,
+    create_dict: bool,
+    truncate: bool,
+    append: bool,
+    alloc_hint: u64,
+    cb_sid: Option<xous::SID>,
+    write_policy: WritePolicy,
+    read_ahead: bool,
+}
+
+/// When data written to a `Key` is sent to the server.
+///
+/// With `Buffered` and `FlushOnDrop`, call `flush()` before dropping the
+/// key to find out whether the last writes made it. Dropping the key sends
+/// them too, but can only log a failure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Every call to `write()` is sent to the server straight away.
+    #[default]
+    WriteThrough,
+    /// Small writes are gathered into a page-sized buffer, which is sent
+    /// when it fills up, when the key is read, flushed or dropped, or when
+    /// a write doesn't follow on from the previous one.
+    Buffered,
+    /// The same as `Buffered`, but the key is also flushed when dropped.
+    FlushOnDrop,
+}
+
+impl KeyOptions {
+    /// Create a blank set of options with every flag set to `false`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Allow reading from the key.
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    /// Allow writing to the key.
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Create the key if it does not exist. Requires `write`.
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Create the key, failing if it already exists. Requires `write`.
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Create the dict if it does not exist.
+    pub fn create_dict(&mut self, create_dict: bool) -> &mut Self {
+        self.create_dict = create_dict;
+        self
+    }
+
+    /// Truncate the key to zero bytes when it is opened. Requires `write`.
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Perform every write at the end of the key. Implies `write`.
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// The number of bytes to reserve for the key when it is created.
+    pub fn alloc_hint(&mut self, alloc_hint: u64) -> &mut Self {
+        self.alloc_hint = alloc_hint;
+        self
+    }
+
+    /// A server to notify when the key is changed by someone else.
+    pub fn callback(&mut self, cb_sid: Option<xous::SID>) -> &mut Self {
+        self.cb_sid = cb_sid;
+        self
+    }
+
+    /// Control when written data is sent to the server.
+    pub fn write_policy(&mut self, write_policy: WritePolicy) -> &mut Self {
+        self.write_policy = write_policy;
+        self
+    }
+
+    /// Read a whole page whenever a small read misses, so that later reads
+    /// can be served without talking to the server. Data that was read ahead
+    /// will not reflect changes made through other handles.
+    pub fn read_ahead(&mut self, read_ahead: bool) -> &mut Self {
+        self.read_ahead = read_ahead;
+        self
+    }
+
+    /// Open a key using these options.
+    pub fn open(
+        &self,
+        connection: xous::CID,
+        basis: Option<&str>,
+        dict: &str,
+        key: &str,
+    ) -> std::io::Result<Key> {
+        Key::open_with(connection, basis, dict, key, self)
+    }
+
+    fn writable(&self) -> bool {
+        self.write || self.append
+    }
+
+    /// Check that the combination of options makes sense, using the same
+    /// rules as libstd.
+    fn validate(&self) -> std::io::Result<()> {
+        if !self.read && !self.writable() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "key must be opened for reading or writing",
+            ));
+        }
+        if !self.writable() && (self.create || self.create_new || self.truncate) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "creating or truncating a key requires write access",
+            ));
+        }
+        if self.truncate && self.append && !self.create_new {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cannot both truncate and append to a key",
+            ));
+        }
+        Ok(())
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.read {
+            flags |= open_flags::READ;
+        }
+        if self.writable() {
+            flags |= open_flags::WRITE;
+        }
+        if self.truncate && !self.create_new {
+            flags |= open_flags::TRUNCATE;
+        }
+        if self.append {
+            flags |= open_flags::APPEND;
+        }
+        if self.create_new {
+            flags |= open_flags::CREATE_NEW;
+        }
+        flags
+    }
+}
+
+/// Information about a key, dict or basis.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub(crate) kind: EntryKind,
+    pub(crate) len: u64,
+    pub(crate) basis: String,
+    pub(crate) reserved: u64,
+    pub(crate) alloc_hint: u64,
+    pub(crate) flushed: bool,
+}
+
+impl Metadata {
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    pub fn is_key(&self) -> bool {
+        self.kind == EntryKind::Key
+    }
+
+    pub fn is_dict(&self) -> bool {
+        self.kind == EntryKind::Dict
+    }
+
+    pub fn is_basis(&self) -> bool {
+        self.kind == EntryKind::Basis
+    }
+
+    /// The length of a key in bytes. This is 0 for dicts and bases.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The basis this entry lives in. When a key is present in several
+    /// bases, this is the one that reads come from and writes go to.
+    pub fn basis(&self) -> &str {
+        &self.basis
+    }
+
+    /// The number of bytes set aside for a key, which is at least its length.
+    pub fn reserved(&self) -> u64 {
+        self.reserved
+    }
+
+    /// The allocation hint the key was created with.
+    pub fn alloc_hint(&self) -> u64 {
+        self.alloc_hint
+    }
+
+    /// Returns `false` if the key has been written to since it was last
+    /// committed to disk.
+    pub fn is_flushed(&self) -> bool {
+        self.flushed
+    }
+
+    /// Describe an entry when the server only gave its kind.
+    pub(crate) fn kind_only(kind: EntryKind) -> Self {
+        Metadata {
+            kind,
+            len: 0,
+            basis: String::new(),
+            reserved: 0,
+            alloc_hint: 0,
+            flushed: true,
+        }
+    }
+
+    /// Decode the fields of a `KstR` or `StaR` reply, starting with the length.
+    pub(crate) fn read_from<B: Senres>(
+        kind: EntryKind,
+        reader: &crate::senres::Reader<'_, B>,
+    ) -> std::io::Result<Self> {
+        Ok(Metadata {
+            kind,
+            len: reader.try_get_from().or(Err(invalid_response()))?,
+            basis: reader.try_get_from().or(Err(invalid_response()))?,
+            reserved: reader.try_get_from().or(Err(invalid_response()))?,
+            alloc_hint: reader.try_get_from().or(Err(invalid_response()))?,
+            flushed: reader.try_get_from().or(Err(invalid_response()))?,
+        })
+    }
 }
 
 pub struct Key {
     fd: ApiToken,
     connection: u32,
     offset: u64,
-    len: u64,
+    /// Cached length of the key. This is atomic so that positional reads
+    /// and writes can share a handle.
+    len: AtomicU64,
+    readable: bool,
+    writable: bool,
+    append: bool,
+    /// What the server said it supports. This is empty if it didn't say.
+    features: Features,
+    write_policy: WritePolicy,
+    read_ahead: bool,
+    buffers: Mutex<Buffers>,
+}
+
+/// Data that is held on the client side of a `Key`.
+#[derive(Default)]
+struct Buffers {
+    /// Where the pending write data goes in the key
+    write_pos: u64,
+    /// Data that has been written but not yet sent to the server
+    write: Vec<u8>,
+    /// Where the read-ahead data came from in the key
+    read_pos: u64,
+    /// Data that was read ahead of the cursor
+    read: Vec<u8>,
 }
 
 impl Key {
+    /// Open an existing key for reading and writing.
     pub fn open(
         connection: xous::CID,
         basis: Option<&str>,
         dict: &str,
         key: &str,
-    ) -> Result<Key, ()> {
-        let create_key = false;
-        let create_dict = false;
-        let cb_sid: Option<xous::SID> = None;
-        let mut req = OpenKeyRequest { data: [0u8; 4096] };
+    ) -> std::io::Result<Key> {
+        Self::open_with(
+            connection,
+            basis,
+            dict,
+            key,
+            KeyOptions::new().read(true).write(true),
+        )
+    }
 
-        let mut read_offset = 0;
+    /// Open a key using the specified options.
+    pub fn open_with(
+        connection: xous::CID,
+        basis: Option<&str>,
+        dict: &str,
+        key: &str,
+        opts: &KeyOptions,
+    ) -> std::io::Result<Key> {
+        Self::open_negotiated(connection, None, basis, dict, key, opts)
+    }
 
-        let request_version: u32 = 1usize.try_into().unwrap();
-        // Version number of the request
-        for (src, dest) in request_version
-            .to_le_bytes()
-            .iter()
-            .zip(req.data[0..4].iter_mut())
-        {
-            *dest = *src;
+    /// Open a key, using what the server said it supports to decide which
+    /// request to send. If `capabilities` is `None`, only the packed version 1
+    /// request that every server understands is sent.
+    ///
+    /// Version 1 requests have no open flags, so for servers that only
+    /// understand those, `create_new` and `truncate` are done here. This
+    /// takes more than one message and isn't atomic: `create_new` first
+    /// checks that the key can't be opened, and `truncate` deletes a
+    /// non-empty key and creates it again in the basis it was found in.
+    /// If no basis was given and the key is in more than one unlocked
+    /// basis, there's no telling which of them was opened, so truncating
+    /// returns an `Unsupported` error.
+    pub(crate) fn open_negotiated(
+        connection: xous::CID,
+        capabilities: Option<&Capabilities>,
+        basis: Option<&str>,
+        dict: &str,
+        key: &str,
+        opts: &KeyOptions,
+    ) -> std::io::Result<Key> {
+        opts.validate()?;
+        crate::path::validate_key_path(basis, dict, key)?;
+        let mut request = OpenKeyRequest::new(basis, dict, key, opts);
+        if capabilities.is_some_and(|c| c.open_key_version() >= OPEN_KEY_VERSION) {
+            request.version = OPEN_KEY_VERSION;
+        }
+        if request.version >= 2 {
+            let response = Self::send_request(connection, capabilities, &request)?;
+            return Ok(Self::from_response(
+                connection,
+                capabilities,
+                response,
+                opts,
+            ));
         }
-        read_offset += 4;
 
-        // If there's a name, add that
-        if let Some(basis) = basis {
-            let name_length = basis.len() as u32;
-            for (src, dest) in name_length
-                .to_le_bytes()
-                .iter()
-                .zip(req.data[read_offset..read_offset + 4].iter_mut())
-            {
-                *dest = *src;
-            }
-            read_offset += 4;
-            // Copy the name bytes
-            for (src, dest) in basis
-                .as_bytes()
-                .iter()
-                .zip(req.data[read_offset..].iter_mut())
-            {
-                *dest = *src;
+        if opts.create_new {
+            let probe = OpenKeyRequest {
+                create_dict: false,
+                create_key: false,
+                ..request.clone()
+            };
+            // The firmware reports a missing key or dict as `BasisLost`,
+            // which reads back as `NotFound`. Anything else means the probe
+            // itself failed.
+            match Self::send_request(connection, capabilities, &probe) {
+                Ok(response) => {
+                    drop(Self::from_response(
+                        connection,
+                        capabilities,
+                        response,
+                        &KeyOptions::new(),
+                    ));
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        "key already exists",
+                    ));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
             }
-            read_offset += basis.len();
         }
-        // Otherwise, zero out the "name" field
-        else {
-            // Write "0" for the length
-            for dest in req.data[read_offset..read_offset + 4].iter_mut() {
-                *dest = 0;
+        let mut response = Self::send_request(connection, capabilities, &request)?;
+        if opts.truncate && !opts.create_new && response.len > 0 {
+            drop(Self::from_response(
+                connection,
+                capabilities,
+                response,
+                &KeyOptions::new(),
+            ));
+            let found = match basis {
+                Some(basis) => basis.to_owned(),
+                None => Self::find_basis(connection, capabilities, &request)?,
+            };
+            crate::Pddb::from_connection(connection)
+                .verbatim()
+                .delete_key(Some(&found), dict, key)?;
+            let request = OpenKeyRequest {
+                basis: Some(found),
+                create_key: true,
+                ..request
+            };
+            response = Self::send_request(connection, capabilities, &request)?;
+        }
+        Ok(Self::from_response(
+            connection,
+            capabilities,
+            response,
+            opts,
+        ))
+    }
+
+    /// Find the one unlocked basis that holds the key `request` names, by
+    /// opening it in each basis in turn.
+    fn find_basis(
+        connection: xous::CID,
+        capabilities: Option<&Capabilities>,
+        request: &OpenKeyRequest,
+    ) -> std::io::Result<String> {
+        let bases = crate::Pddb::from_connection(connection).list_bases()?;
+        let mut found = None;
+        for name in bases.iter() {
+            let probe = OpenKeyRequest {
+                basis: Some(name.to_owned()),
+                create_dict: false,
+                create_key: false,
+                ..request.clone()
+            };
+            match Self::send_request(connection, capabilities, &probe) {
+                Ok(response) => {
+                    drop(Self::from_response(
+                        connection,
+                        capabilities,
+                        response,
+                        &KeyOptions::new(),
+                    ));
+                    if found.replace(name.to_owned()).is_some() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::Unsupported,
+                            "the key is in more than one basis, so it can't be truncated",
+                        ));
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
             }
-            read_offset += 4;
         }
+        found.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "key not found in any basis")
+        })
+    }
 
-        // Copy the dict name
-        let name_length = dict.len() as u32;
-        for (src, dest) in name_length
-            .to_le_bytes()
-            .iter()
-            .zip(req.data[read_offset..read_offset + 4].iter_mut())
-        {
-            *dest = *src;
+    /// Send an open request in whichever encoding the server understands.
+    fn send_request(
+        connection: xous::CID,
+        capabilities: Option<&Capabilities>,
+        request: &OpenKeyRequest,
+    ) -> std::io::Result<OpenKeyResponse> {
+        if capabilities.is_some_and(|c| c.supports(Features::SENRES_OPEN_KEY)) {
+            Self::send_open(connection, request)
+        } else {
+            Self::send_open_packed(connection, request)
         }
-        read_offset += 4;
-        // Copy the name bytes
-        for (src, dest) in dict
-            .as_bytes()
-            .iter()
-            .zip(req.data[read_offset..].iter_mut())
-        {
-            *dest = *src;
+    }
+
+    fn from_response(
+        connection: xous::CID,
+        capabilities: Option<&Capabilities>,
+        response: OpenKeyResponse,
+        opts: &KeyOptions,
+    ) -> Key {
+        let len = response.len;
+        Key {
+            fd: response.token,
+            connection,
+            len: AtomicU64::new(len),
+            offset: if opts.append { len } else { 0 },
+            readable: opts.read,
+            writable: opts.writable(),
+            append: opts.append,
+            features: capabilities.map(Capabilities::features).unwrap_or_default(),
+            write_policy: opts.write_policy,
+            read_ahead: opts.read_ahead,
+            buffers: Mutex::new(Buffers::default()),
         }
-        read_offset += dict.len();
+    }
 
-        // Copy the key name
-        let name_length = key.len() as u32;
-        for (src, dest) in name_length
-            .to_le_bytes()
-            .iter()
-            .zip(req.data[read_offset..read_offset + 4].iter_mut())
+    /// Send the request as senres. Only servers that report
+    /// `Features::SENRES_OPEN_KEY` understand this.
+    fn send_open(
+        connection: xous::CID,
+        request: &OpenKeyRequest,
+    ) -> std::io::Result<OpenKeyResponse> {
+        let mut message = crate::senres::Stack::<4096>::new();
         {
-            *dest = *src;
+            let mut writer = message
+                .writer(*b"OpkQ")
+                .ok_or_else(|| std::io::Error::other("unable to create writer"))?;
+            request.write_to(&mut writer);
         }
-        read_offset += 4;
-        // Copy the key name bytes
-        for (src, dest) in key
-            .as_bytes()
-            .iter()
-            .zip(req.data[read_offset..].iter_mut())
-        {
-            *dest = *src;
+        message
+            .lend_mut(connection, crate::Opcodes::OpenKeyStd as usize)
+            .or(Err(std::io::Error::other("unable to open key")))?;
+
+        if let Some(reader) = message.reader(*b"OpkE") {
+            let code = reader.try_get_from::<u8>().or(Err(invalid_response()))?;
+            return Err(open_error(PddbRetcode::from(code as usize)));
         }
-        read_offset += key.len();
+        let reader = message.reader(*b"OpkR").ok_or_else(invalid_response)?;
+        OpenKeyResponse::read_from(&reader).ok_or_else(invalid_response)
+    }
 
-        req.data[read_offset] = if create_dict { 1 } else { 0 };
-        read_offset += 1;
+    /// Send the request in the original packed layout.
+    fn send_open_packed(
+        connection: xous::CID,
+        request: &OpenKeyRequest,
+    ) -> std::io::Result<OpenKeyResponse> {
+        let mut message = crate::senres::Stack::<4096>::new();
+        request.encode_packed(message.as_mut_slice())?;
+        message
+            .lend_mut(connection, crate::Opcodes::OpenKeyStd as usize)
+            .or(Err(std::io::Error::other("unable to open key")))?;
+        OpenKeyResponse::decode_packed(message.as_slice()).map_err(open_error)
+    }
+}
 
-        req.data[read_offset] = if create_key { 1 } else { 0 };
-        read_offset += 1;
+fn open_error(code: PddbRetcode) -> std::io::Error {
+    std::io::Error::new(code.into(), "unable to open key")
+}
 
-        let alloc_hint = 0u64;
-        for (src, dest) in alloc_hint.to_le_bytes().iter().zip(req.data[read_offset..read_offset+8].iter_mut()) {
-            *dest = *src;
+impl Key {
+    /// The length of the key, as of the last time it was read from or
+    /// written to. Use `refresh_len()` to ask the server. Servers that
+    /// don't report `Features::METADATA` only give the length when the key
+    /// is opened, after which it is tracked from writes and short reads.
+    pub fn len(&self) -> u64 {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Ask the server for the current length of the key, which may have
+    /// been changed through another handle. Any buffered writes are sent first.
+    /// Returns `Unsupported` if the server doesn't report `Features::METADATA`.
+    pub fn refresh_len(&self) -> std::io::Result<u64> {
+        self.flush_buffer()?;
+        self.query_len()
+    }
+
+    /// Change when written data is sent to the server. Switching to
+    /// `WriteThrough` sends any buffered data.
+    pub fn set_write_policy(&mut self, write_policy: WritePolicy) -> std::io::Result<()> {
+        if write_policy == WritePolicy::WriteThrough {
+            self.flush_buffer()?;
         }
-        read_offset += 8;
-    
+        self.write_policy = write_policy;
+        Ok(())
+    }
 
-        if let Some(cb_sid) = cb_sid {
-            req.data[read_offset] = 1;
-            read_offset += 1;
+    /// Ask the server about the key. Any buffered writes are sent first.
+    /// Returns `Unsupported` if the server doesn't report `Features::METADATA`.
+    pub fn metadata(&self) -> std::io::Result<Metadata> {
+        self.flush_buffer()?;
+        self.stat(|reader| Metadata::read_from(EntryKind::Key, reader))
+    }
 
-            for word in cb_sid.to_array().iter() {
-                for (src, dest) in word
-                    .to_le_bytes()
-                    .iter()
-                    .zip(req.data[read_offset..read_offset + 4].iter_mut())
-                {
-                    *dest = *src;
-                }
-                read_offset += 4;
-            }
-        } else {
-            req.data[read_offset] = 0;
-            // read_offset += 1;
-            // read_offset += 16;
+    fn query_len(&self) -> std::io::Result<u64> {
+        self.stat(|reader| reader.try_get_from::<u64>().or(Err(invalid_response())))
+    }
+
+    /// Send a `KeyStat` request, and use `parse` to decode the reply. The
+    /// cached length is updated from the reply.
+    fn stat<T>(
+        &self,
+        parse: impl FnOnce(&crate::senres::Reader<'_, crate::senres::Stack>) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        if !self.features.contains(Features::METADATA) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the server cannot stat keys",
+            ));
         }
+        let mut request = crate::senres::Stack::<4096>::new();
 
-        let memory_range = unsafe {
-            xous::MemoryRange::new(
-                &mut req.data as *mut _ as usize,
-                core::mem::size_of::<OpenKeyRequest>(),
-            )
-            .unwrap()
-        };
+        {
+            let mut writer = request
+                .writer(*b"KstQ")
+                .ok_or_else(|| std::io::Error::other("unable to create writer"))?;
+            writer.append(self.fd.0);
+        }
 
-        let result = xous::send_message(
-            connection,
-            xous::Message::new_lend_mut(
-                crate::Opcodes::OpenKeyStd as usize,
-                memory_range,
-                None,
-                core::num::NonZeroUsize::new(4096),
+        request
+            .lend_mut(self.connection, crate::Opcodes::KeyStat as usize)
+            .or(Err(std::io::Error::other("unable to stat key")))?;
+
+        if let Some(reader) = request.reader(*b"KstE") {
+            let code = reader.try_get_from::<u8>().or(Err(invalid_response()))?;
+            return Err(std::io::Error::new(
+                PddbRetcode::from(code as usize).into(),
+                "unable to stat key",
+            ));
+        }
+        let reader = request.reader(*b"KstR").ok_or_else(invalid_response)?;
+        let len = reader.try_get_from::<u64>().or(Err(invalid_response()))?;
+        self.len.store(len, Ordering::Relaxed);
+
+        let reader = request.reader(*b"KstR").ok_or_else(invalid_response)?;
+        parse(&reader)
+    }
+
+    /// Grow or shrink the key to `size` bytes. New bytes are filled with
+    /// zeroes. If the key shrinks below the current position, the position
+    /// is moved back to the new end. Returns `Unsupported` if the server
+    /// doesn't report `Features::SET_LEN`.
+    pub fn set_len(&mut self, size: u64) -> std::io::Result<()> {
+        self.check_writable()?;
+        if !self.features.contains(Features::SET_LEN) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the server cannot change key lengths",
+            ));
+        }
+        self.flush_buffer()?;
+        self.discard_read_ahead();
+        let size_arg: usize = size.try_into().map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "key length too large")
+        })?;
+        let result = crate::transport::send_message(
+            self.connection,
+            xous::Message::new_blocking_scalar(
+                crate::Opcodes::KeySetLen as usize,
+                self.fd.0[0].try_into().unwrap(),
+                self.fd.0[1].try_into().unwrap(),
+                self.fd.0[2].try_into().unwrap(),
+                size_arg,
             ),
         );
 
-        if let Ok(xous::Result::MemoryReturned(_, _)) = result {
-            let result = u32::from_le_bytes(req.data[0..4].try_into().unwrap()) as usize;
-            if result == 0 {
-                let mut fd = ApiToken::default();
-                for (chunk, word) in req.data[4..].chunks(4).zip(fd.0.iter_mut()) {
-                    *word = u32::from_le_bytes(chunk.try_into().unwrap());
-                }
-                let len = u64::from_le_bytes(req.data[16..24].try_into().unwrap());
-                Ok(Key {
-                    fd,
-                    connection,
-                    len,
-                    offset: 0,
-                })
+        if let Ok(xous::Result::Scalar1(val)) = result {
+            if val == PddbRetcode::Ok as _ {
+                self.len.store(size, Ordering::Relaxed);
+                self.offset = self.offset.min(size);
+                Ok(())
             } else {
-                println!("Error code: {:?}", result);
-                Err(())
+                Err(std::io::Error::new(
+                    PddbRetcode::from(val).into(),
+                    "unable to set key length",
+                ))
             }
         } else {
-            println!("Unexpected return: {:?}", result);
-            Err(())
+            Err(std::io::Error::other(format!(
+                "unexpected return from KeySetLen: {:?}",
+                result
+            )))
         }
     }
-}
 
-impl std::io::Read for Key {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut pddb_buffer = PddbBuf {
-            /// api token for the given buffer
-            token: self.fd,
-            /// a field reserved for the return code
-            retcode: PddbRetcode::Uninit,
-            reserved: 0,
-            /// length of the data field
-            len: buf.len().try_into().unwrap_or(u16::MAX).min(4072),
-            /// point in the key stream. 64-bit for future-compatibility; but, can't be larger than 32 bits on a 32-bit target.
-            position: self.offset,
-            data: [0u8; 4072],
-        };
+    /// Remove the contents of the key and rewind to the start. This is the
+    /// same as `set_len(0)`.
+    pub fn truncate(&mut self) -> std::io::Result<()> {
+        self.set_len(0)
+    }
 
-        let memory_range = unsafe {
-            xous::MemoryRange::new(
-                &mut pddb_buffer as *mut _ as usize,
-                core::mem::size_of::<PddbBuf>(),
-            )
-            .unwrap()
-        };
+    /// Read from the key starting at `pos`, without moving the cursor.
+    /// This returns 0 at or past the end of the key.
+    pub fn read_at(&self, buf: &mut [u8], pos: u64) -> std::io::Result<usize> {
+        self.flush_buffer()?;
+        self.read_at_into(pos, buf.len(), buf.iter_mut())
+    }
 
-        let result = xous::send_message(
-            self.connection,
-            xous::Message::new_lend_mut(
-                crate::Opcodes::ReadKeyStd as usize,
-                memory_range,
-                None,
-                core::num::NonZeroUsize::new(4096),
-            ),
-        );
+    /// Write to the key starting at `pos`, without moving the cursor.
+    /// The key is extended if `pos` is past the end.
+    pub fn write_at(&self, buf: &[u8], pos: u64) -> std::io::Result<usize> {
+        self.flush_buffer()?;
+        self.discard_read_ahead();
+        self.write_at_from(pos, buf.len(), buf.iter())
+    }
 
-        if let Ok(xous::Result::MemoryReturned(_, _)) = result {
-            if pddb_buffer.retcode == PddbRetcode::Ok {
-                let contents = &pddb_buffer.data[0..pddb_buffer.len as usize];
-                for (src, dest) in contents.iter().zip(buf.iter_mut()) {
-                    *dest = *src;
+    /// Fill `buf` with data from the key starting at `pos`, without moving
+    /// the cursor. Returns `UnexpectedEof` if the key is too short.
+    pub fn read_exact_at(&self, mut buf: &mut [u8], mut pos: u64) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, pos)? {
+                0 => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
+                n => {
+                    buf = &mut buf[n..];
+                    pos += n as u64;
                 }
-                self.offset += pddb_buffer.len as u64;
-                Ok(pddb_buffer.len.into())
-            } else {
-                println!("Pddb error: {:?}", pddb_buffer);
-                Err(std::io::Error::new(pddb_buffer.retcode.into(), "key error"))
             }
-        } else {
-            println!("Unexpected return: {:?}", result);
-            Err(std::io::Error::new(std::io::ErrorKind::Other, "key error"))
         }
+        Ok(())
     }
-}
 
-impl std::io::Write for Key {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let mut pddb_buffer = PddbBuf {
-            /// api token for the given buffer
-            token: self.fd,
-            /// a field reserved for the return code
-            retcode: PddbRetcode::Uninit,
-            reserved: 0,
-            /// length of the data field
-            len: buf.len().try_into().unwrap_or(u16::MAX).min(4072),
-            /// point in the key stream. 64-bit for future-compatibility; but, can't be larger than 32 bits on a 32-bit target.
-            position: self.offset,
-            data: [0u8; 4072],
-        };
+    /// Write all of `buf` to the key starting at `pos`, without moving the cursor.
+    pub fn write_all_at(&self, mut buf: &[u8], mut pos: u64) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            match self.write_at(buf, pos)? {
+                0 => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                n => {
+                    buf = &buf[n..];
+                    pos += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        // Copy the data to the buffer for writing
-        for (src, dest) in buf.iter().zip(pddb_buffer.data.iter_mut()) {
-            *dest = *src;
+    /// Build the pages needed to transfer `len` bytes starting at `position`.
+    fn pages(&self, position: u64, len: usize) -> Vec<PddbBuf> {
+        let len = len.min(MAX_PAGES * PAGE_DATA_LEN);
+        (0..len.div_ceil(PAGE_DATA_LEN).max(1))
+            .map(|index| {
+                let start = index * PAGE_DATA_LEN;
+                PddbBuf::new(
+                    self.fd,
+                    position + start as u64,
+                    (len - start).min(PAGE_DATA_LEN),
+                )
+            })
+            .collect()
+    }
+
+    /// Send one or more pages to the server. Returns the number of bytes
+    /// that were transferred, stopping at the first page that came back short.
+    fn transfer(&self, write: bool, pages: &mut [PddbBuf]) -> std::io::Result<usize> {
+        let requested: Vec<u16> = pages.iter().map(|page| page.len).collect();
+
+        let sent = pages.len() > 1 && self.features.contains(Features::MULTI_PAGE_IO);
+        if sent {
+            let opcode = if write {
+                crate::Opcodes::WriteKeyMulti
+            } else {
+                crate::Opcodes::ReadKeyMulti
+            };
+            Self::send_pages(self.connection, opcode, pages)?;
         }
 
-        let memory_range = unsafe {
-            xous::MemoryRange::new(
-                &mut pddb_buffer as *mut _ as usize,
-                core::mem::size_of::<PddbBuf>(),
-            )
-            .unwrap()
-        };
+        let mut total = 0;
+        for (index, page) in pages.iter_mut().enumerate() {
+            if !sent {
+                let opcode = if write {
+                    crate::Opcodes::WriteKeyStd
+                } else {
+                    crate::Opcodes::ReadKeyStd
+                };
+                Self::send_pages(self.connection, opcode, core::slice::from_mut(page))?;
+            }
+            if page.retcode() != PddbRetcode::Ok {
+                if total == 0 {
+                    return Err(std::io::Error::new(page.retcode().into(), "key error"));
+                }
+                break;
+            }
+            total += page.len as usize;
+            if page.len < requested[index] {
+                break;
+            }
+        }
+        Ok(total)
+    }
 
-        let result = xous::send_message(
-            self.connection,
+    fn send_pages(
+        connection: xous::CID,
+        opcode: crate::Opcodes,
+        pages: &mut [PddbBuf],
+    ) -> std::io::Result<()> {
+        let size = core::mem::size_of_val(pages);
+        let memory_range =
+            unsafe { xous::MemoryRange::new(pages.as_mut_ptr() as usize, size).unwrap() };
+
+        let result = crate::transport::send_message(
+            connection,
             xous::Message::new_lend_mut(
-                crate::Opcodes::WriteKeyStd as usize,
+                opcode as usize,
                 memory_range,
                 None,
-                core::num::NonZeroUsize::new(4096),
+                core::num::NonZeroUsize::new(size),
             ),
         );
 
         if let Ok(xous::Result::MemoryReturned(_, _)) = result {
-            if pddb_buffer.retcode == PddbRetcode::Ok {
-                self.offset += pddb_buffer.len as u64;
-                // If we've written past the end of the file, update the file length
-                if self.offset > self.len {
-                    self.len = self.offset;
+            Ok(())
+        } else {
+            Err(std::io::Error::other(format!(
+                "unexpected return: {:?}",
+                result
+            )))
+        }
+    }
+
+    fn check_readable(&self) -> std::io::Result<()> {
+        if self.readable {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "key was not opened for reading",
+            ))
+        }
+    }
+
+    fn check_writable(&self) -> std::io::Result<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "key was not opened for writing",
+            ))
+        }
+    }
+
+    /// Read up to `len` bytes from `pos` into `dest`. Reads are cut
+    /// short at the end of the key, and return 0 at or past the end.
+    fn read_at_into<'a>(
+        &self,
+        pos: u64,
+        len: usize,
+        dest: impl Iterator<Item = &'a mut u8>,
+    ) -> std::io::Result<usize> {
+        self.check_readable()?;
+        if len == 0 {
+            return Ok(0);
+        }
+        // The key may have been extended through another handle, so check
+        // with the server before reporting the end of the key. Servers that
+        // can't report the length are asked for the data instead, and the
+        // read comes back short at the end.
+        let metadata = self.features.contains(Features::METADATA);
+        let len = if metadata {
+            if pos >= self.len() {
+                self.query_len()?;
+                if pos >= self.len() {
+                    return Ok(0);
+                }
+            }
+            len.min((self.len() - pos).try_into().unwrap_or(usize::MAX))
+        } else {
+            len
+        };
+        let mut pages = self.pages(pos, len);
+        let count = match self.transfer(false, &mut pages) {
+            Ok(count) => count,
+            // The key was shortened through another handle
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                if metadata {
+                    self.query_len()?;
+                } else {
+                    self.len.fetch_min(pos, Ordering::Relaxed);
                 }
-                Ok(pddb_buffer.len.into())
+                return Ok(0);
+            }
+            Err(e) => return Err(e),
+        };
+        if !metadata && count < len.min(MAX_PAGES * PAGE_DATA_LEN) {
+            // A short read ends at the end of the key
+            if count == 0 {
+                self.len.fetch_min(pos, Ordering::Relaxed);
             } else {
-                println!("Pddb error: {:?}", pddb_buffer);
-                Err(std::io::Error::new(pddb_buffer.retcode.into(), "key error"))
+                self.len.store(pos + count as u64, Ordering::Relaxed);
+            }
+        }
+        for (src, dest) in pages
+            .iter()
+            .flat_map(|page| page.data.iter())
+            .take(count)
+            .zip(dest)
+        {
+            *dest = *src;
+        }
+        Ok(count)
+    }
+
+    /// Write up to `len` bytes taken from `src` at `pos`.
+    fn write_at_from<'a>(
+        &self,
+        pos: u64,
+        len: usize,
+        src: impl Iterator<Item = &'a u8>,
+    ) -> std::io::Result<usize> {
+        self.check_writable()?;
+        let mut pages = self.pages(pos, len);
+        for (src, dest) in src.zip(pages.iter_mut().flat_map(|page| page.data.iter_mut())) {
+            *dest = *src;
+        }
+        let count = self.transfer(true, &mut pages)?;
+        // If we've written past the end of the file, update the file length
+        self.len.fetch_max(pos + count as u64, Ordering::Relaxed);
+        Ok(count)
+    }
+
+    /// Send any buffered writes to the server.
+    fn flush_buffer(&self) -> std::io::Result<()> {
+        let mut buffers = self.buffers.lock().unwrap();
+        self.send_buffer(&mut buffers)
+    }
+
+    fn send_buffer(&self, buffers: &mut Buffers) -> std::io::Result<()> {
+        while !buffers.write.is_empty() {
+            let count =
+                self.write_at_from(buffers.write_pos, buffers.write.len(), buffers.write.iter())?;
+            if count == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    "failed to write buffered data",
+                ));
             }
+            buffers.write.drain(..count);
+            buffers.write_pos += count as u64;
+        }
+        Ok(())
+    }
+
+    fn discard_read_ahead(&self) {
+        self.buffers.lock().unwrap().read.clear();
+    }
+
+    /// Read up to `len` bytes from the current offset into `dest`.
+    fn read_into<'a>(
+        &mut self,
+        len: usize,
+        dest: impl Iterator<Item = &'a mut u8>,
+    ) -> std::io::Result<usize> {
+        let mut buffers = self.buffers.lock().unwrap();
+        self.send_buffer(&mut buffers)?;
+
+        let offset = self.offset;
+        let cached = |buffers: &Buffers| {
+            offset >= buffers.read_pos && offset - buffers.read_pos < buffers.read.len() as u64
+        };
+        if self.read_ahead && !cached(&buffers) && len < PAGE_DATA_LEN {
+            buffers.read.resize(PAGE_DATA_LEN, 0);
+            buffers.read_pos = offset;
+            let result = self.read_at_into(offset, PAGE_DATA_LEN, buffers.read.iter_mut());
+            buffers.read.truncate(*result.as_ref().unwrap_or(&0));
+            // Nothing was read, so the cursor is at or past the end of the key
+            if result? == 0 {
+                return Ok(0);
+            }
+        }
+        let count = if self.read_ahead && cached(&buffers) {
+            let start = (offset - buffers.read_pos) as usize;
+            let available = &buffers.read[start..];
+            for (src, dest) in available.iter().take(len).zip(dest) {
+                *dest = *src;
+            }
+            len.min(available.len())
         } else {
-            println!("Unexpected return: {:?}", result);
-            Err(std::io::Error::new(std::io::ErrorKind::Other, "key error"))
+            drop(buffers);
+            self.read_at_into(offset, len, dest)?
+        };
+        self.offset += count as u64;
+        Ok(count)
+    }
+
+    /// Write up to `len` bytes taken from `src` at the current offset.
+    fn write_from<'a>(
+        &mut self,
+        len: usize,
+        src: impl Iterator<Item = &'a u8>,
+    ) -> std::io::Result<usize> {
+        self.check_writable()?;
+        if self.append {
+            self.offset = self.len();
         }
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.read.clear();
+
+        let offset = self.offset;
+        let count = if self.write_policy == WritePolicy::WriteThrough {
+            drop(buffers);
+            self.write_at_from(offset, len, src)?
+        } else {
+            // Send the buffer if it is full, or if this write doesn't follow on from it
+            let buffer_end = buffers.write_pos + buffers.write.len() as u64;
+            if !buffers.write.is_empty()
+                && (buffer_end != offset || buffers.write.len() >= PAGE_DATA_LEN)
+            {
+                self.send_buffer(&mut buffers)?;
+            }
+            if buffers.write.is_empty() && len >= PAGE_DATA_LEN {
+                drop(buffers);
+                self.write_at_from(offset, len, src)?
+            } else {
+                if buffers.write.is_empty() {
+                    buffers.write_pos = offset;
+                }
+                let count = len.min(PAGE_DATA_LEN - buffers.write.len());
+                buffers.write.extend(src.take(count));
+                self.len.fetch_max(offset + count as u64, Ordering::Relaxed);
+                count
+            }
+        };
+        self.offset += count as u64;
+        Ok(count)
+    }
+}
+
+impl std::io::Read for Key {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read_into(buf.len(), buf.iter_mut())
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [std::io::IoSliceMut<'_>]) -> std::io::Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        self.read_into(len, bufs.iter_mut().flat_map(|buf| buf.iter_mut()))
+    }
+}
+
+impl std::io::Write for Key {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_from(buf.len(), buf.iter())
+    }
+
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        self.write_from(len, bufs.iter().flat_map(|buf| buf.iter()))
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        let result = xous::send_message(
+        self.flush_buffer()?;
+        let result = crate::transport::send_message(
             self.connection,
             xous::Message::new_blocking_scalar(
                 crate::Opcodes::WriteKeyFlush as usize,
@@ -387,7 +1424,10 @@ impl std::io::Write for Key {
                 ))
             }
         } else {
-            panic!("Unexpected return from WriteKeyFlush: {:?}", result);
+            Err(std::io::Error::other(format!(
+                "unexpected return from WriteKeyFlush: {:?}",
+                result
+            )))
         }
     }
 }
@@ -395,7 +1435,7 @@ impl std::io::Write for Key {
 impl std::io::Seek for Key {
     fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
         fn seek_from_point(this: &mut Key, point: u64, by: i64) -> std::io::Result<u64> {
-            let by64 = by as u64;
+            let by64 = by.unsigned_abs();
             // Note that it's possible to seek past the end of a key, and in this case
             // the `offset` will be greater than the `len`. This is fine, and `len` will
             // be updated as soon as `write()` is called.
@@ -415,14 +1455,34 @@ impl std::io::Seek for Key {
         match pos {
             SeekFrom::Start(offset) => seek_from_point(self, 0, offset as i64),
             SeekFrom::Current(by) => seek_from_point(self, self.offset, by),
-            SeekFrom::End(by) => seek_from_point(self, self.len, by),
+            SeekFrom::End(by) => {
+                // Servers that can't report the length leave the cached one,
+                // which was last updated by a write or a short read.
+                if self.features.contains(Features::METADATA) {
+                    self.refresh_len()?;
+                }
+                seek_from_point(self, self.len(), by)
+            }
         }
     }
 }
 
 impl Drop for Key {
     fn drop(&mut self) {
-        let result = xous::send_message(
+        // There's nobody left to report errors to, so they can only be logged
+        let flushed = if self.write_policy == WritePolicy::FlushOnDrop {
+            std::io::Write::flush(self)
+        } else {
+            self.flush_buffer()
+        };
+        if let Err(e) = flushed {
+            eprintln!(
+                "pddb: unable to write buffered data when dropping key: {}",
+                e
+            );
+        }
+
+        let result = crate::transport::send_message(
             self.connection,
             xous::Message::new_blocking_scalar(
                 crate::Opcodes::KeyDrop as usize,
@@ -433,15 +1493,29 @@ impl Drop for Key {
             ),
         );
 
-        if let Ok(xous::Result::Scalar1(1)) = result {
-        } else {
-            panic!("Unexpected return from KeyDrop: {:?}", result);
+        // The handle is gone as far as this side is concerned, so a failure
+        // here can only be logged.
+        if !matches!(result, Ok(xous::Result::Scalar1(1))) {
+            eprintln!("pddb: unexpected return from KeyDrop: {:?}", result);
         }
     }
 }
 
+fn invalid_response() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "invalid response from server",
+    )
+}
+
 impl ListKeyRequest {
-    pub fn new(basis: Option<&str>, dict: &str) -> ListKeyRequest {
+    /// Build a request for the keys in `dict`. Fails if either name can't
+    /// be stored in the PDDB, which also guarantees that they fit.
+    pub fn new(basis: Option<&str>, dict: &str) -> std::io::Result<ListKeyRequest> {
+        if let Some(basis) = basis {
+            crate::path::validate_name(EntryKind::Basis, basis)?;
+        }
+        crate::path::validate_name(EntryKind::Dict, dict)?;
         let mut this = ListKeyRequest { data: [0u8; 4096] };
         Self::set_version(&mut this, 1);
         Self::set_basis(&mut this, basis);
@@ -449,10 +1523,10 @@ impl ListKeyRequest {
         // This call must come after `set_basis()`.
         Self::set_dict(&mut this, dict);
 
-        this
+        Ok(this)
     }
 
-    pub fn invoke(mut self, connection: u32) -> Result<KeyList, ()> {
+    pub fn invoke(mut self, connection: u32) -> std::io::Result<KeyList> {
         let memory_range = unsafe {
             xous::MemoryRange::new(
                 &mut self.data as *mut _ as usize,
@@ -461,7 +1535,7 @@ impl ListKeyRequest {
             .unwrap()
         };
 
-        let result = xous::send_message(
+        let result = crate::transport::send_message(
             connection,
             xous::Message::new_lend_mut(
                 crate::Opcodes::ListKeyStd as usize,
@@ -472,9 +1546,14 @@ impl ListKeyRequest {
         );
 
         if let Ok(xous::Result::MemoryReturned(_, _)) = result {
-            Ok(KeyList::new(self.data).unwrap())
+            KeyList::new(self.data).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid key list")
+            })
         } else {
-            Err(())
+            Err(std::io::Error::other(format!(
+                "unexpected return: {:?}",
+                result
+            )))
         }
     }
 
@@ -502,7 +1581,7 @@ impl ListKeyRequest {
                 *dest = *src;
             }
             // Copy the name bytes
-            for (src, dest) in basis.as_bytes().iter().zip(self.data[4..].iter_mut()) {
+            for (src, dest) in basis.as_bytes().iter().zip(self.data[8..].iter_mut()) {
                 *dest = *src;
             }
         }
@@ -536,44 +1615,92 @@ impl ListKeyRequest {
     }
 }
 
+/// A list of key names. This is either the whole of a dict, or a single
+/// page of it as returned by `Pddb::list_keys_page()`.
 pub struct KeyList {
-    data: [u8; 4096],
+    names: Vec<String>,
+    next: Option<String>,
 }
 
 impl KeyList {
+    /// Parse a list in the original hand-packed layout, where the count and
+    /// each name length are a single byte. Returns `None` if the version is
+    /// unknown or the list doesn't fit in the buffer.
     pub fn new(buffer: [u8; 4096]) -> Option<Self> {
         let version = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
         if version != 1 {
             return None;
         }
-        Some(KeyList { data: buffer })
+        let count = buffer[4] as usize;
+        let lengths = buffer.get(5..5 + count)?;
+        let mut offset = 5 + count;
+        let mut names = Vec::with_capacity(count);
+        for &len in lengths {
+            let name = buffer.get(offset..offset + len as usize)?;
+            names.push(core::str::from_utf8(name).ok()?.to_owned());
+            offset += len as usize;
+        }
+        Some(KeyList { names, next: None })
+    }
+
+    /// Read a page from a `LskR` reply: a u32 count, the names, and the
+    /// cursor for the next page.
+    pub(crate) fn read_from(
+        reader: &crate::senres::Reader<'_, crate::senres::Stack<4096>>,
+    ) -> std::io::Result<Self> {
+        let count = reader.try_get_from::<u32>().or(Err(invalid_response()))?;
+        let names = (0..count)
+            .map(|_| reader.try_get_from::<String>().or(Err(invalid_response())))
+            .collect::<std::io::Result<_>>()?;
+        let next = reader
+            .try_get_from::<Option<String>>()
+            .or(Err(invalid_response()))?;
+        Ok(KeyList { names, next })
+    }
+
+    pub(crate) fn from_names(names: Vec<String>) -> Self {
+        KeyList { names, next: None }
+    }
+
+    pub(crate) fn into_names(self) -> Vec<String> {
+        self.names
+    }
+
+    /// The cursor to pass to `Pddb::list_keys_page()` to get the page after
+    /// this one, or `None` if this is the last page.
+    pub fn next_cursor(&self) -> Option<&str> {
+        self.next.as_deref()
     }
 
     pub fn len(&self) -> usize {
-        self.data[4] as usize
+        self.names.len()
     }
 
-    pub fn iter(&self) -> KeyListIter {
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn iter(&self) -> KeyListIter<'_> {
         KeyListIter::new(self)
     }
 }
 
+impl<'a> IntoIterator for &'a KeyList {
+    type Item = &'a str;
+    type IntoIter = KeyListIter<'a>;
+    fn into_iter(self) -> KeyListIter<'a> {
+        self.iter()
+    }
+}
+
 pub struct KeyListIter<'a> {
-    data: &'a [u8],
-    index: usize,
-    running_offset: usize,
+    names: std::slice::Iter<'a, String>,
 }
 
 impl<'a> KeyListIter<'a> {
     pub fn new(list: &'a KeyList) -> Self {
-        let len = list.data[4] as usize;
         KeyListIter {
-            data: list.data.as_slice(),
-            index: 0,
-            // Set the running offset to point at the first entry, which is
-            // 4 bytes of version plus one byte of length data plus the
-            // length of the lengths.
-            running_offset: 4 + 1 + len,
+            names: list.names.iter(),
         }
     }
 }
@@ -581,20 +1708,12 @@ impl<'a> KeyListIter<'a> {
 impl<'a> Iterator for KeyListIter<'a> {
     type Item = &'a str;
     fn next(&mut self) -> Option<Self::Item> {
-        let current_value = core::str::from_utf8(
-            &self.data
-                [self.running_offset..self.running_offset + self.data[4 + 1 + self.index] as usize],
-        )
-        .ok();
-
-        if self.index >= self.data[4] as usize {
-            return None;
-        }
-
-        // Skip past the current string in preparation for the next string
-        self.running_offset += self.data[4 + 1 + self.index] as usize;
-        self.index += 1;
+        self.names.next().map(|name| name.as_str())
+    }
 
-        current_value
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.names.size_hint()
     }
 }
+
+impl ExactSizeIterator for KeyListIter<'_> {}
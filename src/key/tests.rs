@@ -0,0 +1,1079 @@
+use std::io::{ErrorKind, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write};
+
+use super::{
+    open_flags, KeyOptions, ListKeyRequest, OpenKeyRequest, WritePolicy, MAX_PAGES,
+    OPEN_KEY_VERSION, PAGE_DATA_LEN,
+};
+use crate::capabilities::{Capabilities, Features, CAPABILITIES_VERSION};
+use crate::mock::{MockPddb, SYSTEM_BASIS};
+use crate::senres::{Senres, SenresMut, Stack};
+
+fn mock() -> MockPddb {
+    let mock = MockPddb::new();
+    mock.insert_key(None, "sys.rtc", "tz_offset", &[1, 2, 3, 4]);
+    mock
+}
+
+fn contents(key: &mut super::Key) -> Vec<u8> {
+    let mut v = vec![];
+    key.rewind().unwrap();
+    key.read_to_end(&mut v).unwrap();
+    v
+}
+
+#[test]
+fn open_existing() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    assert_eq!(contents(&mut key), [1, 2, 3, 4]);
+}
+
+#[test]
+fn read_only() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb
+        .open_key_with(None, "sys.rtc", "tz_offset", KeyOptions::new().read(true))
+        .unwrap();
+    assert_eq!(contents(&mut key), [1, 2, 3, 4]);
+    assert_eq!(
+        key.write(&[5]).unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "tz_offset").unwrap(),
+        [1, 2, 3, 4]
+    );
+}
+
+#[test]
+fn write_only() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb
+        .open_key_with(None, "sys.rtc", "tz_offset", KeyOptions::new().write(true))
+        .unwrap();
+    let mut buf = [0u8; 4];
+    assert_eq!(
+        key.read(&mut buf).unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    key.write_all(&[9]).unwrap();
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "tz_offset").unwrap(),
+        [9, 2, 3, 4]
+    );
+}
+
+#[test]
+fn create_missing_key() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let opts = KeyOptions::new().write(true).create(true).clone();
+
+    let mut key = pddb.open_key_with(None, "sys.rtc", "new", &opts).unwrap();
+    key.write_all(b"new").unwrap();
+    drop(key);
+    assert_eq!(mock.key_contents(None, "sys.rtc", "new").unwrap(), b"new");
+
+    // Opening it again with `create` leaves the contents alone
+    pddb.open_key_with(None, "sys.rtc", "new", &opts).unwrap();
+    assert_eq!(mock.key_contents(None, "sys.rtc", "new").unwrap(), b"new");
+}
+
+#[test]
+fn create_in_missing_dict() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut opts = KeyOptions::new();
+    opts.write(true).create(true);
+
+    assert_eq!(
+        pddb.open_key_with(None, "new.dict", "key", &opts)
+            .err()
+            .unwrap()
+            .kind(),
+        ErrorKind::NotFound
+    );
+
+    opts.create_dict(true);
+    pddb.open_key_with(None, "new.dict", "key", &opts).unwrap();
+    assert_eq!(mock.key_contents(None, "new.dict", "key").unwrap(), b"");
+}
+
+#[test]
+fn create_in_basis() {
+    let mock = mock();
+    mock.add_basis("secret");
+    let pddb = mock.connect();
+    let mut opts = KeyOptions::new();
+    opts.write(true).create(true).create_dict(true);
+
+    pddb.open_key_with(Some(SYSTEM_BASIS), "sys.rtc", "new", &opts)
+        .unwrap();
+    assert!(mock
+        .key_contents(Some(SYSTEM_BASIS), "sys.rtc", "new")
+        .is_some());
+    assert!(mock
+        .key_contents(Some("secret"), "sys.rtc", "new")
+        .is_none());
+
+    // Without a basis, new keys land in the most recent basis
+    pddb.open_key_with(None, "sys.rtc", "newer", &opts).unwrap();
+    assert!(mock
+        .key_contents(Some("secret"), "sys.rtc", "newer")
+        .is_some());
+}
+
+#[test]
+fn create_new() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut opts = KeyOptions::new();
+    opts.write(true).create_new(true);
+
+    assert_eq!(
+        pddb.open_key_with(None, "sys.rtc", "tz_offset", &opts)
+            .err()
+            .unwrap()
+            .kind(),
+        ErrorKind::AlreadyExists
+    );
+    pddb.open_key_with(None, "sys.rtc", "new", &opts).unwrap();
+    assert!(mock.key_contents(None, "sys.rtc", "new").is_some());
+
+    // `create_new` ignores `create` and `truncate`
+    opts.create(true).truncate(true);
+    assert_eq!(
+        pddb.open_key_with(None, "sys.rtc", "tz_offset", &opts)
+            .err()
+            .unwrap()
+            .kind(),
+        ErrorKind::AlreadyExists
+    );
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "tz_offset").unwrap(),
+        [1, 2, 3, 4]
+    );
+}
+
+#[test]
+fn truncate() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb
+        .open_key_with(
+            None,
+            "sys.rtc",
+            "tz_offset",
+            KeyOptions::new().read(true).write(true).truncate(true),
+        )
+        .unwrap();
+    assert_eq!(contents(&mut key), []);
+    key.write_all(&[42]).unwrap();
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "tz_offset").unwrap(),
+        [42]
+    );
+}
+
+#[test]
+fn append() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb
+        .open_key_with(
+            None,
+            "sys.rtc",
+            "tz_offset",
+            KeyOptions::new().read(true).append(true),
+        )
+        .unwrap();
+    assert_eq!(key.stream_position().unwrap(), 4);
+    key.write_all(&[5]).unwrap();
+    key.seek(SeekFrom::Start(0)).unwrap();
+    key.write_all(&[6]).unwrap();
+    assert_eq!(contents(&mut key), [1, 2, 3, 4, 5, 6]);
+}
+
+#[test]
+fn alloc_hint_and_callback() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let sid = xous::SID::from_bytes(b"pddb-callback-00").unwrap();
+    let mut key = pddb
+        .open_key_with(
+            None,
+            "sys.rtc",
+            "hinted",
+            KeyOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .alloc_hint(8192)
+                .callback(Some(sid)),
+        )
+        .unwrap();
+    key.write_all(&[1]).unwrap();
+    assert_eq!(mock.key_contents(None, "sys.rtc", "hinted").unwrap(), [1]);
+}
+
+#[test]
+fn invalid_combinations() {
+    let mock = mock();
+    let pddb = mock.connect();
+    for opts in [
+        KeyOptions::new(),
+        KeyOptions::new().read(true).create(true).clone(),
+        KeyOptions::new().read(true).create_new(true).clone(),
+        KeyOptions::new().read(true).truncate(true).clone(),
+        KeyOptions::new().append(true).truncate(true).clone(),
+    ] {
+        assert_eq!(
+            pddb.open_key_with(None, "sys.rtc", "tz_offset", &opts)
+                .err()
+                .unwrap()
+                .kind(),
+            ErrorKind::InvalidInput,
+            "{:?}",
+            opts
+        );
+    }
+    assert_eq!(mock.open_handles(), 0);
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn create(pddb: &crate::Pddb, key: &str) -> super::Key {
+    pddb.open_key_with(
+        None,
+        "sys.rtc",
+        key,
+        KeyOptions::new().read(true).write(true).create(true),
+    )
+    .unwrap()
+}
+
+#[test]
+fn large_write_is_not_short() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let data = pattern(MAX_PAGES * PAGE_DATA_LEN + 100);
+    let mut key = create(&pddb, "large");
+
+    assert_eq!(key.write(&data).unwrap(), MAX_PAGES * PAGE_DATA_LEN);
+    key.write_all(&data[MAX_PAGES * PAGE_DATA_LEN..]).unwrap();
+    assert_eq!(mock.key_contents(None, "sys.rtc", "large").unwrap(), data);
+
+    let mut buf = vec![0u8; data.len()];
+    key.rewind().unwrap();
+    key.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+    assert_eq!(
+        key.read_exact(&mut [0u8; 1]).unwrap_err().kind(),
+        ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+fn read_stops_at_end_of_key() {
+    let mock = mock();
+    let data = pattern(PAGE_DATA_LEN * 2 + 17);
+    mock.insert_key(None, "sys.rtc", "large", &data);
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "large").unwrap();
+
+    let mut buf = vec![0u8; PAGE_DATA_LEN * 4];
+    assert_eq!(key.read(&mut buf).unwrap(), data.len());
+    assert_eq!(&buf[..data.len()], data);
+}
+
+#[test]
+fn vectored() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = create(&pddb, "vectored");
+
+    let first = pattern(5000);
+    let second = vec![0xaa; 3000];
+    let written = key
+        .write_vectored(&[
+            IoSlice::new(&first),
+            IoSlice::new(&[]),
+            IoSlice::new(&second),
+        ])
+        .unwrap();
+    assert_eq!(written, 8000);
+    let mut expected = first.clone();
+    expected.extend_from_slice(&second);
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "vectored").unwrap(),
+        expected
+    );
+
+    key.rewind().unwrap();
+    let mut a = [0u8; 10];
+    let mut b = vec![0u8; 7000];
+    let mut c = vec![0u8; 100];
+    let read = key
+        .read_vectored(&mut [
+            IoSliceMut::new(&mut a),
+            IoSliceMut::new(&mut b),
+            IoSliceMut::new(&mut c),
+        ])
+        .unwrap();
+    assert_eq!(read, 7110);
+    assert_eq!(a, expected[..10]);
+    assert_eq!(b, expected[10..7010]);
+    assert_eq!(c, expected[7010..7110]);
+}
+
+#[test]
+fn single_pages_without_multi_page_io() {
+    let mock = mock();
+    mock.single_page_only();
+    let pddb = mock.connect();
+    let data = pattern(PAGE_DATA_LEN * 3 + 1);
+    let mut key = create(&pddb, "large");
+    let before = mock.message_count();
+    key.write_all(&data).unwrap();
+    assert_eq!(mock.message_count() - before, 4);
+    let mut v = vec![];
+    key.rewind().unwrap();
+    key.read_to_end(&mut v).unwrap();
+    assert_eq!(v, data);
+}
+
+/// Move a megabyte through the mock server in both directions, comparing
+/// the number of messages needed with and without multi-page transfers.
+/// Run with `--nocapture` to see the throughput.
+#[test]
+fn throughput() {
+    fn measure(mock: &MockPddb) -> usize {
+        let pddb = mock.connect();
+        let data = pattern(1024 * 1024);
+        let mut key = create(&pddb, "throughput");
+        let before = mock.message_count();
+        let start = std::time::Instant::now();
+
+        key.write_all(&data).unwrap();
+        key.rewind().unwrap();
+        let mut buf = vec![0u8; data.len()];
+        key.read_exact(&mut buf).unwrap();
+
+        let elapsed = start.elapsed();
+        let messages = mock.message_count() - before;
+        assert_eq!(buf, data);
+        println!(
+            "2 MiB moved in {:?} ({:.1} MiB/s) using {} messages",
+            elapsed,
+            2.0 / elapsed.as_secs_f64(),
+            messages
+        );
+        messages
+    }
+
+    let single = mock();
+    single.single_page_only();
+    let single = measure(&single);
+    let multi = measure(&mock());
+
+    let pages = 2 * (1024 * 1024usize).div_ceil(PAGE_DATA_LEN);
+    // Older servers get one message per page
+    assert_eq!(single, pages);
+    assert!(multi <= pages.div_ceil(MAX_PAGES) + 2, "{}", multi);
+}
+
+#[test]
+fn read_at_end() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    key.seek(SeekFrom::End(0)).unwrap();
+    assert_eq!(key.read(&mut [0u8; 16]).unwrap(), 0);
+    assert_eq!(key.read(&mut [0u8; 16]).unwrap(), 0);
+}
+
+#[test]
+fn read_past_end() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    key.seek(SeekFrom::Start(100)).unwrap();
+    assert_eq!(key.read(&mut [0u8; 16]).unwrap(), 0);
+    assert_eq!(
+        key.read_exact(&mut [0u8; 1]).unwrap_err().kind(),
+        ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+fn read_straddling_end() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    key.seek(SeekFrom::Start(2)).unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(key.read(&mut buf).unwrap(), 2);
+    assert_eq!(buf[..2], [3, 4]);
+    assert_eq!(key.stream_position().unwrap(), 4);
+    assert_eq!(key.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn length_changed_elsewhere() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    assert_eq!(key.len(), 4);
+
+    // Grown through another handle
+    mock.insert_key(None, "sys.rtc", "tz_offset", &[1, 2, 3, 4, 5, 6]);
+    assert_eq!(key.len(), 4);
+    assert_eq!(contents(&mut key), [1, 2, 3, 4, 5, 6]);
+    assert_eq!(key.len(), 6);
+    assert_eq!(key.seek(SeekFrom::End(0)).unwrap(), 6);
+
+    // Shrunk through another handle
+    mock.insert_key(None, "sys.rtc", "tz_offset", &[1]);
+    key.seek(SeekFrom::Start(3)).unwrap();
+    assert_eq!(key.read(&mut [0u8; 16]).unwrap(), 0);
+    assert_eq!(key.len(), 1);
+    assert_eq!(key.refresh_len().unwrap(), 1);
+    assert_eq!(key.seek(SeekFrom::End(-1)).unwrap(), 0);
+}
+
+#[test]
+fn length_without_metadata() {
+    let mock = mock();
+    let features = Features::from_bits_truncate(Features::ALL.bits() & !Features::METADATA.bits());
+    mock.report_capabilities(Capabilities::new(
+        CAPABILITIES_VERSION,
+        OPEN_KEY_VERSION,
+        features,
+    ));
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    assert_eq!(
+        key.refresh_len().unwrap_err().kind(),
+        ErrorKind::Unsupported
+    );
+    assert_eq!(key.metadata().unwrap_err().kind(), ErrorKind::Unsupported);
+
+    // The length from the open reply is used until a short read shows otherwise
+    mock.insert_key(None, "sys.rtc", "tz_offset", &[1, 2, 3, 4, 5, 6]);
+    let before = mock.message_count();
+    assert_eq!(key.seek(SeekFrom::End(0)).unwrap(), 4);
+    assert_eq!(mock.message_count(), before);
+    assert_eq!(contents(&mut key), [1, 2, 3, 4, 5, 6]);
+    assert_eq!(key.len(), 6);
+
+    mock.insert_key(None, "sys.rtc", "tz_offset", &[1]);
+    assert_eq!(contents(&mut key), [1]);
+    assert_eq!(key.len(), 1);
+    assert_eq!(key.seek(SeekFrom::End(0)).unwrap(), 1);
+}
+
+#[test]
+fn length_tracks_writes() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = create(&pddb, "new");
+    assert!(key.is_empty());
+    key.write_all(&[1, 2, 3]).unwrap();
+    assert_eq!(key.len(), 3);
+    key.seek(SeekFrom::Start(10)).unwrap();
+    key.write_all(&[4]).unwrap();
+    assert_eq!(key.len(), 11);
+    assert_eq!(key.refresh_len().unwrap(), 11);
+}
+
+#[test]
+fn set_len_grow() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    key.set_len(6).unwrap();
+    assert_eq!(key.len(), 6);
+    assert_eq!(key.stream_position().unwrap(), 0);
+    assert_eq!(contents(&mut key), [1, 2, 3, 4, 0, 0]);
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "tz_offset").unwrap(),
+        [1, 2, 3, 4, 0, 0]
+    );
+}
+
+#[test]
+fn set_len_shrink() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    key.seek(SeekFrom::Start(1)).unwrap();
+    key.set_len(2).unwrap();
+    assert_eq!(key.len(), 2);
+    assert_eq!(key.stream_position().unwrap(), 1);
+    assert_eq!(key.seek(SeekFrom::End(0)).unwrap(), 2);
+    assert_eq!(contents(&mut key), [1, 2]);
+}
+
+#[test]
+fn set_len_below_cursor() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    key.seek(SeekFrom::End(0)).unwrap();
+    key.set_len(1).unwrap();
+    assert_eq!(key.stream_position().unwrap(), 1);
+    assert_eq!(key.read(&mut [0u8; 4]).unwrap(), 0);
+    key.write_all(&[7]).unwrap();
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "tz_offset").unwrap(),
+        [1, 7]
+    );
+
+    // Shrinking above the position leaves it alone
+    key.seek(SeekFrom::Start(1)).unwrap();
+    key.set_len(2).unwrap();
+    assert_eq!(key.stream_position().unwrap(), 1);
+}
+
+#[test]
+fn truncate_to_zero() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    key.seek(SeekFrom::Start(3)).unwrap();
+    key.truncate().unwrap();
+    assert!(key.is_empty());
+    assert_eq!(key.stream_position().unwrap(), 0);
+    assert_eq!(mock.key_contents(None, "sys.rtc", "tz_offset").unwrap(), []);
+}
+
+#[test]
+fn set_len_without_server_support() {
+    let mock = mock();
+    let features = Features::from_bits_truncate(Features::ALL.bits() & !Features::SET_LEN.bits());
+    mock.report_capabilities(Capabilities::new(
+        CAPABILITIES_VERSION,
+        OPEN_KEY_VERSION,
+        features,
+    ));
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    let before = mock.message_count();
+    assert_eq!(key.set_len(0).unwrap_err().kind(), ErrorKind::Unsupported);
+    assert_eq!(mock.message_count(), before);
+    assert_eq!(key.len(), 4);
+}
+
+#[test]
+fn set_len_needs_write_access() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb
+        .open_key_with(None, "sys.rtc", "tz_offset", KeyOptions::new().read(true))
+        .unwrap();
+    assert_eq!(
+        key.set_len(0).unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    assert_eq!(key.len(), 4);
+}
+
+#[test]
+fn read_at_leaves_cursor() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    key.seek(SeekFrom::Start(1)).unwrap();
+
+    let mut buf = [0u8; 2];
+    assert_eq!(key.read_at(&mut buf, 2).unwrap(), 2);
+    assert_eq!(buf, [3, 4]);
+    assert_eq!(key.read_at(&mut buf, 3).unwrap(), 1);
+    assert_eq!(key.read_at(&mut buf, 4).unwrap(), 0);
+    assert_eq!(key.read_at(&mut buf, 100).unwrap(), 0);
+    assert_eq!(key.stream_position().unwrap(), 1);
+
+    key.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, [1, 2]);
+    assert_eq!(
+        key.read_exact_at(&mut buf, 3).unwrap_err().kind(),
+        ErrorKind::UnexpectedEof
+    );
+    assert_eq!(key.read(&mut buf).unwrap(), 2);
+    assert_eq!(buf, [2, 3]);
+}
+
+#[test]
+fn write_at_leaves_cursor() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    key.seek(SeekFrom::Start(1)).unwrap();
+
+    assert_eq!(key.write_at(&[9, 9], 2).unwrap(), 2);
+    key.write_all_at(&[7], 5).unwrap();
+    assert_eq!(key.len(), 6);
+    assert_eq!(key.stream_position().unwrap(), 1);
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "tz_offset").unwrap(),
+        [1, 2, 9, 9, 0, 7]
+    );
+
+    let key = pddb
+        .open_key_with(None, "sys.rtc", "tz_offset", KeyOptions::new().read(true))
+        .unwrap();
+    assert_eq!(
+        key.write_at(&[1], 0).unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn shared_readers() {
+    let mock = mock();
+    let data = pattern(PAGE_DATA_LEN * 8);
+    mock.insert_key(None, "sys.rtc", "log", &data);
+    let pddb = mock.connect();
+    let key = pddb.open_key(None, "sys.rtc", "log").unwrap();
+
+    std::thread::scope(|s| {
+        for chunk in 0..8 {
+            let key = &key;
+            let data = &data;
+            s.spawn(move || {
+                let pos = chunk * PAGE_DATA_LEN;
+                let mut buf = vec![0u8; PAGE_DATA_LEN];
+                key.read_exact_at(&mut buf, pos as u64).unwrap();
+                assert_eq!(buf, data[pos..pos + PAGE_DATA_LEN]);
+            });
+        }
+    });
+}
+
+fn create_with(pddb: &crate::Pddb, key: &str, policy: WritePolicy, read_ahead: bool) -> super::Key {
+    pddb.open_key_with(
+        None,
+        "sys.rtc",
+        key,
+        KeyOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .write_policy(policy)
+            .read_ahead(read_ahead),
+    )
+    .unwrap()
+}
+
+/// Write 10000 bytes one at a time and read them back one at a time,
+/// returning the number of messages needed for each.
+fn byte_at_a_time(policy: WritePolicy, read_ahead: bool) -> (usize, usize) {
+    let mock = mock();
+    let pddb = mock.connect();
+    let data = pattern(10_000);
+    let mut key = create_with(&pddb, "bytes", policy, read_ahead);
+
+    let before = mock.message_count();
+    for byte in &data {
+        key.write_all(core::slice::from_ref(byte)).unwrap();
+    }
+    key.flush().unwrap();
+    let writes = mock.message_count() - before;
+    assert_eq!(mock.key_contents(None, "sys.rtc", "bytes").unwrap(), data);
+
+    key.rewind().unwrap();
+    let before = mock.message_count();
+    let mut byte = [0u8];
+    for expected in &data {
+        key.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], *expected);
+    }
+    let reads = mock.message_count() - before;
+    (writes, reads)
+}
+
+/// Run with `--nocapture` to see the number of messages for each policy.
+#[test]
+fn buffering_ipc_counts() {
+    let (through_writes, plain_reads) = byte_at_a_time(WritePolicy::WriteThrough, false);
+    let (buffered_writes, ahead_reads) = byte_at_a_time(WritePolicy::Buffered, true);
+    println!(
+        "10000 single-byte writes: {} messages write-through, {} buffered",
+        through_writes, buffered_writes
+    );
+    println!(
+        "10000 single-byte reads: {} messages without read-ahead, {} with",
+        plain_reads, ahead_reads
+    );
+
+    // One message per write, plus the flush
+    assert_eq!(through_writes, 10_001);
+    assert_eq!(plain_reads, 10_000);
+    // One message per page, plus the flush
+    assert_eq!(buffered_writes, 10_000usize.div_ceil(PAGE_DATA_LEN) + 1);
+    assert_eq!(ahead_reads, 10_000usize.div_ceil(PAGE_DATA_LEN));
+}
+
+#[test]
+fn buffered_writes_are_visible() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = create_with(&pddb, "buffered", WritePolicy::Buffered, false);
+    key.write_all(&[1, 2, 3]).unwrap();
+    assert_eq!(key.len(), 3);
+    assert_eq!(mock.key_contents(None, "sys.rtc", "buffered").unwrap(), []);
+
+    // Reads through the cursor and positional reads both see buffered data
+    let mut buf = [0u8; 2];
+    key.write_all(&[4]).unwrap();
+    assert_eq!(key.read_at(&mut buf, 2).unwrap(), 2);
+    assert_eq!(buf, [3, 4]);
+    key.write_all(&[5]).unwrap();
+    key.seek(SeekFrom::Start(3)).unwrap();
+    key.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [4, 5]);
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "buffered").unwrap(),
+        [1, 2, 3, 4, 5]
+    );
+}
+
+#[test]
+fn buffered_seek_sends_buffer() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = create_with(&pddb, "buffered", WritePolicy::Buffered, false);
+    key.write_all(&[1, 2, 3, 4]).unwrap();
+    key.seek(SeekFrom::Start(1)).unwrap();
+    key.write_all(&[9]).unwrap();
+    key.seek(SeekFrom::Start(6)).unwrap();
+    key.write_all(&[7]).unwrap();
+    assert_eq!(key.len(), 7);
+    drop(key);
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "buffered").unwrap(),
+        [1, 9, 3, 4, 0, 0, 7]
+    );
+}
+
+#[test]
+fn buffered_append() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb
+        .open_key_with(
+            None,
+            "sys.rtc",
+            "tz_offset",
+            KeyOptions::new()
+                .append(true)
+                .write_policy(WritePolicy::Buffered),
+        )
+        .unwrap();
+    key.write_all(&[5]).unwrap();
+    key.rewind().unwrap();
+    key.write_all(&[6]).unwrap();
+    key.set_write_policy(WritePolicy::WriteThrough).unwrap();
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "tz_offset").unwrap(),
+        [1, 2, 3, 4, 5, 6]
+    );
+}
+
+#[test]
+fn drop_sends_buffer() {
+    let mock = mock();
+    let pddb = mock.connect();
+
+    let mut key = create_with(&pddb, "buffered", WritePolicy::Buffered, false);
+    key.write_all(&[1]).unwrap();
+    let before = mock.message_count();
+    drop(key);
+    // The buffered write and the `KeyDrop`
+    assert_eq!(mock.message_count() - before, 2);
+    assert_eq!(mock.key_contents(None, "sys.rtc", "buffered").unwrap(), [1]);
+
+    let mut key = create_with(&pddb, "buffered", WritePolicy::FlushOnDrop, false);
+    key.write_all(&[2]).unwrap();
+    let before = mock.message_count();
+    drop(key);
+    // The buffered write, a `WriteKeyFlush` and the `KeyDrop`
+    assert_eq!(mock.message_count() - before, 3);
+    assert_eq!(mock.key_contents(None, "sys.rtc", "buffered").unwrap(), [2]);
+}
+
+#[test]
+fn read_ahead_at_end() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = create_with(&pddb, "tz_offset", WritePolicy::WriteThrough, true);
+    key.seek(SeekFrom::End(0)).unwrap();
+    let before = mock.message_count();
+    assert_eq!(key.read(&mut [0u8; 4]).unwrap(), 0);
+    assert_eq!(mock.message_count() - before, 1);
+}
+
+#[test]
+fn read_ahead_is_discarded_on_write() {
+    let mock = mock();
+    let pddb = mock.connect();
+    let mut key = pddb
+        .open_key_with(
+            None,
+            "sys.rtc",
+            "tz_offset",
+            KeyOptions::new().read(true).write(true).read_ahead(true),
+        )
+        .unwrap();
+    let mut byte = [0u8];
+    key.read_exact(&mut byte).unwrap();
+    assert_eq!(byte, [1]);
+
+    key.write_at(&[8, 8], 1).unwrap();
+    key.read_exact(&mut byte).unwrap();
+    assert_eq!(byte, [8]);
+
+    key.write_all(&[9]).unwrap();
+    key.read_exact(&mut byte).unwrap();
+    assert_eq!(byte, [4]);
+    key.rewind().unwrap();
+    assert_eq!(contents(&mut key), [1, 8, 9, 4]);
+}
+
+#[test]
+fn metadata() {
+    let mock = mock();
+    mock.add_basis("secret");
+    let pddb = mock.connect();
+
+    let key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    let metadata = key.metadata().unwrap();
+    assert!(metadata.is_key());
+    assert_eq!(metadata.len(), 4);
+    assert_eq!(metadata.basis(), SYSTEM_BASIS);
+    assert_eq!(metadata.reserved(), 4);
+    assert_eq!(metadata.alloc_hint(), 0);
+    assert!(metadata.is_flushed());
+
+    let mut key = pddb
+        .open_key_with(
+            None,
+            "sys.rtc",
+            "hinted",
+            KeyOptions::new()
+                .write(true)
+                .create(true)
+                .alloc_hint(8192)
+                .write_policy(WritePolicy::Buffered),
+        )
+        .unwrap();
+    key.write_all(&[1, 2]).unwrap();
+    let metadata = key.metadata().unwrap();
+    assert_eq!(metadata.len(), 2);
+    assert_eq!(metadata.basis(), "secret");
+    assert_eq!(metadata.reserved(), 8192);
+    assert_eq!(metadata.alloc_hint(), 8192);
+    assert!(!metadata.is_flushed());
+
+    key.flush().unwrap();
+    assert!(key.metadata().unwrap().is_flushed());
+}
+
+#[test]
+fn original_key_list_format() {
+    let mut buffer = [0u8; 4096];
+    buffer[0..4].copy_from_slice(&1u32.to_le_bytes());
+    buffer[4] = 2;
+    buffer[5] = 3;
+    buffer[6] = 2;
+    buffer[7..12].copy_from_slice(b"onetw");
+    let list = super::KeyList::new(buffer).unwrap();
+    assert_eq!(list.iter().collect::<Vec<_>>(), ["one", "tw"]);
+    assert_eq!(list.next_cursor(), None);
+
+    // Names that run off the end of the buffer are rejected rather than
+    // read out of bounds.
+    buffer[4] = 255;
+    buffer[5..260].fill(255);
+    assert!(super::KeyList::new(buffer).is_none());
+
+    buffer[0..4].copy_from_slice(&2u32.to_le_bytes());
+    assert!(super::KeyList::new(buffer).is_none());
+}
+
+/// The packed layout as `Key::open_with()` used to build it by hand.
+fn hand_rolled_open_request(
+    version: u32,
+    basis: Option<&str>,
+    dict: &str,
+    key: &str,
+    alloc_hint: u64,
+    cb_sid: Option<[u32; 4]>,
+    flags: u8,
+) -> [u8; 4096] {
+    let mut data = [0u8; 4096];
+    data[0..4].copy_from_slice(&version.to_le_bytes());
+    let mut offset = 4;
+    for name in [basis.unwrap_or(""), dict, key] {
+        data[offset..offset + 4].copy_from_slice(&(name.len() as u32).to_le_bytes());
+        offset += 4;
+        data[offset..offset + name.len()].copy_from_slice(name.as_bytes());
+        offset += name.len();
+    }
+    data[offset] = 1;
+    data[offset + 1] = 1;
+    offset += 2;
+    data[offset..offset + 8].copy_from_slice(&alloc_hint.to_le_bytes());
+    offset += 8;
+    if let Some(cb_sid) = cb_sid {
+        data[offset] = 1;
+        offset += 1;
+        for word in cb_sid {
+            data[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+            offset += 4;
+        }
+    } else {
+        offset += 1 + 16;
+    }
+    if version >= 2 {
+        data[offset] = flags;
+    }
+    data
+}
+
+fn open_request(version: u32, basis: Option<&str>, cb_sid: Option<[u32; 4]>) -> OpenKeyRequest {
+    let mut opts = KeyOptions::new();
+    opts.read(true)
+        .write(true)
+        .create(true)
+        .create_dict(true)
+        .truncate(true)
+        .alloc_hint(0x1234_5678_9abc)
+        .callback(cb_sid.map(xous::SID::from_array));
+    let mut request = OpenKeyRequest::new(basis, "sys.rtc", "tz_offset", &opts);
+    request.version = version;
+    request
+}
+
+#[test]
+fn packed_open_request_is_unchanged() {
+    for version in [1, 2] {
+        for basis in [None, Some("secret")] {
+            for cb_sid in [None, Some([1, 2, 3, 0xdead_beef])] {
+                let request = open_request(version, basis, cb_sid);
+                let mut data = [0u8; 4096];
+                request.encode_packed(&mut data).unwrap();
+                let expected = hand_rolled_open_request(
+                    version,
+                    basis,
+                    "sys.rtc",
+                    "tz_offset",
+                    0x1234_5678_9abc,
+                    cb_sid,
+                    request.flags,
+                );
+                assert_eq!(data, expected);
+
+                let decoded = OpenKeyRequest::decode_packed(&data).unwrap();
+                if version == 1 {
+                    assert_eq!(decoded.flags, open_flags::READ | open_flags::WRITE);
+                } else {
+                    assert_eq!(decoded, request);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn senres_open_request() {
+    for version in [1, 2] {
+        let request = open_request(version, Some("secret"), Some([1, 2, 3, 4]));
+        let mut message = Stack::<4096>::new();
+        request.write_to(&mut message.writer(*b"OpkQ").unwrap());
+        let reader = message.reader(*b"OpkQ").unwrap();
+        let decoded = OpenKeyRequest::read_from(&reader).unwrap();
+        assert_eq!(decoded.version, version);
+        assert_eq!(decoded.basis.as_deref(), Some("secret"));
+        assert_eq!(decoded.cb_sid, Some([1, 2, 3, 4]));
+        if version == 2 {
+            assert_eq!(decoded, request);
+        }
+    }
+
+    let mut request = open_request(2, None, None);
+    request.version = 3;
+    let mut message = Stack::<4096>::new();
+    request.write_to(&mut message.writer(*b"OpkQ").unwrap());
+    assert!(OpenKeyRequest::read_from(&message.reader(*b"OpkQ").unwrap()).is_none());
+}
+
+#[test]
+fn packed_open_request_bounds() {
+    let mut request = open_request(2, None, None);
+    request.key = "k".repeat(4096);
+    let mut data = [0u8; 4096];
+    assert_eq!(
+        request.encode_packed(&mut data).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+
+    // A name length that runs past the end of the buffer
+    data[0..4].copy_from_slice(&2u32.to_le_bytes());
+    data[4..8].copy_from_slice(&5000u32.to_le_bytes());
+    assert!(OpenKeyRequest::decode_packed(&data).is_none());
+}
+
+#[test]
+fn open_key_on_older_server() {
+    let mock = mock();
+    mock.legacy_open_key();
+    let pddb = mock.connect();
+
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    assert_eq!(contents(&mut key), [1, 2, 3, 4]);
+    let err = pddb
+        .open_key(None, "sys.rtc", "missing")
+        .map(drop)
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+
+    let before = mock.message_count();
+    let _key = pddb
+        .open_key_with(
+            None,
+            "sys.rtc",
+            "new",
+            KeyOptions::new().write(true).create_new(true),
+        )
+        .unwrap();
+    // Exclusive creation checks that the key can't be opened before creating it
+    assert_eq!(mock.message_count() - before, 2);
+    assert_eq!(mock.key_contents(None, "sys.rtc", "new"), Some(vec![]));
+}
+
+#[test]
+fn list_request_checks_names() {
+    assert!(ListKeyRequest::new(Some(SYSTEM_BASIS), "wlan.networks").is_ok());
+    let long_basis = "b".repeat(crate::path::BASIS_NAME_LEN + 1);
+    let long_dict = "d".repeat(crate::path::DICT_NAME_LEN + 1);
+    for (basis, dict) in [
+        (Some(long_basis.as_str()), "wlan.networks"),
+        (Some("a:b"), "wlan.networks"),
+        (None, long_dict.as_str()),
+        (None, ""),
+    ] {
+        assert_eq!(
+            ListKeyRequest::new(basis, dict)
+                .map(drop)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
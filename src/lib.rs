@@ -0,0 +1,81 @@
+//! # PDDB Raw Client
+//!
+//! A client for the Plausibly Deniable Database that speaks the raw
+//! message protocol rather than going through libstd. The [`Pddb`]
+//! struct is the entry point, and hands out [`Key`] objects that
+//! implement `Read`, `Write` and `Seek`.
+
+pub mod basis;
+pub mod capabilities;
+pub mod conformance;
+pub mod dict;
+pub mod glob;
+pub mod key;
+#[cfg(not(target_os = "xous"))]
+pub mod mock;
+pub mod path;
+mod pddb;
+pub mod senres;
+pub mod services;
+pub mod transport;
+pub mod union;
+pub mod walk;
+
+pub use basis::BasisInfo;
+pub use capabilities::{Capabilities, Features};
+pub use glob::{Glob, GlobEntry};
+pub use key::{Key, KeyOptions, Metadata, WritePolicy};
+pub use path::{NamePolicy, PddbPath, PddbPathBuf};
+pub use pddb::Pddb;
+pub use union::{Resolution, UnionResolver};
+pub use walk::{Walk, WalkEntry, WalkKind};
+
+pub const SERVER_NAME_PDDB: &str = "_Plausibly Deniable Database_";
+
+#[repr(usize)]
+pub(crate) enum Opcodes {
+    IsMounted = 0,
+    TryMount = 1,
+
+    WriteKeyFlush = 18,
+    PeriodicScrub = 19,
+    KeyDrop = 20,
+
+    ListBasisStd = 26,
+    ListDictStd = 28,
+    ListKeyStd = 29,
+
+    OpenKeyStd = 30,
+    ReadKeyStd = 31,
+    WriteKeyStd = 32,
+    DeleteKeyStd = 35,
+
+    ListPathStd = 37,
+    StatPathStd = 38,
+    CreateDictStd = 39,
+    DeleteDictStd = 40,
+
+    ReadKeyMulti = 50,
+    WriteKeyMulti = 51,
+    KeyStat = 52,
+    KeySetLen = 53,
+    RenameKey = 54,
+    CopyKey = 55,
+    RenameDict = 56,
+
+    CreateBasis = 58,
+    UnlockBasis = 59,
+    LockBasis = 60,
+    DeleteBasis = 61,
+    BasisOrder = 62,
+    SetBasisOrder = 63,
+    ListBasisInfo = 64,
+
+    Capabilities = 65,
+}
+
+/// Turn an error from the kernel into something that can be returned
+/// from a `std::io` call.
+pub(crate) fn xous_error(error: xous::Error) -> std::io::Error {
+    std::io::Error::other(format!("xous error: {:?}", error))
+}
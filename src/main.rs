@@ -1,472 +1,123 @@
-const SERVER_NAME_KEYS: &str = "_Root key server and update manager_";
-const SERVER_NAME_PDDB: &str = "_Plausibly Deniable Database_";
-
-use std::fmt::Write as _;
-use std::fs::File;
-use std::path::Path;
-use std::{io::Read, io::Seek, io::Write};
-
-#[cfg(target_os = "xous")]
-use std::os::xous::path::PathExt;
-
-mod services;
-
-mod basis;
-mod dict;
-mod key;
-mod path;
-mod senres;
-
-#[repr(usize)]
-pub(crate) enum Opcodes {
-    // IsMounted = 0,
-    TryMount = 1,
-
-    WriteKeyFlush = 18,
-    PeriodicScrub = 19,
-    KeyDrop = 20,
-
-    ListBasisStd = 26,
-    ListDictStd = 28,
-    ListKeyStd = 29,
-
-    OpenKeyStd = 30,
-    ReadKeyStd = 31,
-    WriteKeyStd = 32,
-
-    ListPathStd = 37,
-}
-
-fn unlock_db(key: &str) {
-    #[repr(C, align(4096))]
-    struct UnlockPasswordRequest {
-        key: [u8; 4096],
-    }
-
-    #[allow(non_upper_case_globals)]
-    const InitBootPassword: usize = 340;
-    let root_keys = services::connect(SERVER_NAME_KEYS).unwrap();
-    let mut unlock_request = UnlockPasswordRequest { key: [0u8; 4096] };
-
-    for (dest, src) in unlock_request.key.iter_mut().zip(key.as_bytes()) {
-        *dest = *src;
-    }
-
-    let memory_range = unsafe {
-        xous::MemoryRange::new(
-            &mut unlock_request as *mut UnlockPasswordRequest as usize,
-            core::mem::size_of::<UnlockPasswordRequest>(),
-        )
-        .unwrap()
-    };
-
-    xous::send_message(
-        root_keys,
-        xous::Message::new_lend(
-            InitBootPassword,
-            memory_range,
-            None,
-            core::num::NonZeroUsize::new(key.len()),
-        ),
-    )
-    .unwrap();
-}
-
-struct Pddb {
-    cid: u32,
-}
-
-impl Pddb {
-    pub fn new() -> Self {
-        Pddb {
-            cid: services::connect(SERVER_NAME_PDDB).unwrap(),
+use pddb_raw::dict::EntryFlags;
+use pddb_raw::{conformance, Pddb, WalkKind};
+
+/// Print everything below `root` as an indented tree.
+fn print_tree(pddb: &Pddb, root: &str) {
+    println!("Recursively listing \"{}\"", root);
+    let walk = match pddb.walk(root) {
+        Ok(walk) => walk.sort_by_name(),
+        Err(e) => {
+            println!("error reading {}: {}", root, e);
+            return;
         }
-    }
-
-    // pub fn is_mounted(&self) -> bool {
-    //     xous::send_message(
-    //         self.cid,
-    //         xous::Message::new_blocking_scalar(crate::Opcodes::IsMounted as usize, 0, 0, 0, 0),
-    //     )
-    //     .map(|v| {
-    //         // println!("is_mounted result: {:?}", v);
-    //         matches!(v, xous::Result::Scalar2(0, _))
-    //     })
-    //     .expect("unexpected return value")
-    // }
-
-    pub fn scrub(&self) {
-        // this is what actually runs every interval, to a first order
-        xous::send_message(
-            self.cid,
-            xous::Message::new_scalar(crate::Opcodes::PeriodicScrub as usize, 0, 0, 0, 0),
-        )
-        .expect("couldn't send scrub request");
-    }
-
-    pub fn try_mount(&self) -> bool {
-        xous::send_message(
-            self.cid,
-            xous::Message::new_blocking_scalar(crate::Opcodes::TryMount as usize, 0, 0, 0, 0),
-        )
-        .map(|v| {
-            // println!("try_mount result: {:?}", v);
-            matches!(v, xous::Result::Scalar2(0, _))
-        })
-        .expect("unexpected return value")
-    }
-
-    pub fn list_bases(&self) -> basis::BasisList {
-        basis::BasisList::new(self.cid).unwrap()
-    }
-
-    // fn list_dictionaries(&self, basis: Option<&str>) -> dict::DictList {
-    //     let request = dict::ListDictRequest::new(basis);
-    //     request.invoke(self.cid).unwrap()
-    // }
-
-    fn list_path(&self, path: &str) -> dict::PathList {
-        dict::PathList::new(self.cid, path).unwrap()
-    }
-
-    // fn list_keys(&self, basis: Option<&str>, dict: &str) -> key::KeyList {
-    //     let request = key::ListKeyRequest::new(basis, dict);
-    //     request.invoke(self.cid).unwrap()
-    // }
-}
-
-fn recursively_list_dirs<P: AsRef<Path>>(root: P) {
-    use std::fs;
-    let root = root.as_ref();
-    println!("Recursively listing \"{}\"", root.display());
-
-    fn visit_dirs(dir: &Path, depth: usize) -> std::io::Result<()> {
-        let entries = match fs::read_dir(dir) {
+    };
+    for entry in walk {
+        let entry = match entry {
             Ok(o) => o,
-
             Err(e) => {
-                println!("error reading {}: {}", dir.display(), e);
-                return Ok(());
+                println!("error: {}", e);
+                continue;
             }
         };
-        for entry in entries {
-            let entry = match entry {
-                Ok(o) => o,
-                Err(e) => {
-                    println!("error: {}", e);
-                    continue;
-                }
-            };
-            // let mut path = dir.to_owned();
-            // path.push(entry.path());
-            let path = entry.path();
-            print!("|");
-            let kind;
-            let mut should_recurse = false;
-
-            let is_dir = path.is_dir();
-            let is_file = path.is_file();
-            #[cfg(target_os = "xous")]
-            let is_basis = path.is_basis();
-            #[cfg(not(target_os = "xous"))]
-            let is_basis = false;
-
-            if is_basis {
-                should_recurse = true;
-                kind = "[BASIS]";
-            } else if is_dir && is_file {
-                kind = "[DIR/FILE]";
-                should_recurse = true;
-            } else if is_dir {
-                kind = "[DIR]";
-                should_recurse = true;
-            } else if is_file {
-                kind = "[KEY]";
-            } else {
-                kind = "[UNKNOWN]";
-            }
-
-            for _ in 0..depth * 4 {
-                print!(" ");
-            }
-            print!(
-                "{:50}",
-                entry.file_name().to_str().unwrap_or("{invalid filename}")
-            );
-            for _ in 0..(24usize.saturating_sub(depth * 4)) {
-                print!(" ");
-            }
-            println!("{}", kind);
-            if should_recurse {
-                // let mut path_down = dir.to_owned();
-                // path_down.push(&path);
-                visit_dirs(&path, depth + 1)?;
-            }
-            continue;
-        }
-
-        Ok(())
+        let kind = match entry.kind() {
+            WalkKind::Basis => "[BASIS]",
+            WalkKind::DictAndKey => "[DIR/FILE]",
+            WalkKind::Dict => "[DIR]",
+            WalkKind::Key => "[KEY]",
+        };
+        let indent = entry.depth() * 4;
+        println!(
+            "|{:indent$}{:50}{:pad$}{}",
+            "",
+            entry.name(),
+            "",
+            kind,
+            indent = indent,
+            pad = 24usize.saturating_sub(indent)
+        );
     }
-
-    visit_dirs(root, 1).unwrap();
     println!();
 }
 
-fn test_recursive_mkdir() {
-    println!("Creating path test-create...");
-    std::fs::create_dir_all("test-create").expect("unable to create dir test-create");
-    // println!("Created the path, trying again...");
-    // std::fs::create_dir_all("test-create").expect("unable to create it again");
-    // println!("Created the path again");
-}
-
-fn test_metadata_not_exist() {
-    let mut keypath = std::path::PathBuf::new();
-    let test_dict = "nonexistent-dict-name";
-    let test_key_name = "nonexistent-key";
-    keypath.push(test_dict);
-    println!("Testing that {} exists", keypath.display());
-    if std::fs::metadata(&keypath).is_ok() {
-        // keypath exists
-        println!("dict '{}' exists", keypath.display());
+fn kind_label(flags: EntryFlags) -> &'static str {
+    if flags.is_basis() {
+        "[BASIS]"
+    } else if flags.is_dict() && flags.is_key() {
+        "[DIR/FILE]"
+    } else if flags.is_dict() {
+        "[DIR]"
     } else {
-        println!("dict '{}' does NOT exist.. creating it", keypath.display());
-        std::fs::create_dir_all(&keypath).expect("unable to create directory");
+        "[KEY]"
     }
-    keypath.push(test_key_name);
-    if std::fs::metadata(&keypath).is_ok() {
-        // keypath exists
-        println!(
-            "dict:key = '{}:{}' exists.. deleting it",
-            test_dict, test_key_name
-        );
+}
+
+/// The dict the conformance suite works in. It's created before the suite
+/// runs and deleted afterwards, so nothing outside it is touched.
+const CONFORMANCE_DICT: &str = "pddb-raw.conformance";
 
-        std::fs::remove_file(keypath).expect("unable to remove file");
+/// Run the conformance suite through libstd and through the raw API.
+fn run_conformance(pddb: &Pddb) {
+    if let Err(e) = pddb.create_dict(None, CONFORMANCE_DICT) {
+        println!("unable to create {}: {}", CONFORMANCE_DICT, e);
+        return;
     }
+    println!("Running conformance suite in {}...", CONFORMANCE_DICT);
+    let report = conformance::run(&conformance::StdFs, CONFORMANCE_DICT);
+    println!("{}", report);
+    let report = conformance::run(&conformance::Raw::new(pddb), CONFORMANCE_DICT);
+    println!("{}", report);
+    if let Err(e) = pddb.delete_dict(None, CONFORMANCE_DICT, true) {
+        println!("unable to delete {}: {}", CONFORMANCE_DICT, e);
+    }
+}
 
-    if std::fs::metadata(test_dict).is_ok() {
-        std::fs::remove_dir(test_dict).expect("unable to remove dict");
+const USAGE: &str = "Usage: pddb-raw (glob PATTERN... | walk ROOT... | conformance)";
+
+/// Run the command given on the command line.
+fn run_command(pddb: &Pddb, args: &[String]) {
+    match args {
+        [command, patterns @ ..] if command == "glob" => {
+            for pattern in patterns {
+                for entry in pddb.glob(pattern) {
+                    match entry {
+                        Ok(entry) => println!("{:8} {}", kind_label(entry.flags()), entry.path()),
+                        Err(e) => println!("error matching {}: {}", pattern, e),
+                    }
+                }
+            }
+        }
+        [command, roots @ ..] if command == "walk" => {
+            for root in roots {
+                print_tree(pddb, root);
+            }
+        }
+        [command] if command == "conformance" => run_conformance(pddb),
+        [] => println!("{}", USAGE),
+        [command, ..] => {
+            println!("Unknown command {}", command);
+            println!("{}", USAGE);
+        }
     }
 }
 
 fn main() {
     println!("PDDB Raw Operations");
-    // println!("Unlocking DB...");
-    // unlock_db("a");
 
     // The PDDB seems to take a long time to start up
     let start_time = std::time::Instant::now();
-    let mut try_mount_calls = 0;
     println!("Connecting to PDDB...");
-    let pddb = Pddb::new();
+    let pddb = Pddb::connect().expect("couldn't connect to the PDDB");
     println!(
         "Starting mount (elapsed: {} ms)",
         start_time.elapsed().as_millis()
     );
-    while !pddb.try_mount() {
-        // pddb.try_mount();
-        try_mount_calls += 1;
-        // if pddb.is_mounted() {
-        //     break;
-        // }
-    }
+    let try_mount_calls = pddb.wait_mounted().expect("couldn't mount the PDDB");
     println!(
         "PDDB mounted with {} try_mount calls after {} ms",
         try_mount_calls,
         start_time.elapsed().as_millis()
     );
 
-   println!("Doing other operations...");
-    // {
-    //     let list = pddb.list_bases();
-    //     println!("There are {} bases", list.len());
-    //     for entry in &list.iter() {
-    //         println!("Basis: {}", entry);
-    //     }
-    // }
-
-    // {
-    //     println!("Opening file sys.rtc:tz_offset");
-    //     let mut f = File::open("sys.rtc:tz_offset").expect("couldn't open tz_offset file!");
-    //     let mut buf = vec![];
-    //     let bytes_read = f
-    //         .read_to_end(&mut buf)
-    //         .expect("couldn't read contents of file");
-    //     println!("Read {} bytes of data: {:?}", bytes_read, buf);
-    // }
-
-    // println!("Opening file wlan.networks:Renode");
-    // if let Ok(mut f) = File::open("wlan.networks:Renode") {
-    //     let mut buf = vec![];
-    //     let bytes_read = f
-    //         .read_to_end(&mut buf)
-    //         .expect("couldn't read contents of file");
-    //     println!("Read {} bytes of data: {:?}", bytes_read, buf);
-    //     if let Ok(val) = core::str::from_utf8(&buf) {
-    //         println!("Data as string: [{}]", val);
-    //     }
-    // }
-
-    // {
-    //     for path in [
-    //         "",
-    //         ":",
-    //         "wlan.networks",
-    //         "sys.rtc",
-    //         "fido.cfg",
-    //         "vault.passwords",
-    //     ] {
-    //         println!("Listing path {}", path);
-    //         let entries = pddb.list_path(path);
-    //         for entry in entries.iter() {
-    //             println!("{:?}", entry);
-    //         }
-    //         println!();
-    //     }
-    // }
-
-    // std::fs::File::open("nonexistent-file").ok();
-
-    test_recursive_mkdir();
-    // test_metadata_not_exist();
-
-    // println!("Listing sys.rtc");
-    // recursively_list_dirs(Path::new("sys.rtc"));
-
-    // println!("Deleting file just to be sure");
-    // std::fs::remove_file("sys.rtc:foobar").ok();
-
-    // std::fs::File::open("sys.rtc:foobar").expect_err("foobar existed when it shouldn't");
-
-    // println!("Creating sys.rtc:foobar");
-    // let mut file = std::fs::File::create("sys.rtc:foobar").expect("couldn't create foobar");
-    // file.write_all(&[1, 2, 3, 4]).expect("couldn't write file");
-    // core::mem::drop(file);
-
-    // println!("Listing sys.rtc again");
-    // recursively_list_dirs(Path::new("sys.rtc"));
-
-    // let mut file = std::fs::File::open("sys.rtc:foobar").expect("couldn't open foobar");
-    // let mut v = vec![];
-    // file.read_to_end(&mut v)
-    //     .expect("couldn't snarf data from foobar");
-    // println!("Contents of file: {:?}", v);
-    // assert_eq!(&v, &[1, 2, 3, 4]);
-    // file.rewind().expect("couldn't rewind file");
-    // v.push(42);
-    // println!("Writing {:?} to file", v);
-    // file.write_all(&v).expect("couldn't append data to foobar");
-    // println!("closing file again");
-    // core::mem::drop(file);
-
-    // let mut file = std::fs::File::open("sys.rtc:foobar").expect("couldn't open foobar");
-    // let mut v = vec![];
-    // file.read_to_end(&mut v)
-    //     .expect("couldn't snarf data from foobar");
-    // println!("Contents of file: {:?}", v);
-    // assert_eq!(&v, &[1, 2, 3, 4, 42]);
-    // println!("closing file again");
-    // core::mem::drop(file);
-
-    // // Test that "create_new" works
-    // println!("Trying to create a file that exists. This should fail.");
-    // std::fs::OpenOptions::new()
-    //     .create_new(true)
-    //     .open("sys.rtc:foobar")
-    //     .expect_err("opened a file twice");
-
-    // // Test that "truncate" works
-    // let mut file = std::fs::OpenOptions::new()
-    //     .truncate(true)
-    //     .open("sys.rtc:foobar")
-    //     .expect("couldn't open foobar for truncating");
-    // let mut v = vec![];
-    // file.read_to_end(&mut v)
-    //     .expect("couldn't snarf data from foobar");
-    // println!("Contents of file: {:?}", v);
-    // assert_eq!(&v, &[]);
-    // v.push(42);
-    // file.write_all(&v).expect("couldn't append data to foobar");
-    // println!("closing file again");
-    // core::mem::drop(file);
-
-    // println!("Testing that the truncate append worked");
-    // let mut file = std::fs::File::open("sys.rtc:foobar").expect("couldn't open foobar");
-    // let mut v = vec![];
-    // file.read_to_end(&mut v)
-    //     .expect("couldn't snarf data from foobar");
-    // println!("Contents of file: {:?}", v);
-    // assert_eq!(&v, &[42]);
-    // core::mem::drop(file);
-
-    // // Test that "append" works
-    // let mut file = std::fs::OpenOptions::new()
-    //     .append(true)
-    //     .open("sys.rtc:foobar")
-    //     .expect("couldn't open foobar for truncating");
-    // let mut v = vec![];
-    // file.read_to_end(&mut v)
-    //     .expect("couldn't snarf data from foobar");
-    // println!("Contents of file: {:?}", v);
-    // assert_eq!(&v, &[]);
-    // file.rewind().expect("couldn't rewind");
-    // file.read_to_end(&mut v)
-    //     .expect("couldn't snarf data from foobar");
-    // assert_eq!(&v, &[42]);
-    // core::mem::drop(file);
-
-    // // Test deleting
-    // std::fs::remove_file("sys.rtc:foobar").expect("couldn't delete sys.rtc:foobar");
-    // std::fs::remove_file("sys.rtc:foobar").expect_err("delete sys.rtc:foobar twice");
-
-    // println!("Trying file creation. First, removing possible files");
-    // std::fs::remove_file("sys.rtc:baz:quux").ok();
-    // std::fs::remove_dir("sys.rtc:baz").ok();
-
-    // println!("Testing path creation");
-    // std::fs::create_dir("sys.rtc:baz").expect("unable to create sys.rtc:baz");
-    // recursively_list_dirs(Path::new("sys.rtc"));
-
-    // println!("Testing file creation");
-    // let mut file = std::fs::File::create("sys.rtc:baz:quux").expect("couldn't create file");
-    // file.write_all(&[5, 4, 3, 2])
-    //     .expect("couldn't write test data");
-    // core::mem::drop(file);
-
-    // println!("Listing new directory");
-    // recursively_list_dirs(Path::new("sys.rtc"));
-
-    // println!("Removing the directory. This should fail.");
-    // std::fs::remove_dir("sys.rtc:baz")
-    //     .expect_err("directory was deleted even though it's got stuff");
-
-    // println!("Removing files for real");
-    // std::fs::remove_file("sys.rtc:baz:quux").expect("unable to remove file");
-    // println!("Current directory listing:");
-    // recursively_list_dirs(Path::new("sys.rtc"));
-    // std::fs::remove_dir("sys.rtc:baz").expect("directory was deleted even though it's empty");
-
-    // println!("Going to recursively list directories...");
-    // recursively_list_dirs(Path::new(""));
-    // recursively_list_dirs(Path::new("::"));
-    // recursively_list_dirs(Path::new(":"));
-    // recursively_list_dirs(Path::new(":.System"));
-    // recursively_list_dirs(Path::new("sys.rtc"));
-
-    // println!("Opening a file...");
-    // let mut file = key::Key::open(pddb.cid, None, "wlan.networks", "Renode").unwrap();
-    // println!("Reading password...");
-    // let mut password = String::new();
-    // let len = file
-    //     .read_to_string(&mut password)
-    //     .expect("Unable to read password");
-    // println!("Password is {} bytes long: {}", len, password);
-    // println!("Appending {} to the password", password.len());
-    // write!(password, "{}", password.len() + 1).unwrap();
-    // println!("Writing {} to password field", password);
-    // file.rewind().expect("couldn't rewind password file");
-    // file.write_all(password.as_bytes())
-    //     .expect("unable to update password");
-
-    println!("Done with operations");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    run_command(&pddb, &args);
 }
@@ -0,0 +1,1544 @@
+//! An in-memory emulation of the PDDB server, for running the client on
+//! platforms other than Xous. It speaks the same wire formats as the real
+//! server for the subset of the protocol implemented by this crate, and is
+//! attached to the client through the host transport.
+//!
+//! Bases are kept in the order they were added. Reads are served from the
+//! union of all bases with the most-recently-added basis taking precedence,
+//! and writes go to the most-recently-added basis that already contains the
+//! key. New keys are created in the most-recently-added basis.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::capabilities::{Capabilities, Features, CAPABILITIES_VERSION};
+use crate::dict::EntryKind;
+use crate::key::{
+    open_flags, ApiToken, Metadata, OpenKeyRequest, OpenKeyResponse, PddbBuf, PddbRetcode,
+    OPEN_KEY_VERSION,
+};
+use crate::senres::{Senres, SenresMut};
+use crate::union::{Resolution, UnionResolver};
+use crate::{transport, Opcodes, Pddb};
+
+#[cfg(test)]
+mod tests;
+
+/// The name of the basis that is always present.
+pub const SYSTEM_BASIS: &str = ".System";
+
+#[derive(Default)]
+struct Basis {
+    name: String,
+    /// The password needed to unlock this basis. Bases added with
+    /// `add_basis()` and the system basis have none.
+    password: Option<String>,
+    /// Every dict in this basis, each with its keys.
+    dicts: BTreeMap<String, BTreeMap<String, KeyData>>,
+}
+
+#[derive(Default, Clone)]
+struct KeyData {
+    data: Vec<u8>,
+    alloc_hint: u64,
+    /// Set when the key is written to, and cleared by `WriteKeyFlush`
+    dirty: bool,
+}
+
+impl KeyData {
+    /// Keys are given at least as much space as they were hinted.
+    fn reserved(&self) -> u64 {
+        self.alloc_hint.max(self.data.len() as u64)
+    }
+}
+
+struct Handle {
+    basis: String,
+    dict: String,
+    key: String,
+    writable: bool,
+}
+
+/// A key named in a request. If `basis` is `None`, the union rules apply.
+struct KeyName {
+    basis: Option<String>,
+    dict: String,
+    key: String,
+}
+
+/// Everything the emulated server stores, shared between the `MockPddb`
+/// handle and the server registered with the host transport.
+#[derive(Default)]
+struct State {
+    /// Unlocked bases in priority order. The last entry has the highest priority.
+    bases: Vec<Basis>,
+    /// Bases that exist but are not unlocked
+    locked: Vec<Basis>,
+    handles: HashMap<ApiToken, Handle>,
+    next_token: u32,
+    /// Number of calls to `TryMount` that will fail before the mount succeeds
+    mount_delay: usize,
+    mounted: bool,
+    scrub_count: usize,
+    /// Number of messages received, of any kind
+    message_count: usize,
+    /// Whether multi-page reads and writes are understood
+    multi_page: bool,
+    /// Whether path listings can include entry flags and sizes
+    path_flags: bool,
+    /// Whether keys can be listed a page at a time
+    paged_keys: bool,
+    /// Whether dicts can be listed a page at a time
+    paged_dicts: bool,
+    /// Whether keys can be opened with a senres request
+    senres_open: bool,
+    /// Whether the capability query is understood
+    capability_query: bool,
+    /// Whether to behave like the shipping firmware, which predates every
+    /// message from `ReadKeyMulti` on, version 2 open requests and the
+    /// `NotFound`, `AlreadyExists` and `NotEmpty` return codes
+    firmware: bool,
+    /// Capabilities to report instead of the ones the mock really has
+    reported: Option<Capabilities>,
+}
+
+/// A handle to an emulated PDDB server. The server is unregistered
+/// from the host transport when this is dropped.
+pub struct MockPddb {
+    state: Arc<Mutex<State>>,
+    cid: xous::CID,
+}
+
+struct MockServer {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for MockPddb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockPddb {
+    /// Create a new, mounted PDDB containing only the system basis.
+    pub fn new() -> Self {
+        let state = Arc::new(Mutex::new(State {
+            bases: vec![Basis {
+                name: SYSTEM_BASIS.to_owned(),
+                ..Default::default()
+            }],
+            mounted: true,
+            multi_page: true,
+            path_flags: true,
+            paged_keys: true,
+            paged_dicts: true,
+            senres_open: true,
+            capability_query: true,
+            next_token: 1,
+            ..Default::default()
+        }));
+        let cid = transport::register(Box::new(MockServer {
+            state: state.clone(),
+        }));
+        MockPddb { state, cid }
+    }
+
+    /// The connection ID that messages for this server should be sent to.
+    pub fn cid(&self) -> xous::CID {
+        self.cid
+    }
+
+    /// Connect to the server and ask it what it supports, the way
+    /// `Pddb::connect()` does.
+    pub fn connect(&self) -> Pddb {
+        let mut pddb = Pddb::from_connection(self.cid);
+        pddb.negotiate();
+        pddb
+    }
+
+    /// Leave the PDDB unmounted until `TryMount` has been called `attempts` times.
+    pub fn mount_after(&self, attempts: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.mounted = attempts == 0;
+        state.mount_delay = attempts;
+    }
+
+    /// Add a new basis, which will take precedence over every existing basis.
+    pub fn add_basis(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.basis(name).is_none() {
+            state.bases.push(Basis {
+                name: name.to_owned(),
+                ..Default::default()
+            });
+        }
+    }
+
+    /// Create a dict. If `basis` is `None`, the dict is created in the
+    /// most-recently-added basis.
+    pub fn add_dict(&self, basis: Option<&str>, dict: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .basis_for_create(basis)
+            .expect("basis does not exist")
+            .dicts
+            .entry(dict.to_owned())
+            .or_default();
+    }
+
+    /// Set the contents of a key, creating it and its dict if necessary.
+    /// If `basis` is `None` the key is written following the union rules.
+    pub fn insert_key(&self, basis: Option<&str>, dict: &str, key: &str, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let basis = match basis {
+            Some(basis) => basis.to_owned(),
+            None => match state.find_key(dict, key) {
+                Some(basis) => basis,
+                None => state.bases.last().unwrap().name.clone(),
+            },
+        };
+        state
+            .basis_mut(&basis)
+            .expect("basis does not exist")
+            .dicts
+            .entry(dict.to_owned())
+            .or_default()
+            .entry(key.to_owned())
+            .or_default()
+            .data = data.to_vec();
+    }
+
+    /// Read the contents of a key. If `basis` is `None`, the key is read
+    /// from the union basis.
+    pub fn key_contents(&self, basis: Option<&str>, dict: &str, key: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let basis = match basis {
+            Some(basis) => basis.to_owned(),
+            None => state.find_key(dict, key)?,
+        };
+        Some(state.basis(&basis)?.dicts.get(dict)?.get(key)?.data.clone())
+    }
+
+    /// The number of keys currently held open by clients.
+    pub fn open_handles(&self) -> usize {
+        self.state.lock().unwrap().handles.len()
+    }
+
+    /// The number of messages that have been received so far.
+    pub fn message_count(&self) -> usize {
+        self.state.lock().unwrap().message_count
+    }
+
+    /// Refuse multi-page reads and writes, the way an older server would.
+    pub fn single_page_only(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.multi_page = false;
+        state.capability_query = false;
+    }
+
+    /// List paths with one kind per entry, the way an older server would.
+    pub fn legacy_path_listing(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.path_flags = false;
+        state.capability_query = false;
+    }
+
+    /// Only understand the original key list format, the way an older server would.
+    pub fn legacy_key_listing(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paged_keys = false;
+        state.capability_query = false;
+    }
+
+    /// Only understand the original packed layout for opening keys, the
+    /// way an older server would.
+    pub fn legacy_open_key(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.senres_open = false;
+        state.capability_query = false;
+    }
+
+    /// Behave like the shipping firmware. Messages added since then are
+    /// refused, only the original formats are understood, and successful
+    /// requests that only have a status are returned untouched.
+    pub fn firmware_only(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.multi_page = false;
+        state.path_flags = false;
+        state.paged_keys = false;
+        state.paged_dicts = false;
+        state.senres_open = false;
+        state.capability_query = false;
+        state.firmware = true;
+    }
+
+    /// Report `capabilities` in reply to the capability query, rather than
+    /// what the mock really supports.
+    pub fn report_capabilities(&self, capabilities: Capabilities) {
+        self.state.lock().unwrap().reported = Some(capabilities);
+    }
+
+    /// The number of times `PeriodicScrub` has been received.
+    pub fn scrub_count(&self) -> usize {
+        self.state.lock().unwrap().scrub_count
+    }
+}
+
+impl Drop for MockPddb {
+    fn drop(&mut self) {
+        transport::unregister(self.cid);
+    }
+}
+
+impl State {
+    /// What the mock supports, given the older server it may be emulating.
+    fn capabilities(&self) -> Capabilities {
+        if let Some(reported) = self.reported {
+            return reported;
+        }
+        let mut features = Features::METADATA
+            | Features::SET_LEN
+            | Features::RENAME_COPY_KEY
+            | Features::RENAME_DICT
+            | Features::BASIS_MANAGEMENT;
+        for (enabled, feature) in [
+            (self.paged_keys, Features::PAGED_KEY_LIST),
+            (self.paged_dicts, Features::PAGED_DICT_LIST),
+            (self.multi_page, Features::MULTI_PAGE_IO),
+            (self.path_flags, Features::PATH_FLAGS),
+            (self.senres_open, Features::SENRES_OPEN_KEY),
+        ] {
+            if enabled {
+                features |= feature;
+            }
+        }
+        Capabilities::new(CAPABILITIES_VERSION, OPEN_KEY_VERSION, features)
+    }
+
+    /// The code to send for an error. The firmware has no codes past
+    /// `DiskFull`: it reports anything missing as `BasisLost` and every
+    /// other failure as `InternalError`.
+    fn reply_code(&self, code: PddbRetcode) -> PddbRetcode {
+        if !self.firmware {
+            return code;
+        }
+        match code {
+            PddbRetcode::NotFound => PddbRetcode::BasisLost,
+            code if code > PddbRetcode::DiskFull && code != PddbRetcode::Invalid => {
+                PddbRetcode::InternalError
+            }
+            code => code,
+        }
+    }
+
+    fn basis(&self, name: &str) -> Option<&Basis> {
+        self.bases.iter().find(|b| b.name == name)
+    }
+
+    fn basis_mut(&mut self, name: &str) -> Option<&mut Basis> {
+        self.bases.iter_mut().find(|b| b.name == name)
+    }
+
+    /// The basis that new entries should be created in.
+    fn basis_for_create(&mut self, name: Option<&str>) -> Option<&mut Basis> {
+        match name {
+            Some(name) => self.basis_mut(name),
+            None => self.bases.last_mut(),
+        }
+    }
+
+    /// Bases that are visible for a given request, from highest to lowest priority.
+    fn visible_bases<'a>(&'a self, name: Option<&'a str>) -> impl Iterator<Item = &'a Basis> {
+        self.bases
+            .iter()
+            .rev()
+            .filter(move |b| name.is_none() || name == Some(b.name.as_str()))
+    }
+
+    /// The union order, used as the reference model for resolving keys.
+    fn resolver(&self) -> UnionResolver {
+        UnionResolver::new(self.bases.iter().rev().map(|b| b.name.as_str()))
+    }
+
+    /// Work out where a key in `dict` would be read from and written to.
+    fn resolve(&self, basis: Option<&str>, dict: &str, key: &str) -> Resolution {
+        self.resolver().resolve(basis, |name| {
+            self.basis(name)
+                .and_then(|b| b.dicts.get(dict))
+                .is_some_and(|d| d.contains_key(key))
+        })
+    }
+
+    /// Find the basis that a key would be read from in the union basis.
+    fn find_key(&self, dict: &str, key: &str) -> Option<String> {
+        self.resolve(None, dict, key).read().map(str::to_owned)
+    }
+
+    /// Find the basis that a key lives in, checking that a named basis exists.
+    fn resolve_key(&self, name: &KeyName) -> Result<String, PddbRetcode> {
+        let basis = name.basis.as_deref();
+        if let Some(basis) = basis {
+            if self.basis(basis).is_none() {
+                return Err(PddbRetcode::BasisLost);
+            }
+        }
+        self.resolve(basis, &name.dict, &name.key)
+            .read()
+            .map(str::to_owned)
+            .ok_or(PddbRetcode::NotFound)
+    }
+
+    fn create_basis(&mut self, name: &str, password: &str) -> Result<(), PddbRetcode> {
+        if name.is_empty() {
+            return Err(PddbRetcode::Invalid);
+        }
+        if self.basis(name).is_some() || self.locked.iter().any(|b| b.name == name) {
+            return Err(PddbRetcode::AlreadyExists);
+        }
+        self.locked.push(Basis {
+            name: name.to_owned(),
+            password: Some(password.to_owned()),
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    fn unlock_basis(&mut self, name: &str, password: &str) -> Result<(), PddbRetcode> {
+        if self.basis(name).is_some() {
+            return Ok(());
+        }
+        let index = self
+            .locked
+            .iter()
+            .position(|b| b.name == name && b.password.as_deref() == Some(password))
+            .ok_or(PddbRetcode::AccessDenied)?;
+        let basis = self.locked.remove(index);
+        self.bases.push(basis);
+        Ok(())
+    }
+
+    fn lock_basis(&mut self, name: &str) -> Result<(), PddbRetcode> {
+        if name == SYSTEM_BASIS {
+            return Err(PddbRetcode::AccessDenied);
+        }
+        let index = self
+            .bases
+            .iter()
+            .position(|b| b.name == name)
+            .ok_or(PddbRetcode::BasisLost)?;
+        let basis = self.bases.remove(index);
+        // Bases added by the test harness have no password, and cannot
+        // be unlocked again
+        if basis.password.is_some() {
+            self.locked.push(basis);
+        }
+        Ok(())
+    }
+
+    fn delete_basis(&mut self, name: &str) -> Result<(), PddbRetcode> {
+        if name == SYSTEM_BASIS {
+            return Err(PddbRetcode::AccessDenied);
+        }
+        let index = self
+            .bases
+            .iter()
+            .position(|b| b.name == name)
+            .ok_or(PddbRetcode::BasisLost)?;
+        self.bases.remove(index);
+        Ok(())
+    }
+
+    /// Reorder the unlocked bases. `order` is highest priority first, and
+    /// must name every unlocked basis with the system basis last.
+    fn set_basis_order(&mut self, order: &[String]) -> Result<(), PddbRetcode> {
+        let mut wanted: Vec<&str> = order.iter().map(|s| s.as_str()).collect();
+        wanted.sort_unstable();
+        let mut current: Vec<&str> = self.bases.iter().map(|b| b.name.as_str()).collect();
+        current.sort_unstable();
+        if wanted != current || order.last().map(|s| s.as_str()) != Some(SYSTEM_BASIS) {
+            return Err(PddbRetcode::Invalid);
+        }
+        for (position, name) in order.iter().rev().enumerate() {
+            let index = self.bases.iter().position(|b| &b.name == name).unwrap();
+            self.bases.swap(position, index);
+        }
+        Ok(())
+    }
+
+    fn delete_key(&mut self, name: &KeyName) -> Result<(), PddbRetcode> {
+        let basis = self.resolve_key(name)?;
+        self.basis_mut(&basis)
+            .unwrap()
+            .dicts
+            .get_mut(&name.dict)
+            .unwrap()
+            .remove(&name.key);
+        Ok(())
+    }
+
+    /// Copy a key, removing the original if `rename` is set. The target
+    /// dict must exist in the union, and is created in the target basis if
+    /// necessary. Open handles follow a renamed key.
+    fn copy_key(&mut self, from: &KeyName, to: &KeyName, rename: bool) -> Result<(), PddbRetcode> {
+        let source = self.resolve_key(from)?;
+        let target = to.basis.clone().unwrap_or_else(|| source.clone());
+        if self.basis(&target).is_none() {
+            return Err(PddbRetcode::BasisLost);
+        }
+        if !self.dict_exists(None, &to.dict) {
+            return Err(PddbRetcode::NotFound);
+        }
+        if source == target && from.dict == to.dict && from.key == to.key {
+            return if rename {
+                Ok(())
+            } else {
+                Err(PddbRetcode::AlreadyExists)
+            };
+        }
+        let target_basis = self.basis_mut(&target).unwrap();
+        if target_basis
+            .dicts
+            .get(&to.dict)
+            .is_some_and(|d| d.contains_key(&to.key))
+        {
+            return Err(PddbRetcode::AlreadyExists);
+        }
+
+        let source_dict = self
+            .basis_mut(&source)
+            .unwrap()
+            .dicts
+            .get_mut(&from.dict)
+            .unwrap();
+        let data = if rename {
+            source_dict.remove(&from.key).unwrap()
+        } else {
+            KeyData {
+                dirty: true,
+                ..source_dict.get(&from.key).unwrap().clone()
+            }
+        };
+        self.basis_mut(&target)
+            .unwrap()
+            .dicts
+            .entry(to.dict.clone())
+            .or_default()
+            .insert(to.key.clone(), data);
+
+        if rename {
+            for handle in self.handles.values_mut() {
+                if handle.basis == source && handle.dict == from.dict && handle.key == from.key {
+                    handle.basis = target.clone();
+                    handle.dict = to.dict.clone();
+                    handle.key = to.key.clone();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn create_dict(&mut self, basis: Option<&str>, dict: &str) -> Result<(), PddbRetcode> {
+        if dict.is_empty() {
+            return Err(PddbRetcode::Invalid);
+        }
+        let target = self.basis_for_create(basis).ok_or(PddbRetcode::BasisLost)?;
+        if target.dicts.contains_key(dict) {
+            return Err(PddbRetcode::AlreadyExists);
+        }
+        target.dicts.insert(dict.to_owned(), BTreeMap::new());
+        Ok(())
+    }
+
+    /// Find the basis that a dict lives in, checking that a named basis
+    /// exists. A dict exists if it was created, or if a nested dict was.
+    fn resolve_dict(&self, basis: Option<&str>, dict: &str) -> Result<String, PddbRetcode> {
+        if let Some(basis) = basis {
+            if self.basis(basis).is_none() {
+                return Err(PddbRetcode::BasisLost);
+            }
+        }
+        let prefix = format!("{}:", dict);
+        self.visible_bases(basis)
+            .find(|b| b.dicts.keys().any(|d| d == dict || d.starts_with(&prefix)))
+            .map(|b| b.name.clone())
+            .ok_or(PddbRetcode::NotFound)
+    }
+
+    fn delete_dict(
+        &mut self,
+        basis: Option<&str>,
+        dict: &str,
+        recursive: bool,
+    ) -> Result<(), PddbRetcode> {
+        let name = self.resolve_dict(basis, dict)?;
+        let prefix = format!("{}:", dict);
+        let dicts = &mut self.basis_mut(&name).unwrap().dicts;
+        let has_keys = dicts.get(dict).is_some_and(|d| !d.is_empty());
+        let has_children = dicts.keys().any(|d| d.starts_with(&prefix));
+        if (has_keys || has_children) && !recursive {
+            return Err(PddbRetcode::NotEmpty);
+        }
+        dicts.retain(|d, _| d != dict && !d.starts_with(&prefix));
+        Ok(())
+    }
+
+    /// Rename a dict and every dict nested inside it. Open handles follow.
+    fn rename_dict(
+        &mut self,
+        basis: Option<&str>,
+        from: &str,
+        to: &str,
+    ) -> Result<(), PddbRetcode> {
+        if to.is_empty() {
+            return Err(PddbRetcode::Invalid);
+        }
+        let name = self.resolve_dict(basis, from)?;
+        if from == to {
+            return Ok(());
+        }
+        let from_prefix = format!("{}:", from);
+        let to_prefix = format!("{}:", to);
+        // Moving a dict inside itself would make it vanish
+        if to.starts_with(&from_prefix) {
+            return Err(PddbRetcode::Invalid);
+        }
+        let rename = |d: &str| -> Option<String> {
+            if d == from {
+                Some(to.to_owned())
+            } else {
+                d.strip_prefix(&from_prefix)
+                    .map(|rest| format!("{}{}", to_prefix, rest))
+            }
+        };
+
+        let dicts = &mut self.basis_mut(&name).unwrap().dicts;
+        let moving: Vec<String> = dicts
+            .keys()
+            .filter(|d| rename(d).is_some())
+            .cloned()
+            .collect();
+        if moving
+            .iter()
+            .any(|d| dicts.contains_key(&rename(d).unwrap()))
+        {
+            return Err(PddbRetcode::AlreadyExists);
+        }
+        for d in moving {
+            let contents = dicts.remove(&d).unwrap();
+            dicts.insert(rename(&d).unwrap(), contents);
+        }
+        for handle in self.handles.values_mut() {
+            if handle.basis == name {
+                if let Some(renamed) = rename(&handle.dict) {
+                    handle.dict = renamed;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn dict_exists(&self, basis: Option<&str>, dict: &str) -> bool {
+        self.visible_bases(basis)
+            .any(|b| b.dicts.contains_key(dict))
+    }
+
+    fn open_key(&mut self, request: &OpenKeyRequest) -> Result<OpenKeyResponse, PddbRetcode> {
+        let basis = request.basis.as_deref();
+        let dict = request.dict.as_str();
+        let key = request.key.as_str();
+        if let Some(basis) = basis {
+            if self.basis(basis).is_none() {
+                return Err(PddbRetcode::BasisLost);
+            }
+        }
+
+        let resolution = self.resolve(basis, dict, key);
+        let (basis_name, len) = if let Some(basis_name) = resolution.write().map(str::to_owned) {
+            if request.flags & open_flags::CREATE_NEW != 0 {
+                return Err(PddbRetcode::AlreadyExists);
+            }
+            let contents = self
+                .basis_mut(&basis_name)
+                .unwrap()
+                .dicts
+                .get_mut(dict)
+                .unwrap()
+                .get_mut(key)
+                .unwrap();
+            if request.flags & open_flags::TRUNCATE != 0 {
+                contents.data.clear();
+                contents.dirty = true;
+            }
+            let len = contents.data.len() as u64;
+            (basis_name, len)
+        } else {
+            if !request.create_key {
+                return Err(PddbRetcode::NotFound);
+            }
+            if !request.create_dict && !self.dict_exists(basis, dict) {
+                return Err(PddbRetcode::NotFound);
+            }
+            let target = resolution.create().ok_or(PddbRetcode::BasisLost)?;
+            let target = self.basis_mut(target).unwrap();
+            target.dicts.entry(dict.to_owned()).or_default().insert(
+                key.to_owned(),
+                KeyData {
+                    alloc_hint: request.alloc_hint,
+                    ..Default::default()
+                },
+            );
+            (target.name.clone(), 0)
+        };
+
+        let token = ApiToken([self.next_token, 0x6d6f_636b, 0x7064_6462]);
+        self.next_token += 1;
+        self.handles.insert(
+            token,
+            Handle {
+                basis: basis_name,
+                dict: dict.to_owned(),
+                key: key.to_owned(),
+                writable: request.flags & open_flags::WRITE != 0,
+            },
+        );
+        Ok(OpenKeyResponse { token, len })
+    }
+
+    /// Get the key referred to by `token`.
+    fn contents(&mut self, token: &ApiToken) -> Option<&mut KeyData> {
+        let handle = self.handles.get(token)?;
+        self.bases
+            .iter_mut()
+            .find(|b| b.name == handle.basis)?
+            .dicts
+            .get_mut(&handle.dict)?
+            .get_mut(&handle.key)
+    }
+
+    fn metadata(&mut self, token: &ApiToken) -> Option<Metadata> {
+        let basis = self.handles.get(token)?.basis.clone();
+        let key = self.contents(token)?;
+        Some(Metadata {
+            kind: EntryKind::Key,
+            len: key.data.len() as u64,
+            basis,
+            reserved: key.reserved(),
+            alloc_hint: key.alloc_hint,
+            flushed: !key.dirty,
+        })
+    }
+
+    /// Describe whatever `path` refers to, preferring keys over dicts.
+    fn stat(&self, path: &str) -> Result<Metadata, PddbRetcode> {
+        let (basis, rest) = match path.strip_prefix(':') {
+            Some(rest) => match rest.split_once(':') {
+                Some((basis, rest)) => (basis, rest),
+                None => (rest, ""),
+            },
+            None => ("", path),
+        };
+        let rest = rest.strip_suffix(':').unwrap_or(rest);
+        let entry = |kind, basis: &str| Metadata {
+            kind,
+            len: 0,
+            basis: basis.to_owned(),
+            reserved: 0,
+            alloc_hint: 0,
+            flushed: true,
+        };
+
+        let basis = if basis.is_empty() {
+            None
+        } else if self.basis(basis).is_none() {
+            return Err(PddbRetcode::NotFound);
+        } else if rest.is_empty() {
+            return Ok(entry(EntryKind::Basis, basis));
+        } else {
+            Some(basis)
+        };
+        if rest.is_empty() {
+            return Err(PddbRetcode::NotFound);
+        }
+
+        if let Some((dict, key)) = rest.rsplit_once(':') {
+            for b in self.visible_bases(basis) {
+                if let Some(key) = b.dicts.get(dict).and_then(|d| d.get(key)) {
+                    return Ok(Metadata {
+                        kind: EntryKind::Key,
+                        len: key.data.len() as u64,
+                        basis: b.name.clone(),
+                        reserved: key.reserved(),
+                        alloc_hint: key.alloc_hint,
+                        flushed: !key.dirty,
+                    });
+                }
+            }
+        }
+        let prefix = format!("{}:", rest);
+        self.visible_bases(basis)
+            .find(|b| b.dicts.keys().any(|d| d == rest || d.starts_with(&prefix)))
+            .map(|b| entry(EntryKind::Dict, &b.name))
+            .ok_or(PddbRetcode::NotFound)
+    }
+
+    fn list_dicts(&self, basis: Option<&str>) -> Vec<String> {
+        let mut dicts = BTreeSet::new();
+        for b in self.visible_bases(basis) {
+            dicts.extend(b.dicts.keys().cloned());
+        }
+        dicts.into_iter().collect()
+    }
+
+    fn list_keys(&self, basis: Option<&str>, dict: &str) -> Vec<String> {
+        let mut keys = BTreeSet::new();
+        for b in self.visible_bases(basis) {
+            if let Some(d) = b.dicts.get(dict) {
+                keys.extend(d.keys().cloned());
+            }
+        }
+        keys.into_iter().collect()
+    }
+
+    /// List the direct children of a path, returning each name along
+    /// with the kind of entry it is and, for keys, the size of the key.
+    /// Names that are both a dict and a key are listed twice.
+    fn list_path(&self, path: &str) -> Result<Vec<(String, u8, Option<u64>)>, PddbRetcode> {
+        const BASIS: u8 = 0;
+        const DICT: u8 = 1;
+        const KEY: u8 = 2;
+
+        if path == ":" {
+            return Ok(self
+                .bases
+                .iter()
+                .map(|b| (b.name.clone(), BASIS, None))
+                .collect());
+        }
+
+        let (basis, dict) = match path.strip_prefix(':') {
+            Some(rest) => match rest.split_once(':') {
+                Some((basis, dict)) => (basis, dict),
+                None => (rest, ""),
+            },
+            None => ("", path),
+        };
+        let basis = if basis.is_empty() { None } else { Some(basis) };
+        let dict = dict.strip_suffix(':').unwrap_or(dict);
+        if let Some(basis) = basis {
+            if self.basis(basis).is_none() {
+                return Err(PddbRetcode::NotFound);
+            }
+        }
+
+        let mut entries = vec![];
+        let all_dicts = self.list_dicts(basis);
+        if dict.is_empty() {
+            let mut roots = BTreeSet::new();
+            for name in &all_dicts {
+                roots.insert(name.split(':').next().unwrap().to_owned());
+            }
+            entries.extend(roots.into_iter().map(|name| (name, DICT, None)));
+            return Ok(entries);
+        }
+
+        let prefix = format!("{}:", dict);
+        let children: BTreeSet<String> = all_dicts
+            .iter()
+            .filter_map(|name| name.strip_prefix(&prefix))
+            .map(|rest| rest.split(':').next().unwrap().to_owned())
+            .collect();
+        if !all_dicts.iter().any(|d| d == dict) && children.is_empty() {
+            return Err(PddbRetcode::NotFound);
+        }
+        for name in self.list_keys(basis, dict) {
+            let size = self
+                .resolve(basis, dict, &name)
+                .read()
+                .and_then(|b| self.basis(b)?.dicts.get(dict)?.get(&name))
+                .map(|k| k.data.len() as u64);
+            entries.push((name, KEY, size));
+        }
+        entries.extend(children.into_iter().map(|name| (name, DICT, None)));
+        Ok(entries)
+    }
+}
+
+/// Read a length-prefixed string out of a hand-packed request, advancing `offset`.
+fn read_name(data: &[u8], offset: &mut usize) -> Option<String> {
+    let len = u32::from_le_bytes(data.get(*offset..*offset + 4)?.try_into().ok()?) as usize;
+    *offset += 4;
+    let name = core::str::from_utf8(data.get(*offset..*offset + len)?).ok()?;
+    *offset += len;
+    Some(name.to_owned())
+}
+
+fn read_key_name(
+    reader: &crate::senres::Reader<'_, crate::senres::Message<'_>>,
+) -> Option<KeyName> {
+    Some(KeyName {
+        basis: reader.try_get_from::<Option<String>>().ok()?,
+        dict: reader.try_get_from().ok()?,
+        key: reader.try_get_from().ok()?,
+    })
+}
+
+/// Reply to a request that returns nothing but a status, using the
+/// fourcc of the request with the last letter replaced.
+fn write_status(data: &mut [u8], fourcc: [u8; 4], result: Result<(), PddbRetcode>) {
+    let mut message = crate::senres::Message::from_mut_slice(data).unwrap();
+    let [a, b, c, _] = fourcc;
+    match result {
+        Ok(()) => {
+            message.writer([a, b, c, b'R']).unwrap();
+        }
+        Err(code) => {
+            let mut writer = message.writer([a, b, c, b'E']).unwrap();
+            writer.append(code as u8);
+        }
+    }
+}
+
+/// Write the fields of a `KstR` or `StaR` reply, starting with the length.
+fn write_metadata<B: SenresMut>(writer: &mut crate::senres::Writer<'_, B>, metadata: &Metadata) {
+    writer.append(metadata.len);
+    writer.append(metadata.basis.as_str());
+    writer.append(metadata.reserved);
+    writer.append(metadata.alloc_hint);
+    writer.append(metadata.flushed);
+}
+
+/// Write a list of names using the layout expected by `KeyList`.
+fn write_name_list(data: &mut [u8], names: &[String]) {
+    data.fill(0);
+    data[0..4].copy_from_slice(&1u32.to_le_bytes());
+    // Only send as many names as will fit in the page
+    let mut count = 0;
+    let mut total = 5;
+    for name in names.iter().take(u8::MAX as usize) {
+        if name.len() > u8::MAX as usize || total + 1 + name.len() > data.len() {
+            break;
+        }
+        total += 1 + name.len();
+        count += 1;
+    }
+    data[4] = count as u8;
+    let mut offset = 5 + count;
+    for (index, name) in names.iter().take(count).enumerate() {
+        data[5 + index] = name.len() as u8;
+        data[offset..offset + name.len()].copy_from_slice(name.as_bytes());
+        offset += name.len();
+    }
+}
+
+/// Take the names that come after `after` and fit in one page, along
+/// with the cursor for the next page if there are more.
+fn name_page(names: Vec<String>, after: Option<&str>) -> (Vec<String>, Option<String>) {
+    // Leave room for the header, the count and the cursor
+    const PAGE_BUDGET: usize = 3800;
+
+    let mut page = vec![];
+    let mut used = 0;
+    let mut next = None;
+    let remaining = names
+        .into_iter()
+        .filter(|name| after.is_none_or(|after| name.as_str() > after));
+    for name in remaining {
+        if used + 4 + name.len() > PAGE_BUDGET {
+            next = page.last().cloned();
+            break;
+        }
+        used += 4 + name.len();
+        page.push(name);
+    }
+    (page, next)
+}
+
+/// Reply to a paged listing with the page, or with an error code.
+fn write_name_page(
+    message: &mut impl SenresMut,
+    ok: [u8; 4],
+    err: [u8; 4],
+    result: Result<(Vec<String>, Option<String>), PddbRetcode>,
+) {
+    match result {
+        Ok((page, next)) => {
+            let mut writer = message.writer(ok).unwrap();
+            writer.append(page.len() as u32);
+            for name in &page {
+                writer.append(name.as_str());
+            }
+            writer.append(next.as_deref());
+        }
+        Err(code) => {
+            let mut writer = message.writer(err).unwrap();
+            writer.append(code as u8);
+        }
+    }
+}
+
+impl MockServer {
+    fn open_key(&mut self, data: &mut [u8]) {
+        let version = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        // The firmware reads a senres request's magic number as the version
+        if self.state.lock().unwrap().firmware && version != 1 {
+            OpenKeyResponse::encode_packed(Err(PddbRetcode::InternalError), data);
+            return;
+        }
+        if version != 1 && version != 2 {
+            if self.state.lock().unwrap().senres_open {
+                self.open_key_senres(data);
+            }
+            // Older servers return requests they don't understand untouched.
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let result = match OpenKeyRequest::decode_packed(data) {
+            Some(request) => state.open_key(&request),
+            None => Err(PddbRetcode::InternalError),
+        };
+        let result = result.map_err(|code| state.reply_code(code));
+        OpenKeyResponse::encode_packed(result, data);
+    }
+
+    fn open_key_senres(&mut self, data: &mut [u8]) {
+        let mut message = crate::senres::Message::from_mut_slice(data).unwrap();
+        let request = message
+            .reader(*b"OpkQ")
+            .and_then(|reader| OpenKeyRequest::read_from(&reader));
+        let result = match request {
+            Some(request) => self.state.lock().unwrap().open_key(&request),
+            None => Err(PddbRetcode::InternalError),
+        };
+        match result {
+            Ok(response) => {
+                let mut writer = message.writer(*b"OpkR").unwrap();
+                response.write_to(&mut writer);
+            }
+            Err(code) => {
+                let mut writer = message.writer(*b"OpkE").unwrap();
+                writer.append(code as u8);
+            }
+        }
+    }
+
+    fn read_key(&mut self, buffer: &mut PddbBuf) {
+        let mut state = self.state.lock().unwrap();
+        let Some(contents) = state.contents(&buffer.token) else {
+            buffer.retcode = PddbRetcode::AccessDenied as u8;
+            return;
+        };
+        let contents = &contents.data;
+        let position = buffer.position as usize;
+        if position > contents.len() {
+            buffer.len = 0;
+            buffer.retcode = PddbRetcode::UnexpectedEof as u8;
+            return;
+        }
+        let count = (buffer.len as usize)
+            .min(buffer.data.len())
+            .min(contents.len() - position);
+        buffer.data[..count].copy_from_slice(&contents[position..position + count]);
+        buffer.len = count as u16;
+        buffer.retcode = PddbRetcode::Ok as u8;
+    }
+
+    fn write_key(&mut self, buffer: &mut PddbBuf) {
+        let mut state = self.state.lock().unwrap();
+        if !state.handles.get(&buffer.token).is_some_and(|h| h.writable) {
+            buffer.retcode = PddbRetcode::AccessDenied as u8;
+            return;
+        }
+        let Some(contents) = state.contents(&buffer.token) else {
+            buffer.retcode = PddbRetcode::AccessDenied as u8;
+            return;
+        };
+        contents.dirty = true;
+        let contents = &mut contents.data;
+        let position = buffer.position as usize;
+        let count = (buffer.len as usize).min(buffer.data.len());
+        if contents.len() < position + count {
+            contents.resize(position + count, 0);
+        }
+        contents[position..position + count].copy_from_slice(&buffer.data[..count]);
+        buffer.len = count as u16;
+        buffer.retcode = PddbRetcode::Ok as u8;
+    }
+
+    /// Handle a multi-page read or write, stopping at the first page
+    /// that could not be transferred in full.
+    fn transfer_pages(&mut self, data: &mut [u8], write: bool) {
+        for page in data.chunks_exact_mut(core::mem::size_of::<PddbBuf>()) {
+            let page = unsafe { &mut *(page.as_mut_ptr() as *mut PddbBuf) };
+            let requested = page.len;
+            if write {
+                self.write_key(page);
+            } else {
+                self.read_key(page);
+            }
+            if page.retcode() != PddbRetcode::Ok || page.len < requested {
+                break;
+            }
+        }
+    }
+
+    fn key_stat(&mut self, data: &mut [u8]) {
+        let mut message = crate::senres::Message::from_mut_slice(data).unwrap();
+        let token = message
+            .reader(*b"KstQ")
+            .and_then(|reader| reader.try_get_from::<[u32; 3]>().ok());
+        let mut state = self.state.lock().unwrap();
+        match token.and_then(|token| state.metadata(&ApiToken(token))) {
+            Some(metadata) => {
+                let mut writer = message.writer(*b"KstR").unwrap();
+                write_metadata(&mut writer, &metadata);
+            }
+            None => {
+                let mut writer = message.writer(*b"KstE").unwrap();
+                writer.append(PddbRetcode::AccessDenied as u8);
+            }
+        }
+    }
+
+    /// Decode a senres request using `parse`, pass it to `run`, and reply
+    /// with the resulting status.
+    fn status_request<T>(
+        &mut self,
+        data: &mut [u8],
+        fourcc: [u8; 4],
+        parse: impl FnOnce(&crate::senres::Reader<'_, crate::senres::Message<'_>>) -> Option<T>,
+        run: impl FnOnce(&mut State, T) -> Result<(), PddbRetcode>,
+    ) {
+        let request = {
+            let message = crate::senres::Message::from_slice(data).unwrap();
+            let request = message.reader(fourcc).and_then(|reader| parse(&reader));
+            request
+        };
+        let mut state = self.state.lock().unwrap();
+        let result = match request {
+            Some(request) => run(&mut state, request),
+            None => Err(PddbRetcode::InternalError),
+        };
+        if state.firmware && result.is_ok() {
+            return;
+        }
+        let result = result.map_err(|code| state.reply_code(code));
+        write_status(data, fourcc, result);
+    }
+
+    fn basis_order(&mut self, data: &mut [u8]) {
+        let mut message = crate::senres::Message::from_mut_slice(data).unwrap();
+        let state = self.state.lock().unwrap();
+        let mut writer = message.writer(*b"BorR").unwrap();
+        writer.append(state.bases.len() as u32);
+        for basis in state.bases.iter().rev() {
+            writer.append(basis.name.as_str());
+        }
+    }
+
+    fn stat_path(&mut self, data: &mut [u8]) {
+        let mut message = crate::senres::Message::from_mut_slice(data).unwrap();
+        let path = message
+            .reader(*b"StaQ")
+            .and_then(|reader| reader.try_get_from::<String>().ok());
+        let state = self.state.lock().unwrap();
+        let result = match path {
+            Some(path) => state.stat(&path),
+            None => Err(PddbRetcode::InternalError),
+        };
+        let result = result.map_err(|code| state.reply_code(code));
+        let firmware = state.firmware;
+        drop(state);
+        match result {
+            Ok(metadata) => {
+                let mut writer = message.writer(*b"StaR").unwrap();
+                writer.append(metadata.kind as u8);
+                // The firmware only reports the kind
+                if !firmware {
+                    write_metadata(&mut writer, &metadata);
+                }
+            }
+            Err(code) => {
+                let mut writer = message.writer(*b"StaE").unwrap();
+                writer.append(code as u8);
+            }
+        }
+    }
+
+    fn list_keys(&mut self, data: &mut [u8]) {
+        if u32::from_le_bytes(data[0..4].try_into().unwrap()) != 1 {
+            if self.state.lock().unwrap().paged_keys {
+                self.list_keys_paged(data);
+            }
+            // Older servers return requests they don't understand untouched.
+            return;
+        }
+        let mut offset = 4;
+        let basis = read_name(data, &mut offset).unwrap_or_default();
+        let dict = read_name(data, &mut offset).unwrap_or_default();
+        let basis = if basis.is_empty() { None } else { Some(basis) };
+        let keys = self
+            .state
+            .lock()
+            .unwrap()
+            .list_keys(basis.as_deref(), &dict);
+        write_name_list(data, &keys);
+    }
+
+    fn list_keys_paged(&mut self, data: &mut [u8]) {
+        let mut message = crate::senres::Message::from_mut_slice(data).unwrap();
+        let request = message.reader(*b"LskQ").and_then(|reader| {
+            let basis = reader.try_get_from::<Option<String>>().ok()?;
+            let dict = reader.try_get_from::<String>().ok()?;
+            let after = reader.try_get_from::<Option<String>>().ok()?;
+            Some((basis, dict, after))
+        });
+        let state = self.state.lock().unwrap();
+        let result = match request {
+            Some((Some(basis), _, _)) if state.basis(&basis).is_none() => {
+                Err(PddbRetcode::BasisLost)
+            }
+            Some((basis, dict, after)) => Ok(name_page(
+                state.list_keys(basis.as_deref(), &dict),
+                after.as_deref(),
+            )),
+            None => Err(PddbRetcode::InternalError),
+        };
+        drop(state);
+        write_name_page(&mut message, *b"LskR", *b"LskE", result);
+    }
+
+    fn list_dicts_paged(&mut self, data: &mut [u8]) {
+        let mut message = crate::senres::Message::from_mut_slice(data).unwrap();
+        let request = message.reader(*b"LsdQ").and_then(|reader| {
+            let basis = reader.try_get_from::<Option<String>>().ok()?;
+            let after = reader.try_get_from::<Option<String>>().ok()?;
+            Some((basis, after))
+        });
+        let state = self.state.lock().unwrap();
+        let result = match request {
+            Some((Some(basis), _)) if state.basis(&basis).is_none() => Err(PddbRetcode::BasisLost),
+            Some((basis, after)) => Ok(name_page(
+                state.list_dicts(basis.as_deref()),
+                after.as_deref(),
+            )),
+            None => Err(PddbRetcode::InternalError),
+        };
+        drop(state);
+        write_name_page(&mut message, *b"LsdR", *b"LsdE", result);
+    }
+
+    fn list_dicts(&mut self, data: &mut [u8]) {
+        if u32::from_le_bytes(data[0..4].try_into().unwrap()) != 1 {
+            if self.state.lock().unwrap().paged_dicts {
+                self.list_dicts_paged(data);
+            }
+            // Older servers return requests they don't understand untouched.
+            return;
+        }
+        let mut offset = 4;
+        let basis = read_name(data, &mut offset).unwrap_or_default();
+        let basis = if basis.is_empty() { None } else { Some(basis) };
+        let dicts = self.state.lock().unwrap().list_dicts(basis.as_deref());
+        write_name_list(data, &dicts);
+    }
+
+    fn list_bases(&mut self, data: &mut [u8]) {
+        let names: Vec<String> = self
+            .state
+            .lock()
+            .unwrap()
+            .bases
+            .iter()
+            .map(|b| b.name.clone())
+            .collect();
+        let mut message = crate::senres::Message::from_mut_slice(data).unwrap();
+        let mut writer = message.writer(*b"basR").unwrap();
+        writer.append(names.len() as u32);
+        for name in &names {
+            writer.append(name.as_str());
+        }
+    }
+
+    fn capabilities(&mut self, data: &mut [u8]) {
+        let mut message = crate::senres::Message::from_mut_slice(data).unwrap();
+        let capabilities = self.state.lock().unwrap().capabilities();
+        let mut writer = message.writer(*b"CapR").unwrap();
+        capabilities.write_to(&mut writer);
+    }
+
+    fn basis_info(&mut self, data: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        let mut message = crate::senres::Message::from_mut_slice(data).unwrap();
+        let mut writer = message.writer(*b"BinR").unwrap();
+        writer.append((state.bases.len() + state.locked.len()) as u32);
+        for (priority, basis) in state.bases.iter().rev().enumerate() {
+            writer.append(basis.name.as_str());
+            writer.append(basis.name == SYSTEM_BASIS);
+            writer.append(true);
+            writer.append(Some(priority as u32));
+            writer.append(Some(
+                basis.dicts.values().map(|d| d.len() as u64).sum::<u64>(),
+            ));
+            writer.append(Some(basis.dicts.len() as u64));
+        }
+        // The contents of a locked basis can't be counted without its key
+        for basis in &state.locked {
+            writer.append(basis.name.as_str());
+            writer.append(false);
+            writer.append(false);
+            writer.append(None::<u32>);
+            writer.append(None::<u64>);
+            writer.append(None::<u64>);
+        }
+    }
+
+    fn list_path(&mut self, data: &mut [u8]) {
+        let mut message = crate::senres::Message::from_mut_slice(data).unwrap();
+        let request = message.reader(*b"PthQ").and_then(|reader| {
+            let path = reader.try_get_from::<String>().ok()?;
+            // Older clients send only the path.
+            let wants_flags = reader.try_get_from::<bool>().unwrap_or(false);
+            Some((path, wants_flags))
+        });
+        let state = self.state.lock().unwrap();
+        let (result, with_flags) = match request {
+            Some((path, wants_flags)) => (state.list_path(&path), wants_flags && state.path_flags),
+            None => (Err(PddbRetcode::InternalError), false),
+        };
+        let result = result.map_err(|code| state.reply_code(code));
+        drop(state);
+        match result {
+            Ok(entries) if with_flags => {
+                // Merge names that are listed once as a dict and once as a key.
+                let mut merged: Vec<(String, u8, Option<u64>)> = vec![];
+                for (name, kind, size) in entries {
+                    let flag = 1u8 << kind;
+                    match merged.iter_mut().find(|(n, _, _)| *n == name) {
+                        Some(existing) => {
+                            existing.1 |= flag;
+                            existing.2 = existing.2.or(size);
+                        }
+                        None => merged.push((name, flag, size)),
+                    }
+                }
+                let mut writer = message.writer(*b"PtfR").unwrap();
+                writer.append(merged.len() as u32);
+                for (name, flags, size) in &merged {
+                    writer.append(name.as_str());
+                    writer.append(*flags);
+                    writer.append(*size);
+                }
+            }
+            Ok(entries) => {
+                let mut writer = message.writer(*b"PthR").unwrap();
+                writer.append(entries.len() as u32);
+                for (name, kind, _) in &entries {
+                    writer.append(name.as_str());
+                    writer.append(*kind);
+                }
+            }
+            Err(code) => {
+                let mut writer = message.writer(*b"PthE").unwrap();
+                writer.append(code as u8);
+            }
+        }
+    }
+}
+
+impl transport::Server for MockServer {
+    fn handle(&mut self, message: &mut xous::Message) -> Result<xous::Result, xous::Error> {
+        let opcode = message.id();
+        let (multi_page, capability_query) = {
+            let mut state = self.state.lock().unwrap();
+            state.message_count += 1;
+            // Everything from `ReadKeyMulti` on was added after the firmware shipped
+            if state.firmware && opcode >= Opcodes::ReadKeyMulti as usize {
+                return Err(xous::Error::UnhandledSyscall);
+            }
+            (state.multi_page, state.capability_query)
+        };
+        if let Some(scalar) = message.scalar_message() {
+            let token = ApiToken([scalar.arg1 as u32, scalar.arg2 as u32, scalar.arg3 as u32]);
+            let mut state = self.state.lock().unwrap();
+            return match opcode {
+                x if x == Opcodes::IsMounted as usize => {
+                    Ok(xous::Result::Scalar2(if state.mounted { 0 } else { 1 }, 0))
+                }
+                x if x == Opcodes::TryMount as usize => {
+                    if state.mount_delay > 0 {
+                        state.mount_delay -= 1;
+                    } else {
+                        state.mounted = true;
+                    }
+                    Ok(xous::Result::Scalar2(if state.mounted { 0 } else { 1 }, 0))
+                }
+                x if x == Opcodes::PeriodicScrub as usize => {
+                    state.scrub_count += 1;
+                    Ok(xous::Result::Ok)
+                }
+                x if x == Opcodes::WriteKeyFlush as usize => match state.contents(&token) {
+                    Some(key) => {
+                        key.dirty = false;
+                        Ok(xous::Result::Scalar1(PddbRetcode::Ok as usize))
+                    }
+                    None => Ok(xous::Result::Scalar1(PddbRetcode::AccessDenied as usize)),
+                },
+                x if x == Opcodes::KeySetLen as usize => {
+                    if !state.handles.get(&token).is_some_and(|h| h.writable) {
+                        return Ok(xous::Result::Scalar1(PddbRetcode::AccessDenied as usize));
+                    }
+                    match state.contents(&token) {
+                        Some(contents) => {
+                            contents.data.resize(scalar.arg4, 0);
+                            contents.dirty = true;
+                            Ok(xous::Result::Scalar1(PddbRetcode::Ok as usize))
+                        }
+                        None => Ok(xous::Result::Scalar1(PddbRetcode::AccessDenied as usize)),
+                    }
+                }
+                x if x == Opcodes::KeyDrop as usize => {
+                    let found = state.handles.remove(&token).is_some();
+                    Ok(xous::Result::Scalar1(found as usize))
+                }
+                _ => Err(xous::Error::UnhandledSyscall),
+            };
+        }
+
+        let memory = message
+            .memory_message_mut()
+            .ok_or(xous::Error::ShareViolation)?;
+        let data = memory.buf.as_slice_mut::<u8>();
+        if data.len() < 4096 {
+            return Err(xous::Error::BadAlignment);
+        }
+        match opcode {
+            x if x == Opcodes::OpenKeyStd as usize => self.open_key(data),
+            x if x == Opcodes::ReadKeyStd as usize => {
+                self.read_key(unsafe { &mut *(data.as_mut_ptr() as *mut PddbBuf) })
+            }
+            x if x == Opcodes::WriteKeyStd as usize => {
+                self.write_key(unsafe { &mut *(data.as_mut_ptr() as *mut PddbBuf) })
+            }
+            x if x == Opcodes::ReadKeyMulti as usize && multi_page => {
+                self.transfer_pages(data, false)
+            }
+            x if x == Opcodes::WriteKeyMulti as usize && multi_page => {
+                self.transfer_pages(data, true)
+            }
+            x if x == Opcodes::KeyStat as usize => self.key_stat(data),
+            x if x == Opcodes::ListKeyStd as usize => self.list_keys(data),
+            x if x == Opcodes::ListDictStd as usize => self.list_dicts(data),
+            x if x == Opcodes::ListBasisStd as usize => self.list_bases(data),
+            x if x == Opcodes::ListBasisInfo as usize => self.basis_info(data),
+            x if x == Opcodes::Capabilities as usize && capability_query => self.capabilities(data),
+            x if x == Opcodes::ListPathStd as usize => self.list_path(data),
+            x if x == Opcodes::StatPathStd as usize => self.stat_path(data),
+            x if x == Opcodes::DeleteKeyStd as usize => {
+                self.status_request(data, *b"DlkQ", read_key_name, |state, name| {
+                    state.delete_key(&name)
+                })
+            }
+            x if x == Opcodes::RenameKey as usize => self.status_request(
+                data,
+                *b"RnkQ",
+                |reader| Some((read_key_name(reader)?, read_key_name(reader)?)),
+                |state, (from, to)| state.copy_key(&from, &to, true),
+            ),
+            x if x == Opcodes::CopyKey as usize => self.status_request(
+                data,
+                *b"CpkQ",
+                |reader| Some((read_key_name(reader)?, read_key_name(reader)?)),
+                |state, (from, to)| state.copy_key(&from, &to, false),
+            ),
+            x if x == Opcodes::CreateBasis as usize => self.status_request(
+                data,
+                *b"CrbQ",
+                |reader| {
+                    Some((
+                        reader.try_get_from::<String>().ok()?,
+                        reader.try_get_from::<String>().ok()?,
+                    ))
+                },
+                |state, (name, password)| state.create_basis(&name, &password),
+            ),
+            x if x == Opcodes::UnlockBasis as usize => self.status_request(
+                data,
+                *b"UnbQ",
+                |reader| {
+                    Some((
+                        reader.try_get_from::<String>().ok()?,
+                        reader.try_get_from::<String>().ok()?,
+                    ))
+                },
+                |state, (name, password)| state.unlock_basis(&name, &password),
+            ),
+            x if x == Opcodes::LockBasis as usize => self.status_request(
+                data,
+                *b"LkbQ",
+                |reader| reader.try_get_from::<String>().ok(),
+                |state, name| state.lock_basis(&name),
+            ),
+            x if x == Opcodes::DeleteBasis as usize => self.status_request(
+                data,
+                *b"DlbQ",
+                |reader| reader.try_get_from::<String>().ok(),
+                |state, name| state.delete_basis(&name),
+            ),
+            x if x == Opcodes::SetBasisOrder as usize => self.status_request(
+                data,
+                *b"SboQ",
+                |reader| {
+                    let count = reader.try_get_from::<u32>().ok()?;
+                    (0..count)
+                        .map(|_| reader.try_get_from::<String>().ok())
+                        .collect::<Option<Vec<String>>>()
+                },
+                |state, order| state.set_basis_order(&order),
+            ),
+            x if x == Opcodes::BasisOrder as usize => self.basis_order(data),
+            x if x == Opcodes::CreateDictStd as usize => self.status_request(
+                data,
+                *b"CrdQ",
+                |reader| {
+                    Some((
+                        reader.try_get_from::<Option<String>>().ok()?,
+                        reader.try_get_from::<String>().ok()?,
+                    ))
+                },
+                |state, (basis, dict)| match state.create_dict(basis.as_deref(), &dict) {
+                    // The firmware creates dicts that already exist without complaint
+                    Err(PddbRetcode::AlreadyExists) if state.firmware => Ok(()),
+                    result => result,
+                },
+            ),
+            x if x == Opcodes::DeleteDictStd as usize => self.status_request(
+                data,
+                *b"DldQ",
+                |reader| {
+                    Some((
+                        reader.try_get_from::<Option<String>>().ok()?,
+                        reader.try_get_from::<String>().ok()?,
+                        reader.try_get_from::<bool>().ok()?,
+                    ))
+                },
+                |state, (basis, dict, recursive)| {
+                    state.delete_dict(basis.as_deref(), &dict, recursive)
+                },
+            ),
+            x if x == Opcodes::RenameDict as usize => self.status_request(
+                data,
+                *b"RndQ",
+                |reader| {
+                    Some((
+                        reader.try_get_from::<Option<String>>().ok()?,
+                        reader.try_get_from::<String>().ok()?,
+                        reader.try_get_from::<String>().ok()?,
+                    ))
+                },
+                |state, (basis, from, to)| state.rename_dict(basis.as_deref(), &from, &to),
+            ),
+            _ => return Err(xous::Error::UnhandledSyscall),
+        }
+        Ok(xous::Result::Ok)
+    }
+}
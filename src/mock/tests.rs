@@ -0,0 +1,1061 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use super::{MockPddb, SYSTEM_BASIS};
+use crate::dict::{Entry, EntryFlags};
+
+fn names(entries: &crate::dict::PathList) -> Vec<String> {
+    entries.iter().map(|e: &Entry| format!("{:?}", e)).collect()
+}
+
+fn populated() -> MockPddb {
+    let mock = MockPddb::new();
+    mock.insert_key(None, "sys.rtc", "tz_offset", &[1, 2, 3, 4]);
+    mock.insert_key(None, "wlan.networks", "Home Wifi", b"hunter2");
+    mock.insert_key(None, "wlan.networks", "Renode", b"renode");
+    mock.add_dict(None, "wlan.networks:recent");
+    mock
+}
+
+#[test]
+fn wait_for_mount() {
+    let mock = MockPddb::new();
+    mock.mount_after(3);
+    let pddb = mock.connect();
+    assert!(!pddb.is_mounted().unwrap());
+    assert_eq!(pddb.wait_mounted().unwrap(), 3);
+    assert!(pddb.is_mounted().unwrap());
+}
+
+#[test]
+fn scrub() {
+    let mock = MockPddb::new();
+    let pddb = mock.connect();
+    pddb.scrub().unwrap();
+    pddb.scrub().unwrap();
+    assert_eq!(mock.scrub_count(), 2);
+}
+
+#[test]
+fn unregistered_server() {
+    let pddb = {
+        let mock = MockPddb::new();
+        mock.connect()
+    };
+    assert!(pddb.try_mount().is_err());
+}
+
+#[test]
+fn list_bases() {
+    let mock = MockPddb::new();
+    mock.add_basis("secret");
+    let pddb = mock.connect();
+    let bases = pddb.list_bases().unwrap();
+    assert_eq!(bases.len(), 2);
+    assert_eq!(bases.iter().collect::<Vec<_>>(), [SYSTEM_BASIS, "secret"]);
+}
+
+#[test]
+fn basis_info() {
+    let mock = populated();
+    mock.add_basis("work");
+    mock.insert_key(Some("work"), "vault", "bank", b"1234");
+    let pddb = mock.connect();
+    pddb.create_basis("home", "pw").unwrap();
+
+    let info = pddb.basis_info().unwrap();
+    assert_eq!(info.len(), 3);
+    let info: Vec<_> = info.into_iter().collect();
+
+    assert_eq!(info[0].name(), "work");
+    assert!(!info[0].is_system());
+    assert!(info[0].is_unlocked());
+    assert_eq!(info[0].priority(), Some(0));
+    assert_eq!(info[0].key_count(), Some(1));
+    assert_eq!(info[0].dict_count(), Some(1));
+
+    assert_eq!(info[1].name(), SYSTEM_BASIS);
+    assert!(info[1].is_system());
+    assert_eq!(info[1].priority(), Some(1));
+    assert_eq!(info[1].key_count(), Some(3));
+    assert_eq!(info[1].dict_count(), Some(3));
+
+    assert_eq!(info[2].name(), "home");
+    assert!(!info[2].is_unlocked());
+    assert_eq!(info[2].priority(), None);
+    assert_eq!(info[2].key_count(), None);
+
+    pddb.unlock_basis("home", "pw").unwrap();
+    let info = pddb.basis_info().unwrap();
+    let names: Vec<_> = info.iter().map(|b| (b.name(), b.priority())).collect();
+    assert_eq!(
+        names,
+        [
+            ("home", Some(0)),
+            ("work", Some(1)),
+            (SYSTEM_BASIS, Some(2))
+        ]
+    );
+}
+
+#[test]
+fn list_dicts_in_union() {
+    let mock = populated();
+    mock.add_basis("secret");
+    mock.insert_key(Some("secret"), "vault.passwords", "bank", b"1234");
+    let pddb = mock.connect();
+
+    let dicts = pddb.list_dicts(None).unwrap();
+    assert_eq!(
+        dicts,
+        [
+            "sys.rtc",
+            "vault.passwords",
+            "wlan.networks",
+            "wlan.networks:recent"
+        ]
+    );
+
+    let dicts = pddb.list_dicts(Some("secret")).unwrap();
+    assert_eq!(dicts, ["vault.passwords"]);
+
+    assert_eq!(
+        pddb.list_dicts(Some("locked")).unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn list_dicts_paged() {
+    let mock = MockPddb::new();
+    // More dicts than the original format could count, and more than fit in one page
+    let names: Vec<String> = (0..300)
+        .map(|i| format!("a fairly long dict name, number {:03}", i))
+        .collect();
+    for name in &names {
+        mock.add_dict(None, name);
+    }
+    let pddb = mock.connect();
+
+    let before = mock.message_count();
+    assert_eq!(pddb.list_dicts(None).unwrap(), names);
+    assert!(mock.message_count() - before > 1);
+    assert_eq!(pddb.list_dicts(Some(SYSTEM_BASIS)).unwrap(), names);
+
+    // The firmware can only send one page, so a full one is an error
+    mock.firmware_only();
+    let pddb = mock.connect();
+    assert_eq!(
+        pddb.list_dicts(None).unwrap_err().kind(),
+        std::io::ErrorKind::Other
+    );
+    for name in &names[10..] {
+        pddb.delete_dict(None, name, false).unwrap();
+    }
+    assert_eq!(pddb.list_dicts(None).unwrap(), names[..10]);
+}
+
+#[test]
+fn list_keys_in_basis() {
+    let mock = populated();
+    mock.add_basis("secret");
+    mock.insert_key(Some("secret"), "wlan.networks", "Secret Wifi", b"x");
+    let pddb = mock.connect();
+
+    let keys = pddb.list_keys(None, "wlan.networks").unwrap();
+    let keys: Vec<&str> = keys.iter().collect();
+    assert_eq!(keys, ["Home Wifi", "Renode", "Secret Wifi"]);
+
+    let keys = pddb.list_keys(Some(SYSTEM_BASIS), "wlan.networks").unwrap();
+    let keys: Vec<&str> = keys.iter().collect();
+    assert_eq!(keys, ["Home Wifi", "Renode"]);
+}
+
+#[test]
+fn list_keys_paged() {
+    let mock = MockPddb::new();
+    // More keys than the original format could count, and more than fit in one page
+    let names: Vec<String> = (0..300)
+        .map(|i| format!("a fairly long key name, number {:03}", i))
+        .collect();
+    for name in &names {
+        mock.insert_key(None, "big", name, b"");
+    }
+    let pddb = mock.connect();
+
+    let first = pddb.list_keys_page(None, "big", None).unwrap();
+    assert!(first.len() < names.len());
+    let cursor = first.next_cursor().unwrap();
+    assert_eq!(cursor, first.iter().last().unwrap());
+    let second = pddb.list_keys_page(None, "big", Some(cursor)).unwrap();
+    assert_eq!(second.iter().next(), Some(names[first.len()].as_str()));
+
+    let keys = pddb.list_keys(None, "big").unwrap();
+    assert_eq!(keys.len(), 300);
+    assert_eq!(keys.next_cursor(), None);
+    assert!(keys.iter().eq(names.iter().map(String::as_str)));
+
+    assert_eq!(
+        pddb.list_keys(Some("missing"), "big")
+            .map(drop)
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn list_keys_legacy() {
+    let mock = populated();
+    mock.legacy_key_listing();
+    let pddb = mock.connect();
+
+    let keys = pddb.list_keys(None, "wlan.networks").unwrap();
+    assert_eq!(keys.iter().collect::<Vec<_>>(), ["Home Wifi", "Renode"]);
+    assert!(pddb
+        .list_keys_page(None, "wlan.networks", Some("Home Wifi"))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn list_path() {
+    let mock = populated();
+    let pddb = mock.connect();
+
+    assert_eq!(
+        names(&pddb.list_path(":").unwrap()),
+        [r#"Entry { name: ".System", flags: Basis, size: None }"#]
+    );
+    assert_eq!(
+        names(&pddb.list_path("").unwrap()),
+        [
+            r#"Entry { name: "sys.rtc", flags: Dict, size: None }"#,
+            r#"Entry { name: "wlan.networks", flags: Dict, size: None }"#
+        ]
+    );
+    assert_eq!(
+        names(&pddb.list_path(":.System:wlan.networks").unwrap()),
+        [
+            r#"Entry { name: "Home Wifi", flags: Key, size: Some(7) }"#,
+            r#"Entry { name: "Renode", flags: Key, size: Some(6) }"#,
+            r#"Entry { name: "recent", flags: Dict, size: None }"#
+        ]
+    );
+    assert_eq!(
+        pddb.list_path("nonexistent").err().unwrap().kind(),
+        std::io::ErrorKind::NotFound
+    );
+    assert_eq!(
+        pddb.list_path(":nonexistent:").err().unwrap().kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn open_missing_key() {
+    let mock = populated();
+    let pddb = mock.connect();
+    assert_eq!(
+        pddb.open_key(None, "sys.rtc", "nonexistent")
+            .err()
+            .unwrap()
+            .kind(),
+        std::io::ErrorKind::NotFound
+    );
+    assert_eq!(
+        pddb.open_key(Some("nonexistent"), "sys.rtc", "tz_offset")
+            .err()
+            .unwrap()
+            .kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn read_and_write_key() {
+    let mock = populated();
+    let pddb = mock.connect();
+
+    let mut key = pddb.open_key(None, "sys.rtc", "tz_offset").unwrap();
+    let mut v = vec![];
+    key.read_to_end(&mut v).unwrap();
+    assert_eq!(v, [1, 2, 3, 4]);
+
+    key.seek(SeekFrom::End(0)).unwrap();
+    key.write_all(&[5, 6]).unwrap();
+    key.flush().unwrap();
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "tz_offset").unwrap(),
+        [1, 2, 3, 4, 5, 6]
+    );
+
+    assert_eq!(mock.open_handles(), 1);
+    drop(key);
+    assert_eq!(mock.open_handles(), 0);
+}
+
+#[test]
+fn union_reads_prefer_newest_basis() {
+    let mock = populated();
+    mock.add_basis("secret");
+    mock.insert_key(Some("secret"), "wlan.networks", "Home Wifi", b"secret");
+    let pddb = mock.connect();
+
+    let mut v = vec![];
+    pddb.open_key(None, "wlan.networks", "Home Wifi")
+        .unwrap()
+        .read_to_end(&mut v)
+        .unwrap();
+    assert_eq!(v, b"secret");
+
+    let mut v = vec![];
+    pddb.open_key(Some(SYSTEM_BASIS), "wlan.networks", "Home Wifi")
+        .unwrap()
+        .read_to_end(&mut v)
+        .unwrap();
+    assert_eq!(v, b"hunter2");
+}
+
+#[test]
+fn union_writes_go_to_containing_basis() {
+    let mock = populated();
+    mock.add_basis("secret");
+    let pddb = mock.connect();
+
+    pddb.open_key(None, "wlan.networks", "Renode")
+        .unwrap()
+        .write_all(b"RENODE")
+        .unwrap();
+    assert_eq!(
+        mock.key_contents(Some(SYSTEM_BASIS), "wlan.networks", "Renode")
+            .unwrap(),
+        b"RENODE"
+    );
+    assert!(mock
+        .key_contents(Some("secret"), "wlan.networks", "Renode")
+        .is_none());
+}
+
+#[test]
+fn stat() {
+    let mock = populated();
+    mock.add_basis("secret");
+    mock.insert_key(Some("secret"), "wlan.networks", "Home Wifi", b"secret");
+    let pddb = mock.connect();
+
+    let metadata = pddb.stat("wlan.networks:Home Wifi").unwrap();
+    assert!(metadata.is_key());
+    assert_eq!(metadata.len(), 6);
+    assert_eq!(metadata.basis(), "secret");
+
+    let metadata = pddb.stat(":.System:wlan.networks:Home Wifi").unwrap();
+    assert_eq!(metadata.len(), 7);
+    assert_eq!(metadata.basis(), SYSTEM_BASIS);
+
+    let metadata = pddb.stat("wlan.networks:Renode").unwrap();
+    assert_eq!(metadata.basis(), SYSTEM_BASIS);
+
+    assert!(pddb.stat("wlan.networks").unwrap().is_dict());
+    assert!(pddb.stat("wlan.networks:recent").unwrap().is_dict());
+    assert!(pddb.stat(":secret:wlan.networks").unwrap().is_dict());
+    assert!(pddb.stat(":secret").unwrap().is_basis());
+    assert!(pddb.stat(":.System:").unwrap().is_basis());
+
+    for missing in [
+        "nonexistent",
+        "wlan.networks:nonexistent",
+        ":secret:sys.rtc",
+        ":nonexistent:",
+        ":nonexistent:wlan.networks",
+    ] {
+        assert_eq!(
+            pddb.stat(missing).err().unwrap().kind(),
+            std::io::ErrorKind::NotFound,
+            "{}",
+            missing
+        );
+    }
+}
+
+#[test]
+fn delete_key() {
+    let mock = populated();
+    mock.add_basis("secret");
+    mock.insert_key(Some("secret"), "wlan.networks", "Home Wifi", b"secret");
+    let pddb = mock.connect();
+
+    // Deleting from the union removes the copy that reads come from
+    pddb.delete_key(None, "wlan.networks", "Home Wifi").unwrap();
+    assert!(mock
+        .key_contents(Some("secret"), "wlan.networks", "Home Wifi")
+        .is_none());
+    assert_eq!(
+        mock.key_contents(None, "wlan.networks", "Home Wifi")
+            .unwrap(),
+        b"hunter2"
+    );
+    pddb.delete_key(Some(SYSTEM_BASIS), "wlan.networks", "Home Wifi")
+        .unwrap();
+
+    let e = pddb
+        .delete_key(None, "wlan.networks", "Home Wifi")
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    let e = pddb
+        .delete_key(Some("locked"), "wlan.networks", "Renode")
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    assert!(e.to_string().contains("locked"), "{}", e);
+}
+
+#[test]
+fn rename_key() {
+    let mock = populated();
+    mock.add_basis("secret");
+    let pddb = mock.connect();
+    let mut handle = pddb.open_key(None, "wlan.networks", "Renode").unwrap();
+
+    // Within a dict
+    pddb.rename_key(
+        None,
+        "wlan.networks",
+        "Renode",
+        None,
+        "wlan.networks",
+        "Emulator",
+    )
+    .unwrap();
+    assert!(mock.key_contents(None, "wlan.networks", "Renode").is_none());
+    assert_eq!(
+        mock.key_contents(Some(SYSTEM_BASIS), "wlan.networks", "Emulator")
+            .unwrap(),
+        b"renode"
+    );
+
+    // Across dicts, and open handles follow the key
+    pddb.rename_key(
+        None,
+        "wlan.networks",
+        "Emulator",
+        None,
+        "sys.rtc",
+        "Emulator",
+    )
+    .unwrap();
+    handle.write_all(b"R").unwrap();
+    assert_eq!(
+        mock.key_contents(None, "sys.rtc", "Emulator").unwrap(),
+        b"Renode"
+    );
+
+    // Across bases
+    pddb.rename_key(
+        None,
+        "sys.rtc",
+        "Emulator",
+        Some("secret"),
+        "sys.rtc",
+        "Emulator",
+    )
+    .unwrap();
+    assert!(mock
+        .key_contents(Some(SYSTEM_BASIS), "sys.rtc", "Emulator")
+        .is_none());
+    assert_eq!(
+        mock.key_contents(Some("secret"), "sys.rtc", "Emulator")
+            .unwrap(),
+        b"Renode"
+    );
+
+    // Renaming a key to itself does nothing
+    pddb.rename_key(None, "sys.rtc", "Emulator", None, "sys.rtc", "Emulator")
+        .unwrap();
+}
+
+#[test]
+fn rename_key_errors() {
+    let mock = populated();
+    let pddb = mock.connect();
+    let kind = |result: std::io::Result<()>| result.unwrap_err().kind();
+
+    assert_eq!(
+        kind(pddb.rename_key(None, "wlan.networks", "nonexistent", None, "sys.rtc", "x")),
+        std::io::ErrorKind::NotFound
+    );
+    assert_eq!(
+        kind(pddb.rename_key(
+            None,
+            "wlan.networks",
+            "Renode",
+            None,
+            "wlan.networks",
+            "Home Wifi"
+        )),
+        std::io::ErrorKind::AlreadyExists
+    );
+    assert_eq!(
+        kind(pddb.rename_key(None, "wlan.networks", "Renode", None, "nonexistent", "x")),
+        std::io::ErrorKind::NotFound
+    );
+    assert_eq!(
+        kind(pddb.rename_key(
+            None,
+            "wlan.networks",
+            "Renode",
+            Some("locked"),
+            "sys.rtc",
+            "x"
+        )),
+        std::io::ErrorKind::NotFound
+    );
+    assert_eq!(
+        mock.key_contents(None, "wlan.networks", "Renode").unwrap(),
+        b"renode"
+    );
+}
+
+#[test]
+fn copy_key() {
+    let mock = populated();
+    mock.add_basis("secret");
+    let pddb = mock.connect();
+
+    pddb.copy_key(None, "wlan.networks", "Renode", None, "sys.rtc", "Renode")
+        .unwrap();
+    pddb.copy_key(
+        None,
+        "wlan.networks",
+        "Renode",
+        Some("secret"),
+        "wlan.networks",
+        "Renode",
+    )
+    .unwrap();
+    for (basis, dict) in [
+        (SYSTEM_BASIS, "wlan.networks"),
+        (SYSTEM_BASIS, "sys.rtc"),
+        ("secret", "wlan.networks"),
+    ] {
+        assert_eq!(
+            mock.key_contents(Some(basis), dict, "Renode").unwrap(),
+            b"renode"
+        );
+    }
+
+    assert_eq!(
+        pddb.copy_key(
+            None,
+            "wlan.networks",
+            "Renode",
+            Some(SYSTEM_BASIS),
+            "sys.rtc",
+            "Renode"
+        )
+        .unwrap_err()
+        .kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+    assert_eq!(
+        pddb.copy_key(
+            None,
+            "wlan.networks",
+            "Renode",
+            None,
+            "wlan.networks",
+            "Renode"
+        )
+        .unwrap_err()
+        .kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+    assert_eq!(
+        pddb.copy_key(None, "sys.rtc", "nonexistent", None, "sys.rtc", "x")
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn create_dict() {
+    let mock = populated();
+    mock.add_basis("secret");
+    let pddb = mock.connect();
+
+    pddb.create_dict(None, "a:b:c").unwrap();
+    pddb.create_dict(Some(SYSTEM_BASIS), "a").unwrap();
+    assert_eq!(pddb.list_dicts(Some("secret")).unwrap(), ["a:b:c"]);
+    assert_eq!(
+        names(&pddb.list_path("a").unwrap()),
+        [r#"Entry { name: "b", flags: Dict, size: None }"#]
+    );
+    assert!(pddb.stat("a:b").unwrap().is_dict());
+
+    assert_eq!(
+        pddb.create_dict(None, "a:b:c").unwrap_err().kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+    assert_eq!(
+        pddb.create_dict(Some("locked"), "a").unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn delete_dict() {
+    let mock = populated();
+    let pddb = mock.connect();
+
+    assert_eq!(
+        pddb.delete_dict(None, "wlan.networks", false)
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::DirectoryNotEmpty
+    );
+    pddb.delete_dict(None, "wlan.networks:recent", false)
+        .unwrap();
+    assert_eq!(pddb.list_dicts(None).unwrap(), ["sys.rtc", "wlan.networks"]);
+
+    // A dict with only nested dicts is not empty either
+    pddb.create_dict(None, "a:b").unwrap();
+    assert_eq!(
+        pddb.delete_dict(None, "a", false).unwrap_err().kind(),
+        std::io::ErrorKind::DirectoryNotEmpty
+    );
+    pddb.delete_dict(None, "a", true).unwrap();
+
+    pddb.delete_dict(None, "wlan.networks", true).unwrap();
+    assert_eq!(pddb.list_dicts(None).unwrap(), ["sys.rtc"]);
+    assert!(mock.key_contents(None, "wlan.networks", "Renode").is_none());
+    assert_eq!(
+        pddb.delete_dict(None, "wlan.networks", true)
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn rename_dict() {
+    let mock = populated();
+    let pddb = mock.connect();
+    let mut handle = pddb.open_key(None, "wlan.networks", "Renode").unwrap();
+
+    pddb.rename_dict(None, "wlan.networks", "wifi").unwrap();
+    assert_eq!(
+        pddb.list_dicts(None).unwrap(),
+        ["sys.rtc", "wifi", "wifi:recent"]
+    );
+    assert_eq!(
+        pddb.list_keys(None, "wifi")
+            .unwrap()
+            .iter()
+            .collect::<Vec<_>>(),
+        ["Home Wifi", "Renode"]
+    );
+    handle.write_all(b"R").unwrap();
+    assert_eq!(
+        mock.key_contents(None, "wifi", "Renode").unwrap(),
+        b"Renode"
+    );
+
+    assert_eq!(
+        pddb.rename_dict(None, "wifi", "sys.rtc")
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+    assert_eq!(
+        pddb.rename_dict(None, "nonexistent", "x")
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::NotFound
+    );
+    assert!(pddb.rename_dict(None, "wifi", "wifi:inner").is_err());
+}
+
+#[test]
+fn basis_lifecycle() {
+    let mock = populated();
+    let pddb = mock.connect();
+
+    pddb.create_basis("secret", "hunter2").unwrap();
+    assert_eq!(
+        pddb.create_basis("secret", "other").unwrap_err().kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+    // Newly-created bases start out locked
+    assert_eq!(pddb.basis_order().unwrap(), [SYSTEM_BASIS]);
+
+    // A wrong password looks the same as a basis that does not exist
+    assert_eq!(
+        pddb.unlock_basis("secret", "wrong").unwrap_err().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        pddb.unlock_basis("missing", "hunter2").unwrap_err().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+
+    pddb.unlock_basis("secret", "hunter2").unwrap();
+    assert_eq!(pddb.basis_order().unwrap(), ["secret", SYSTEM_BASIS]);
+    pddb.create_dict(Some("secret"), "vault").unwrap();
+    pddb.open_key_with(
+        Some("secret"),
+        "vault",
+        "bank",
+        crate::KeyOptions::new().write(true).create(true),
+    )
+    .unwrap()
+    .write_all(b"1234")
+    .unwrap();
+
+    // Locking hides the basis, and unlocking brings its contents back
+    pddb.lock_basis("secret").unwrap();
+    assert_eq!(pddb.basis_order().unwrap(), [SYSTEM_BASIS]);
+    assert!(pddb.open_key(None, "vault", "bank").is_err());
+    assert_eq!(
+        pddb.lock_basis("secret").unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+    pddb.unlock_basis("secret", "hunter2").unwrap();
+    assert_eq!(mock.key_contents(None, "vault", "bank").unwrap(), b"1234");
+
+    pddb.delete_basis("secret").unwrap();
+    assert_eq!(pddb.basis_order().unwrap(), [SYSTEM_BASIS]);
+    assert_eq!(
+        pddb.unlock_basis("secret", "hunter2").unwrap_err().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+
+    // The system basis is always there
+    assert_eq!(
+        pddb.lock_basis(SYSTEM_BASIS).unwrap_err().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        pddb.delete_basis(SYSTEM_BASIS).unwrap_err().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn basis_order() {
+    let mock = populated();
+    mock.add_basis("work");
+    mock.insert_key(Some("work"), "wlan.networks", "Renode", b"work");
+    let pddb = mock.connect();
+    pddb.create_basis("home", "pw").unwrap();
+    pddb.unlock_basis("home", "pw").unwrap();
+    pddb.create_dict(Some("home"), "wlan.networks").unwrap();
+    pddb.open_key_with(
+        Some("home"),
+        "wlan.networks",
+        "Renode",
+        crate::KeyOptions::new().write(true).create(true),
+    )
+    .unwrap()
+    .write_all(b"home")
+    .unwrap();
+
+    assert_eq!(pddb.basis_order().unwrap(), ["home", "work", SYSTEM_BASIS]);
+    let mut contents = String::new();
+    pddb.open_key(None, "wlan.networks", "Renode")
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "home");
+
+    pddb.set_basis_order(&["work", "home", SYSTEM_BASIS])
+        .unwrap();
+    assert_eq!(pddb.basis_order().unwrap(), ["work", "home", SYSTEM_BASIS]);
+    contents.clear();
+    pddb.open_key(None, "wlan.networks", "Renode")
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "work");
+
+    // Every unlocked basis must be listed once, with the system basis last
+    assert!(pddb.set_basis_order(&["work", SYSTEM_BASIS]).is_err());
+    assert!(pddb
+        .set_basis_order(&[SYSTEM_BASIS, "work", "home"])
+        .is_err());
+    assert!(pddb
+        .set_basis_order(&["work", "home", "missing", SYSTEM_BASIS])
+        .is_err());
+    assert_eq!(pddb.basis_order().unwrap(), ["work", "home", SYSTEM_BASIS]);
+}
+
+#[test]
+fn resolve_key() {
+    let mock = populated();
+    mock.add_basis("work");
+    mock.insert_key(Some("work"), "sys.rtc", "tz_offset", &[5]);
+    let pddb = mock.connect();
+
+    let resolution = pddb.resolve_key(None, "sys.rtc", "tz_offset").unwrap();
+    assert_eq!(resolution.read(), Some("work"));
+    assert_eq!(resolution.write(), Some("work"));
+
+    let resolution = pddb
+        .resolve_key(Some(SYSTEM_BASIS), "sys.rtc", "tz_offset")
+        .unwrap();
+    assert_eq!(resolution.read(), Some(SYSTEM_BASIS));
+
+    // A dict sharing the name of the key is not mistaken for the key
+    let resolution = pddb.resolve_key(None, "wlan.networks", "recent").unwrap();
+    assert_eq!(resolution.read(), None);
+    assert_eq!(resolution.create(), Some("work"));
+
+    // The prediction matches where the key actually ends up
+    let resolution = pddb.resolve_key(None, "sys.rtc", "new").unwrap();
+    pddb.open_key_with(
+        None,
+        "sys.rtc",
+        "new",
+        crate::KeyOptions::new().write(true).create(true),
+    )
+    .unwrap();
+    assert_eq!(
+        pddb.stat("sys.rtc:new").unwrap().basis(),
+        resolution.create().unwrap()
+    );
+
+    assert_eq!(
+        pddb.resolve_key(Some("missing"), "sys.rtc", "tz_offset")
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn invalid_names_are_rejected_before_ipc() {
+    let mock = populated();
+    let pddb = mock.connect();
+    let before = mock.message_count();
+    let long = "x".repeat(4096);
+
+    let errors = [
+        pddb.open_key(None, &long, "key").map(drop).unwrap_err(),
+        pddb.open_key(None, "sys.rtc", "").map(drop).unwrap_err(),
+        pddb.open_key(Some("bad\0basis"), "sys.rtc", "tz_offset")
+            .map(drop)
+            .unwrap_err(),
+        pddb.list_path(&format!(":{}:", long))
+            .map(drop)
+            .unwrap_err(),
+        pddb.list_keys(None, "").map(drop).unwrap_err(),
+        pddb.stat("a\0b").unwrap_err(),
+        pddb.create_dict(None, "a::b").unwrap_err(),
+        pddb.delete_dict(None, "", false).unwrap_err(),
+        pddb.rename_dict(None, "sys.rtc", &long).unwrap_err(),
+        pddb.delete_key(None, "sys.rtc", "a:b").unwrap_err(),
+        pddb.rename_key(None, "sys.rtc", "tz_offset", None, "sys.rtc", "")
+            .unwrap_err(),
+        pddb.create_basis(&long, "password").unwrap_err(),
+        pddb.lock_basis("").unwrap_err(),
+        pddb.set_basis_order(&["a:b", SYSTEM_BASIS]).unwrap_err(),
+    ];
+    for error in errors {
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput, "{}", error);
+    }
+    assert_eq!(mock.message_count(), before);
+}
+
+#[test]
+fn names_are_normalized() {
+    let mock = populated();
+    mock.insert_key(None, "sys.rtc", "caf\u{e9}", b"nfc");
+    let pddb = mock.connect();
+
+    let mut contents = String::new();
+    pddb.open_key(None, "sys.rtc", "cafe\u{301}")
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "nfc");
+
+    let pddb = pddb.with_name_policy(crate::NamePolicy::verbatim());
+    assert_eq!(
+        pddb.open_key(None, "sys.rtc", "cafe\u{301}")
+            .map(drop)
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn name_collisions() {
+    let mock = populated();
+    mock.insert_key(None, "sys.rtc", "caf\u{e9}", b"nfc");
+    mock.insert_key(None, "sys.rtc", "cafe\u{301}", b"nfd");
+    mock.add_dict(None, "Wlan.Networks");
+    let pddb = mock.connect();
+
+    let collisions = pddb.name_collisions(&crate::NamePolicy::new()).unwrap();
+    assert_eq!(collisions.len(), 1);
+    assert_eq!(collisions[0].kind(), crate::dict::EntryKind::Key);
+    assert_eq!(collisions[0].basis(), Some(SYSTEM_BASIS));
+    assert_eq!(collisions[0].dict(), Some("sys.rtc"));
+    assert_eq!(collisions[0].normalized(), "caf\u{e9}");
+    assert_eq!(collisions[0].names().len(), 2);
+
+    let policy = crate::NamePolicy::new().case(crate::path::CasePolicy::Fold);
+    let collisions = pddb.name_collisions(&policy).unwrap();
+    assert_eq!(collisions.len(), 2);
+    assert_eq!(collisions[0].kind(), crate::dict::EntryKind::Dict);
+    assert_eq!(collisions[0].names(), ["Wlan.Networks", "wlan.networks"]);
+}
+
+fn glob(pddb: &crate::Pddb, pattern: &str) -> Vec<(String, EntryFlags)> {
+    pddb.glob(pattern)
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (entry.path().to_string(), entry.flags())
+        })
+        .collect()
+}
+
+#[test]
+fn list_path_dict_and_key() {
+    let mock = populated();
+    mock.insert_key(None, "wlan.networks", "recent", b"abc");
+    let pddb = mock.connect();
+
+    let list = pddb.list_path("wlan.networks").unwrap();
+    assert_eq!(list.len(), 3);
+    let recent = list.iter().find(|e| e.name() == "recent").unwrap();
+    assert_eq!(recent.flags(), EntryFlags::DICT | EntryFlags::KEY);
+    assert!(recent.is_dict() && recent.is_key() && !recent.is_basis());
+    assert_eq!(recent.size(), Some(3));
+    assert_eq!(format!("{:?}", recent.flags()), "Dict | Key");
+}
+
+#[test]
+fn legacy_path_listing() {
+    let mock = populated();
+    mock.insert_key(None, "wlan.networks", "recent", b"abc");
+    mock.legacy_path_listing();
+    let pddb = mock.connect();
+
+    // Older servers list the name twice and don't report sizes
+    assert_eq!(
+        names(&pddb.list_path("wlan.networks").unwrap()),
+        [
+            r#"Entry { name: "Home Wifi", flags: Key, size: None }"#,
+            r#"Entry { name: "Renode", flags: Key, size: None }"#,
+            r#"Entry { name: "recent", flags: Dict | Key, size: None }"#
+        ]
+    );
+}
+
+#[test]
+fn glob_union() {
+    let mock = populated();
+    mock.add_basis("secret");
+    mock.insert_key(Some("secret"), "vault.passwords", "bank", b"1234");
+    mock.insert_key(Some("secret"), "vault.passwords:old", "bank", b"0000");
+    mock.insert_key(Some("secret"), "wlan.networks", "Office", b"office");
+    let pddb = mock.connect();
+
+    assert_eq!(
+        glob(&pddb, "wlan.networks:*"),
+        [
+            ("wlan.networks:Home Wifi".to_owned(), EntryFlags::KEY),
+            ("wlan.networks:Office".to_owned(), EntryFlags::KEY),
+            ("wlan.networks:Renode".to_owned(), EntryFlags::KEY),
+            ("wlan.networks:recent".to_owned(), EntryFlags::DICT),
+        ]
+    );
+    assert_eq!(
+        glob(&pddb, "vault.**"),
+        [
+            ("vault.passwords".to_owned(), EntryFlags::DICT),
+            ("vault.passwords:bank".to_owned(), EntryFlags::KEY),
+            ("vault.passwords:old".to_owned(), EntryFlags::DICT),
+            ("vault.passwords:old:bank".to_owned(), EntryFlags::KEY),
+        ]
+    );
+    assert_eq!(
+        glob(&pddb, "**:bank"),
+        [
+            ("vault.passwords:bank".to_owned(), EntryFlags::KEY),
+            ("vault.passwords:old:bank".to_owned(), EntryFlags::KEY),
+        ]
+    );
+    assert_eq!(
+        glob(&pddb, "::sys.rt?"),
+        [("sys.rtc".to_owned(), EntryFlags::DICT)]
+    );
+    assert!(glob(&pddb, "nothing:*").is_empty());
+}
+
+#[test]
+fn glob_bases() {
+    let mock = populated();
+    mock.add_basis("secret");
+    mock.insert_key(Some("secret"), "wlan.networks", "Office", b"office");
+    let pddb = mock.connect();
+
+    assert_eq!(
+        glob(&pddb, ":*:wlan.networks:*i*"),
+        [
+            (":secret:wlan.networks:Office".to_owned(), EntryFlags::KEY),
+            (
+                ":.System:wlan.networks:Home Wifi".to_owned(),
+                EntryFlags::KEY
+            ),
+        ]
+    );
+    // The basis part of the pattern only matches basis names
+    assert!(glob(&pddb, ":**:Office").is_empty());
+    assert_eq!(
+        glob(&pddb, ":s*"),
+        [(":secret".to_owned(), EntryFlags::BASIS)]
+    );
+    assert_eq!(glob(&pddb, ":*:").len(), 2);
+}
+
+#[test]
+fn glob_is_lazy() {
+    let mock = populated();
+    mock.insert_key(None, "vault.passwords", "bank", b"1234");
+    let pddb = mock.connect();
+
+    // Only the root and `sys.rtc` need to be listed
+    let before = mock.message_count();
+    assert_eq!(glob(&pddb, "sys.rtc:*").len(), 1);
+    assert_eq!(mock.message_count() - before, 2);
+
+    // Nothing is listed until the first result is asked for
+    let before = mock.message_count();
+    let mut matches = pddb.glob("**");
+    assert_eq!(mock.message_count(), before);
+    matches.next().unwrap().unwrap();
+    assert_eq!(mock.message_count() - before, 1);
+}
+
+#[test]
+fn glob_invalid_names() {
+    let mock = populated();
+    mock.insert_key(None, "broken", "a::b", b"");
+    mock.insert_key(None, "broken", "fine", b"");
+    let pddb = mock.connect();
+
+    // The dict with the bad name fails as a whole, and the rest carries on
+    let results: Vec<_> = pddb.glob("**").collect();
+    let errors: Vec<_> = results.iter().filter_map(|r| r.as_ref().err()).collect();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind(), std::io::ErrorKind::InvalidData);
+    let paths: Vec<_> = results
+        .iter()
+        .filter_map(|r| r.as_ref().ok())
+        .map(|entry| entry.path().to_string())
+        .collect();
+    assert!(paths.contains(&"broken".to_owned()));
+    assert!(!paths.iter().any(|path| path.starts_with("broken:")));
+    assert!(paths.contains(&"wlan.networks:Home Wifi".to_owned()));
+}
@@ -1,51 +1,68 @@
-/// # PDDB Path Conventions
-///
-/// A PDDB Path may be a dict, a dict + a key, a basis + dict, or a basis + dict + key.
-/// In the following examples, the given Basis, Dict, and Key are as follows:
-///
-/// * Basis: `.System`
-/// * Dict: `wlan.networks`
-/// * Key: `Home Wifi`
-///
-/// A canonical path looks like:
-///
-///     [:BASIS:]DICT1:DICT2:DICT3[:KEY]
-///
-/// Examples:
-///
-/// * `:Home Wifi` -- A basis named "Home Wifi"
-/// * `:.System:` -- A basis named ".System"
-/// * `wlan.networks` -- A dict named "wlan.networks" in the default basis
-/// * `wlan.networks:recent` -- A dict named "wlan.networks:recent", which may be considered a path, in the default basis. This also desecribes a key called "recent" in the dict "wlan.networks", depending on whether you're treating it as a directory or a file.
-/// * `:.System:wlan.networks` -- A dict named "wlan.networks" in the basis ".System"
-/// * `:.System:wlan.networks:recent` -- a fully-qualified path, describing a key "recent" in the dict "wlan.networks" in the basis ".System". Also describes a dict "wlan.networks:recent" in the basis ".System" when treating it as a directory.
-/// * `:` -- The root, which lists every basis. Files cannot be created here. "Directories" can be
-///             created and destroyed, which corresponds to creating and destroying bases.
-/// * `::` -- An empty basis is a synonym for all bases, so this corresponds to listing all dicts in the root of the default basis.
-/// *  -- An empty string corresponds to listing all dicts in root the union basis.
-///
-/// Corner cases:
-///
-/// * `: :` -- A basis named " ". Legal, but questionable
-/// * ` ` -- A dict named " " in the default basis. Legal, but questionable.
-/// * `: ` -- Also a dict named " " in the default basis.
-/// * ` : ` -- A key named " " in a dict called " ". Legal.
-/// * `baz:` -- A dict named "baz" in the default basis with an extra ":" following. Legal.
-/// * `baz:foo:` -- Identical to "baz:foo", may be either a dict "baz:foo" or a key "foo" in the dict "baz"
-/// * `:::` -- An key named ":" in an empty dict in the default basis. Illegal.
-/// * `::::` -- An key named "::" in an empty dict in the default basis. Illegal.
-/// * `::foo` -- A key "foo" in the default basis.
-/// * `:lorem.ipsum:foo:baz` -- A key called "foo:baz" in the basis "lorem.ipsum". May also describe a dict "foo:baz" in the basis "lorem.ipsum" if treated as a directory.
-/// * `:bar:lorem.ipsum:foo:baz` -- A key called "baz" in the dict "lorem.ipsum:foo" in
-///             the basis "bar", or a dict called "lorem.ipsum:foo:baz". Legal.
-///
-/// Any reference to "default basis" depends on whether the operation is a "read" or a "write":
-///
-/// * "Read" operations come from a union, with the most-recently-added basis taking precedence
-/// * "Write" operations go to the most-recently-added basis that contains the key. If the key does not exist and "Create" was specified, then the file is created in the most-recently-added basis.#[cfg(test)]
+//! # PDDB Path Conventions
+//!
+//! A PDDB Path may be a dict, a dict + a key, a basis + dict, or a basis + dict + key.
+//! In the following examples, the given Basis, Dict, and Key are as follows:
+//!
+//! * Basis: `.System`
+//! * Dict: `wlan.networks`
+//! * Key: `Home Wifi`
+//!
+//! A canonical path looks like:
+//!
+//! ```text
+//! [:BASIS:]DICT1:DICT2:DICT3[:KEY]
+//! ```
+//!
+//! Examples:
+//!
+//! * `:Home Wifi` -- A basis named "Home Wifi"
+//! * `:.System:` -- A basis named ".System"
+//! * `wlan.networks` -- A dict named "wlan.networks" in the default basis
+//! * `wlan.networks:recent` -- A dict named "wlan.networks:recent", which may be considered a path, in the default basis. This also desecribes a key called "recent" in the dict "wlan.networks", depending on whether you're treating it as a directory or a file.
+//! * `:.System:wlan.networks` -- A dict named "wlan.networks" in the basis ".System"
+//! * `:.System:wlan.networks:recent` -- a fully-qualified path, describing a key "recent" in the dict "wlan.networks" in the basis ".System". Also describes a dict "wlan.networks:recent" in the basis ".System" when treating it as a directory.
+//! * `:` -- The root, which lists every basis. Files cannot be created here. "Directories" can be
+//!   created and destroyed, which corresponds to creating and destroying bases.
+//! * `::` -- An empty basis is a synonym for all bases, so this corresponds to listing all dicts in the root of the default basis.
+//! *  -- An empty string corresponds to listing all dicts in root the union basis.
+//!
+//! Corner cases:
+//!
+//! * `: :` -- A basis named " ". Legal, but questionable
+//! * ` ` -- A dict named " " in the default basis. Legal, but questionable.
+//! * `: ` -- Also a dict named " " in the default basis.
+//! * ` : ` -- A key named " " in a dict called " ". Legal.
+//! * `baz:` -- A dict named "baz" in the default basis with an extra ":" following. Legal.
+//! * `baz:foo:` -- Identical to "baz:foo", may be either a dict "baz:foo" or a key "foo" in the dict "baz"
+//! * `:::` -- An key named ":" in an empty dict in the default basis. Illegal.
+//! * `::::` -- An key named "::" in an empty dict in the default basis. Illegal.
+//! * `::foo` -- A key "foo" in the default basis.
+//! * `:lorem.ipsum:foo:baz` -- A key called "baz" in the dict "foo" in the basis "lorem.ipsum", in the same way as `:.System:wlan.networks:recent`. May also describe a dict "foo:baz" in the basis "lorem.ipsum" if treated as a directory.
+//! * `:bar:lorem.ipsum:foo:baz` -- A key called "baz" in the dict "lorem.ipsum:foo" in
+//!   the basis "bar", or a dict called "lorem.ipsum:foo:baz". Legal.
+//!
+//! Any reference to "default basis" depends on whether the operation is a "read" or a "write":
+//!
+//! * "Read" operations come from a union, with the most-recently-added basis taking precedence
+//! * "Write" operations go to the most-recently-added basis that contains the key. If the key does not exist and "Create" was specified, then the file is created in the most-recently-added basis.
+//!
+//! Paths are parsed by [`PddbPath`] and [`PddbPathBuf`], which follow these rules exactly.
+
 #[cfg(test)]
 mod tests;
 
+use std::borrow::Cow;
+
+use crate::dict::EntryKind;
+
+/// The longest basis name the PDDB can store, in bytes.
+pub const BASIS_NAME_LEN: usize = 64;
+/// The longest dict name the PDDB can store, in bytes. This includes any
+/// separators in the name of a nested dict.
+pub const DICT_NAME_LEN: usize = 127;
+/// The longest key name the PDDB can store, in bytes.
+pub const KEY_NAME_LEN: usize = 95;
+
 #[allow(unused)]
 pub const MAIN_SEP_STR: &str = ":";
 #[allow(unused)]
@@ -63,3 +80,614 @@ pub fn is_verbatim_sep(b: u8) -> bool {
     b == b':'
 }
 
+/// If `s` is a direct child of `prefix`, return the name of that child.
+/// An empty `prefix` is the root, so any name without a separator is
+/// returned as-is.
+pub fn get_path<'a>(s: &'a str, prefix: &'a str) -> Option<&'a str> {
+    // Empty strings are invalid
+    if s.is_empty() {
+        return None;
+    }
+    // The "" prefix indicates the root
+    if prefix.is_empty() {
+        let mut s_iter = s.split(MAIN_SEP);
+        let base = s_iter.next();
+        let remainder = s_iter.next();
+        if remainder.is_some() {
+            return None;
+        }
+        return base;
+    }
+    let without_prefix = s.strip_prefix(prefix)?.strip_prefix(MAIN_SEP)?;
+    let mut path_split = without_prefix.split(MAIN_SEP);
+    let parent = path_split.next();
+    if path_split.next().is_some() {
+        None
+    } else {
+        parent
+    }
+}
+
+/// Split a path into its constituant Basis and Dict, if the path is legal.
+/// An empty basis such as `::foo` is replaced with the result of `default()`.
+pub fn split_basis_and_dict<'a, F: Fn() -> Option<&'a str>>(
+    src: &'a str,
+    default: F,
+) -> Result<(Option<&'a str>, Option<&'a str>), PathError> {
+    let mut basis = None;
+    let dict;
+    if let Some(src) = src.strip_prefix(MAIN_SEP) {
+        if let Some((maybe_basis, maybe_dict)) = src.split_once(MAIN_SEP) {
+            if !maybe_basis.is_empty() {
+                basis = Some(maybe_basis);
+            } else {
+                basis = default();
+            }
+
+            if maybe_dict.is_empty() {
+                dict = None;
+            } else {
+                dict = Some(maybe_dict);
+            }
+        } else {
+            if !src.is_empty() {
+                basis = Some(src);
+            }
+            dict = None;
+        }
+    } else {
+        if src.is_empty() {
+            return Ok((basis, Some("")));
+        }
+        dict = Some(src);
+    }
+
+    if basis.is_some_and(|basis| basis.ends_with(MAIN_SEP))
+        || dict.is_some_and(|dict| dict.ends_with(MAIN_SEP))
+    {
+        return Err(PathError::TrailingSeparator);
+    }
+    Ok((basis, dict))
+}
+
+/// The reasons a path can fail to parse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    /// The path contains an empty name, such as `:::` or `foo::bar`.
+    EmptyComponent,
+    /// A path being joined onto another path starts with a separator.
+    NotRelative,
+    /// The basis or dict ends with a separator, which `split_basis_and_dict()`
+    /// does not accept.
+    TrailingSeparator,
+    /// A basis, dict or key name is empty.
+    EmptyName(EntryKind),
+    /// A basis, dict or key name is longer than the PDDB can store.
+    NameTooLong(EntryKind),
+    /// A basis, dict or key name contains a NUL or other control character.
+    InvalidCharacter(EntryKind),
+    /// A basis or key name contains a separator.
+    ContainsSeparator(EntryKind),
+}
+
+fn kind_name(kind: EntryKind) -> &'static str {
+    match kind {
+        EntryKind::Basis => "basis",
+        EntryKind::Dict => "dict",
+        EntryKind::Key => "key",
+    }
+}
+
+impl core::fmt::Display for PathError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PathError::EmptyComponent => write!(f, "path contains an empty name"),
+            PathError::NotRelative => write!(f, "joined path must not start with a separator"),
+            PathError::TrailingSeparator => write!(f, "path ends with a separator"),
+            PathError::EmptyName(kind) => write!(f, "{} name is empty", kind_name(*kind)),
+            PathError::NameTooLong(kind) => write!(
+                f,
+                "{} name is longer than {} bytes",
+                kind_name(*kind),
+                max_name_len(*kind)
+            ),
+            PathError::InvalidCharacter(kind) => {
+                write!(f, "{} name contains a control character", kind_name(*kind))
+            }
+            PathError::ContainsSeparator(kind) => {
+                write!(f, "{} name contains a '{}'", kind_name(*kind), MAIN_SEP)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl From<PathError> for std::io::Error {
+    fn from(e: PathError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// The longest name the PDDB can store for the given kind of entry, in bytes.
+pub fn max_name_len(kind: EntryKind) -> usize {
+    match kind {
+        EntryKind::Basis => BASIS_NAME_LEN,
+        EntryKind::Dict => DICT_NAME_LEN,
+        EntryKind::Key => KEY_NAME_LEN,
+    }
+}
+
+/// Check that a name can be stored in the PDDB. Names must not be empty,
+/// must fit in the PDDB's fixed-size name fields, and must not contain
+/// control characters. Basis and key names must not contain a separator,
+/// and the names of nested dicts must not contain an empty component.
+pub fn validate_name(kind: EntryKind, name: &str) -> Result<(), PathError> {
+    if name.is_empty() {
+        return Err(PathError::EmptyName(kind));
+    }
+    if name.len() > max_name_len(kind) {
+        return Err(PathError::NameTooLong(kind));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(PathError::InvalidCharacter(kind));
+    }
+    if kind == EntryKind::Dict {
+        if name.split(MAIN_SEP).any(str::is_empty) {
+            return Err(PathError::EmptyComponent);
+        }
+    } else if name.contains(MAIN_SEP) {
+        return Err(PathError::ContainsSeparator(kind));
+    }
+    Ok(())
+}
+
+/// Check the names in a basis, dict and key triple, as passed to `Pddb::open_key()`.
+pub fn validate_key_path(basis: Option<&str>, dict: &str, key: &str) -> Result<(), PathError> {
+    if let Some(basis) = basis {
+        validate_name(EntryKind::Basis, basis)?;
+    }
+    validate_name(EntryKind::Dict, dict)?;
+    validate_name(EntryKind::Key, key)
+}
+
+/// How names are normalized before they are sent to the PDDB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Normalization {
+    /// Send names exactly as given
+    None,
+    /// Canonical composition, so that `é` typed as one character and as
+    /// `e` plus a combining accent refer to the same name
+    #[default]
+    Nfc,
+    /// Compatibility composition, which also merges characters such as
+    /// `ﬁ` and `fi`
+    Nfkc,
+}
+
+/// Whether the case of names is preserved before they are sent to the PDDB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CasePolicy {
+    #[default]
+    Preserve,
+    /// Convert names to lower case
+    Fold,
+}
+
+/// The rules applied to basis, dict and key names before they are sent to
+/// the PDDB. The default policy normalizes names to NFC and preserves case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NamePolicy {
+    normalization: Normalization,
+    case: CasePolicy,
+}
+
+impl NamePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A policy that sends names exactly as given.
+    pub fn verbatim() -> Self {
+        NamePolicy {
+            normalization: Normalization::None,
+            case: CasePolicy::Preserve,
+        }
+    }
+
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    pub fn case(mut self, case: CasePolicy) -> Self {
+        self.case = case;
+        self
+    }
+
+    /// Apply this policy to a name or path. Separators are never changed,
+    /// so this may be applied to a whole path at once.
+    pub fn apply<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        use unicode_normalization::{is_nfc, is_nfkc, UnicodeNormalization};
+
+        let name = match self.case {
+            CasePolicy::Fold if name.chars().any(|c| c.to_lowercase().ne(Some(c))) => {
+                Cow::Owned(name.to_lowercase())
+            }
+            _ => Cow::Borrowed(name),
+        };
+        match self.normalization {
+            Normalization::Nfc if !is_nfc(&name) => Cow::Owned(name.nfc().collect()),
+            Normalization::Nfkc if !is_nfkc(&name) => Cow::Owned(name.nfkc().collect()),
+            _ => name,
+        }
+    }
+
+    /// Apply this policy to a name, and check that the result can be stored.
+    pub fn apply_and_validate<'a>(
+        &self,
+        kind: EntryKind,
+        name: &'a str,
+    ) -> Result<Cow<'a, str>, PathError> {
+        let name = self.apply(name);
+        validate_name(kind, &name)?;
+        Ok(name)
+    }
+}
+
+/// A set of names in the same place that are distinct in the PDDB, but
+/// that are the same once a `NamePolicy` has been applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameCollision {
+    pub(crate) kind: EntryKind,
+    pub(crate) basis: Option<String>,
+    pub(crate) dict: Option<String>,
+    pub(crate) normalized: String,
+    pub(crate) names: Vec<String>,
+}
+
+impl NameCollision {
+    /// Whether the colliding names are bases, dicts or keys.
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    /// The basis containing the colliding dicts or keys.
+    pub fn basis(&self) -> Option<&str> {
+        self.basis.as_deref()
+    }
+
+    /// The dict containing the colliding keys.
+    pub fn dict(&self) -> Option<&str> {
+        self.dict.as_deref()
+    }
+
+    /// The name every colliding name becomes under the policy.
+    pub fn normalized(&self) -> &str {
+        &self.normalized
+    }
+
+    /// The colliding names, as they are stored in the PDDB.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+}
+
+/// Find the names in `names` that collide under `policy`. Each entry in
+/// the result has the normalized name along with the original names, and
+/// is sorted by the normalized name.
+pub fn find_collisions<'a>(
+    policy: &NamePolicy,
+    names: impl IntoIterator<Item = &'a str>,
+) -> Vec<(String, Vec<String>)> {
+    let mut groups = std::collections::BTreeMap::<String, Vec<String>>::new();
+    for name in names {
+        let group = groups.entry(policy.apply(name).into_owned()).or_default();
+        if !group.iter().any(|n| n == name) {
+            group.push(name.to_owned());
+        }
+    }
+    groups
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .collect()
+}
+
+/// What comes before the dicts and key in a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prefix<'a> {
+    /// No leading separator, so the path is in the default basis
+    None,
+    /// `:`, the list of every basis
+    Root,
+    /// `::`, an explicitly empty basis, which is the default basis
+    Default,
+    /// `:BASIS` or `:BASIS:`
+    Basis(&'a str),
+}
+
+/// A path broken into its prefix and the dicts and key that follow it.
+struct Parts<'a> {
+    prefix: Prefix<'a>,
+    /// The dicts and key, without any trailing separator
+    rest: &'a str,
+    /// Where `rest` starts in the original string
+    start: usize,
+}
+
+impl<'a> Parts<'a> {
+    fn parse(s: &'a str) -> Result<Self, PathError> {
+        let (prefix, start) = match s.strip_prefix(MAIN_SEP) {
+            None => (Prefix::None, 0),
+            Some("") => (Prefix::Root, 1),
+            Some(rest) => match rest.split_once(MAIN_SEP) {
+                Some(("", _)) => (Prefix::Default, 2),
+                Some((basis, _)) => (Prefix::Basis(basis), basis.len() + 2),
+                // `: ` is a dict named " ", while `: :` is a basis
+                None if rest.trim().is_empty() => (Prefix::None, 1),
+                None => (Prefix::Basis(rest), s.len()),
+            },
+        };
+        let rest = &s[start..];
+        // A single trailing separator is allowed, but must follow a name
+        let rest = match rest.strip_suffix(MAIN_SEP) {
+            Some("") => return Err(PathError::EmptyComponent),
+            Some(rest) => rest,
+            None => rest,
+        };
+        if !rest.is_empty() && rest.split(MAIN_SEP).any(str::is_empty) {
+            return Err(PathError::EmptyComponent);
+        }
+        Ok(Parts {
+            prefix,
+            rest,
+            start,
+        })
+    }
+
+    fn names(&self) -> impl Iterator<Item = &'a str> + 'a {
+        let rest = self.rest;
+        rest.split(MAIN_SEP).filter(|_| !rest.is_empty())
+    }
+}
+
+/// A single part of a path, as returned by `PddbPath::components()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component<'a> {
+    /// The root `:`, which contains every basis
+    Root,
+    /// A named basis
+    Basis(&'a str),
+    /// A dict or key name
+    Name(&'a str),
+}
+
+/// A borrowed PDDB path, following the grammar described in the module
+/// documentation. This is to `PddbPathBuf` what `str` is to `String`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct PddbPath {
+    inner: str,
+}
+
+impl PddbPath {
+    /// Parse a path, checking that it is legal.
+    pub fn parse(s: &str) -> Result<&PddbPath, PathError> {
+        Parts::parse(s)?;
+        Ok(PddbPath::new_unchecked(s))
+    }
+
+    fn new_unchecked(s: &str) -> &PddbPath {
+        // SAFETY: `PddbPath` is a transparent wrapper around `str`
+        unsafe { &*(s as *const str as *const PddbPath) }
+    }
+
+    fn parts(&self) -> Parts<'_> {
+        Parts::parse(&self.inner).expect("PddbPath is always valid")
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+
+    /// Check that every name in this path can be stored in the PDDB. The
+    /// path may name either a dict or a key, so everything after the basis
+    /// is checked as a single dict name.
+    pub fn validate(&self) -> Result<(), PathError> {
+        if let Some(basis) = self.basis() {
+            validate_name(EntryKind::Basis, basis)?;
+        }
+        if let Some(dict) = self.as_dict() {
+            validate_name(EntryKind::Dict, dict)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` for `:`, the path that lists every basis.
+    pub fn is_root(&self) -> bool {
+        self.parts().prefix == Prefix::Root
+    }
+
+    /// The basis named by this path, or `None` if the path is in the default basis.
+    pub fn basis(&self) -> Option<&str> {
+        match self.parts().prefix {
+            Prefix::Basis(basis) => Some(basis),
+            _ => None,
+        }
+    }
+
+    /// The dict this path refers to when it is treated as a key. A path
+    /// with a single name has no key, so that name is the dict, unless
+    /// it follows `::`.
+    pub fn dict(&self) -> Option<&str> {
+        let parts = self.parts();
+        match parts.rest.rsplit_once(MAIN_SEP) {
+            Some((dict, _)) => Some(dict),
+            None if parts.rest.is_empty() || parts.prefix == Prefix::Default => None,
+            None => Some(parts.rest),
+        }
+    }
+
+    /// The key this path refers to, which is the last name if there are at
+    /// least two. A single name following `::` is a key in the default basis.
+    pub fn key(&self) -> Option<&str> {
+        let parts = self.parts();
+        match parts.rest.rsplit_once(MAIN_SEP) {
+            Some((_, key)) => Some(key),
+            None if !parts.rest.is_empty() && parts.prefix == Prefix::Default => Some(parts.rest),
+            None => None,
+        }
+    }
+
+    /// The dict this path refers to when it is treated as a directory,
+    /// which is every name after the basis.
+    pub fn as_dict(&self) -> Option<&str> {
+        Some(self.parts().rest).filter(|rest| !rest.is_empty())
+    }
+
+    /// The path without its final component, or `None` for `:` and for
+    /// paths that name the root of the default basis.
+    pub fn parent(&self) -> Option<&PddbPath> {
+        let parts = self.parts();
+        let end = if let Some(index) = parts.rest.rfind(MAIN_SEP) {
+            parts.start + index
+        } else if !parts.rest.is_empty() && parts.prefix == Prefix::None {
+            // `: ` has a separator before its only name, but no basis
+            0
+        } else if !parts.rest.is_empty() {
+            parts.start
+        } else if let Prefix::Basis(_) = parts.prefix {
+            1
+        } else {
+            return None;
+        };
+        Some(PddbPath::new_unchecked(&self.inner[..end]))
+    }
+
+    /// Create a new path with `path` appended. `path` may contain several
+    /// names, but must not start with a separator. Joining onto `:` names
+    /// a basis.
+    pub fn join(&self, path: &str) -> Result<PddbPathBuf, PathError> {
+        if path.starts_with(MAIN_SEP) {
+            return Err(PathError::NotRelative);
+        }
+        if path.is_empty() {
+            return Err(PathError::EmptyComponent);
+        }
+        let joined = if self.inner.is_empty() || self.inner.ends_with(MAIN_SEP) {
+            format!("{}{}", &self.inner, path)
+        } else {
+            format!("{}{}{}", &self.inner, MAIN_SEP, path)
+        };
+        PddbPathBuf::parse(joined)
+    }
+
+    /// The components of this path, starting with the root and basis if
+    /// there are any.
+    pub fn components(&self) -> impl Iterator<Item = Component<'_>> {
+        let parts = self.parts();
+        let prefix = match parts.prefix {
+            Prefix::Root => vec![Component::Root],
+            Prefix::Basis(basis) => vec![Component::Root, Component::Basis(basis)],
+            Prefix::None | Prefix::Default => vec![],
+        };
+        prefix.into_iter().chain(parts.names().map(Component::Name))
+    }
+
+    pub fn to_path_buf(&self) -> PddbPathBuf {
+        PddbPathBuf {
+            inner: self.inner.to_owned(),
+        }
+    }
+}
+
+impl core::fmt::Display for PddbPath {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.inner)
+    }
+}
+
+impl AsRef<str> for PddbPath {
+    fn as_ref(&self) -> &str {
+        &self.inner
+    }
+}
+
+impl ToOwned for PddbPath {
+    type Owned = PddbPathBuf;
+    fn to_owned(&self) -> PddbPathBuf {
+        self.to_path_buf()
+    }
+}
+
+/// An owned PDDB path. This is to `PddbPath` what `String` is to `str`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PddbPathBuf {
+    inner: String,
+}
+
+impl PddbPathBuf {
+    /// Parse a path, checking that it is legal.
+    pub fn parse(s: impl Into<String>) -> Result<Self, PathError> {
+        let inner = s.into();
+        Parts::parse(&inner)?;
+        Ok(PddbPathBuf { inner })
+    }
+
+    pub fn as_path(&self) -> &PddbPath {
+        PddbPath::new_unchecked(&self.inner)
+    }
+
+    /// Append `path` in place. See `PddbPath::join()` for the rules.
+    pub fn push(&mut self, path: &str) -> Result<(), PathError> {
+        *self = self.join(path)?;
+        Ok(())
+    }
+
+    /// Remove the final component. Returns `false` if there was no parent.
+    pub fn pop(&mut self) -> bool {
+        match self.parent().map(|p| p.inner.len()) {
+            Some(len) => {
+                self.inner.truncate(len);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn into_string(self) -> String {
+        self.inner
+    }
+}
+
+impl core::ops::Deref for PddbPathBuf {
+    type Target = PddbPath;
+    fn deref(&self) -> &PddbPath {
+        self.as_path()
+    }
+}
+
+impl core::borrow::Borrow<PddbPath> for PddbPathBuf {
+    fn borrow(&self) -> &PddbPath {
+        self.as_path()
+    }
+}
+
+impl AsRef<PddbPath> for PddbPathBuf {
+    fn as_ref(&self) -> &PddbPath {
+        self.as_path()
+    }
+}
+
+impl core::str::FromStr for PddbPathBuf {
+    type Err = PathError;
+    fn from_str(s: &str) -> Result<Self, PathError> {
+        PddbPathBuf::parse(s)
+    }
+}
+
+impl core::fmt::Display for PddbPathBuf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.inner)
+    }
+}
@@ -1,31 +1,9 @@
-// use std::path::MAIN_SEPARATOR;
-pub const MAIN_SEPARATOR: char = ':';
-use std::io;
-
-pub fn get_path<'a>(s: &'a str, prefix: &'a str) -> Option<&'a str> {
-    // Empty strings are invalid
-    if s.is_empty() {
-        return None;
-    }
-    // The "" prefix indicates the root
-    if prefix.is_empty() {
-        let mut s_iter = s.split(':');
-        let base = s_iter.next();
-        let remainder = s_iter.next();
-        if remainder.is_some() {
-            return None;
-        }
-        return base;
-    }
-    let without_prefix = s.strip_prefix(prefix)?.strip_prefix(':')?;
-    let mut path_split = without_prefix.split(':');
-    let parent = path_split.next();
-    if path_split.next().is_some() {
-        None
-    } else {
-        parent
-    }
-}
+use super::{
+    find_collisions, get_path, split_basis_and_dict, validate_key_path, validate_name, CasePolicy,
+    Component, NamePolicy, Normalization, PathError, PddbPath, PddbPathBuf, BASIS_NAME_LEN,
+    DICT_NAME_LEN, KEY_NAME_LEN,
+};
+use crate::dict::EntryKind;
 
 #[test]
 fn test_split_vectors() {
@@ -46,74 +24,12 @@ fn test_split_vectors() {
         "oneful",
     ];
     let key = "one:four";
-    for v in vectors.iter().filter(|x| get_path(x, key).is_some()) {
-        // print!("Testing \"{}\": ", v);
-        // if let Some(v) = get_path(v, key) {
-        if let Some((root, end)) = v.rsplit_once(':') {
-            println!(">>> {} <<<", end);
-        }
-        // } else {
-        //     println!("NOT a path");
-        // }
-    }
-}
-
-/// Split a path into its constituant Basis and Dict, if the path is legal.
-pub fn split_basis_and_dict<'a, F: Fn() -> Option<&'a str>>(
-    src: &'a str,
-    default: F,
-) -> io::Result<(Option<&'a str>, Option<&'a str>)> {
-    let mut basis = None;
-    let dict;
-    if let Some(src) = src.strip_prefix(MAIN_SEPARATOR) {
-        if let Some((maybe_basis, maybe_dict)) = src.split_once(MAIN_SEPARATOR) {
-            if !maybe_basis.is_empty() {
-                println!("basis is empty");
-                basis = Some(maybe_basis);
-            } else {
-                println!("default basis");
-                basis = default();
-            }
-
-            if maybe_dict.is_empty() {
-                println!("dict is empty");
-                dict = None;
-            } else {
-                println!("Nope, dict not empty");
-                dict = Some(maybe_dict);
-            }
-        } else {
-            if !src.is_empty() {
-                println!("Oh, src is not empty?");
-                basis = Some(src);
-            } else {
-                println!("it's empty?")
-            }
-            dict = None;
-        }
-    } else {
-        if src.is_empty() {
-            println!("Short circuit");
-            return Ok((basis, Some("")));
-        }
-        println!("other thing -- src is {}", src);
-        dict = Some(src);
-    }
-
-    if let Some(basis) = &basis {
-        if basis.ends_with(MAIN_SEPARATOR) {
-            return Err(io::Error::new(io::ErrorKind::Other, "invalid path"));
-        }
-    }
-    if let Some(dict) = &dict {
-        if dict.ends_with(MAIN_SEPARATOR) {
-            return Err(io::Error::new(io::ErrorKind::Other, "invalid path"));
-        }
-    }
-    Ok((basis, dict))
+    let children: Vec<_> = vectors.iter().filter_map(|x| get_path(x, key)).collect();
+    assert_eq!(children, ["five", "two", "three"]);
+    let roots: Vec<_> = vectors.iter().filter_map(|x| get_path(x, "")).collect();
+    assert_eq!(roots, ["one", "two", "onery", "oneful"]);
 }
 
-#[cfg(test)]
 fn default_path<'a>() -> Option<&'a str> {
     Some("{DEFAULT}")
 }
@@ -267,3 +183,397 @@ fn dict_with_two_keys_two_trailing_colons() {
 fn dict_with_two_keys_three_trailing_colons() {
     split_basis_and_dict("foo:bar:::", default_path).unwrap();
 }
+
+fn parse(s: &str) -> &PddbPath {
+    PddbPath::parse(s).unwrap()
+}
+
+/// The basis, dict (as a key), key and dict (as a directory) of a path.
+fn split(s: &str) -> (Option<&str>, Option<&str>, Option<&str>, Option<&str>) {
+    let path = parse(s);
+    (path.basis(), path.dict(), path.key(), path.as_dict())
+}
+
+#[test]
+fn doc_basis() {
+    assert_eq!(split(":Home Wifi"), (Some("Home Wifi"), None, None, None));
+    assert_eq!(split(":.System:"), (Some(".System"), None, None, None));
+}
+
+#[test]
+fn doc_dict_in_default_basis() {
+    assert_eq!(
+        split("wlan.networks"),
+        (None, Some("wlan.networks"), None, Some("wlan.networks"))
+    );
+}
+
+#[test]
+fn doc_dict_or_key_in_default_basis() {
+    assert_eq!(
+        split("wlan.networks:recent"),
+        (
+            None,
+            Some("wlan.networks"),
+            Some("recent"),
+            Some("wlan.networks:recent")
+        )
+    );
+}
+
+#[test]
+fn doc_dict_in_basis() {
+    assert_eq!(
+        split(":.System:wlan.networks"),
+        (
+            Some(".System"),
+            Some("wlan.networks"),
+            None,
+            Some("wlan.networks")
+        )
+    );
+}
+
+#[test]
+fn doc_fully_qualified() {
+    assert_eq!(
+        split(":.System:wlan.networks:recent"),
+        (
+            Some(".System"),
+            Some("wlan.networks"),
+            Some("recent"),
+            Some("wlan.networks:recent")
+        )
+    );
+}
+
+#[test]
+fn doc_root() {
+    let root = parse(":");
+    assert!(root.is_root());
+    assert_eq!(split(":"), (None, None, None, None));
+    assert_eq!(root.components().collect::<Vec<_>>(), [Component::Root]);
+}
+
+#[test]
+fn doc_empty_basis_and_empty_string() {
+    for s in ["::", ""] {
+        assert!(!parse(s).is_root());
+        assert_eq!(split(s), (None, None, None, None));
+        assert_eq!(parse(s).components().count(), 0);
+    }
+}
+
+#[test]
+fn doc_space_names() {
+    assert_eq!(split(": :"), (Some(" "), None, None, None));
+    assert_eq!(split(" "), (None, Some(" "), None, Some(" ")));
+    assert_eq!(split(": "), split(" "));
+    assert_eq!(
+        parse(": ").components().collect::<Vec<_>>(),
+        [Component::Name(" ")]
+    );
+    assert_eq!(parse(": ").parent().map(PddbPath::as_str), Some(""));
+    assert_eq!(split(" : "), (None, Some(" "), Some(" "), Some(" : ")));
+}
+
+#[test]
+fn doc_trailing_colon() {
+    assert_eq!(split("baz:"), (None, Some("baz"), None, Some("baz")));
+    assert_eq!(split("baz:foo:"), split("baz:foo"));
+}
+
+#[test]
+fn doc_illegal() {
+    assert_eq!(PddbPath::parse(":::"), Err(PathError::EmptyComponent));
+    assert_eq!(PddbPath::parse("::::"), Err(PathError::EmptyComponent));
+    assert_eq!(PddbPath::parse("foo::bar"), Err(PathError::EmptyComponent));
+    assert_eq!(PddbPath::parse("foo:bar::"), Err(PathError::EmptyComponent));
+    assert_eq!(
+        PddbPathBuf::parse("::foo::"),
+        Err(PathError::EmptyComponent)
+    );
+}
+
+#[test]
+fn doc_double_colon_key() {
+    assert_eq!(split("::foo"), (None, None, Some("foo"), Some("foo")));
+    assert_eq!(
+        split("::foo:bar"),
+        (None, Some("foo"), Some("bar"), Some("foo:bar"))
+    );
+}
+
+#[test]
+fn doc_nested_dicts_in_basis() {
+    assert_eq!(
+        split(":lorem.ipsum:foo:baz"),
+        (
+            Some("lorem.ipsum"),
+            Some("foo"),
+            Some("baz"),
+            Some("foo:baz")
+        )
+    );
+    assert_eq!(
+        split(":bar:lorem.ipsum:foo:baz"),
+        (
+            Some("bar"),
+            Some("lorem.ipsum:foo"),
+            Some("baz"),
+            Some("lorem.ipsum:foo:baz")
+        )
+    );
+}
+
+#[test]
+fn components() {
+    assert_eq!(
+        parse(":bar:lorem.ipsum:foo:")
+            .components()
+            .collect::<Vec<_>>(),
+        [
+            Component::Root,
+            Component::Basis("bar"),
+            Component::Name("lorem.ipsum"),
+            Component::Name("foo"),
+        ]
+    );
+    assert_eq!(
+        parse("::wlan.networks:recent")
+            .components()
+            .collect::<Vec<_>>(),
+        [Component::Name("wlan.networks"), Component::Name("recent")]
+    );
+}
+
+#[test]
+fn parent() {
+    let parents = |s: &str| {
+        let mut path = parse(s);
+        let mut parents = vec![];
+        while let Some(parent) = path.parent() {
+            parents.push(parent.as_str().to_owned());
+            path = parent;
+        }
+        parents
+    };
+    assert_eq!(
+        parents(":.System:wlan.networks:recent"),
+        [":.System:wlan.networks", ":.System:", ":"]
+    );
+    assert_eq!(parents(":Home Wifi"), [":"]);
+    assert_eq!(parents("baz:foo:"), ["baz", ""]);
+    assert_eq!(parents("::foo:bar"), ["::foo", "::"]);
+    assert!(parents(":").is_empty());
+    assert!(parents("").is_empty());
+}
+
+#[test]
+fn join() {
+    let root = parse(":");
+    let basis = root.join(".System").unwrap();
+    assert_eq!(basis.as_str(), ":.System");
+    assert_eq!(basis.basis(), Some(".System"));
+    let key = basis.join("wlan.networks:recent").unwrap();
+    assert_eq!(key.as_str(), ":.System:wlan.networks:recent");
+    assert_eq!(key.key(), Some("recent"));
+
+    assert_eq!(parse("").join("a").unwrap().as_str(), "a");
+    assert_eq!(parse("::").join("a").unwrap().as_str(), "::a");
+    assert_eq!(parse("baz:").join("a").unwrap().as_str(), "baz:a");
+    assert_eq!(parse(":b:").join("a").unwrap().as_str(), ":b:a");
+
+    assert_eq!(parse("a").join(":b"), Err(PathError::NotRelative));
+    assert_eq!(parse("a").join(""), Err(PathError::EmptyComponent));
+    assert_eq!(parse("a").join("b::c"), Err(PathError::EmptyComponent));
+}
+
+#[test]
+fn path_buf() {
+    let mut path: PddbPathBuf = ":".parse().unwrap();
+    path.push(".System").unwrap();
+    path.push("wlan.networks").unwrap();
+    path.push("recent").unwrap();
+    assert_eq!(path.to_string(), ":.System:wlan.networks:recent");
+    assert!(path.pop());
+    assert_eq!(path.as_path(), parse(":.System:wlan.networks"));
+    assert!(path.pop());
+    assert!(path.pop());
+    assert!(path.is_root());
+    assert!(!path.pop());
+    assert_eq!(parse("a:b").to_owned(), PddbPathBuf::parse("a:b").unwrap());
+}
+
+#[test]
+fn trailing_separator_error() {
+    assert_eq!(
+        split_basis_and_dict(":one:two:", default_path),
+        Err(PathError::TrailingSeparator)
+    );
+    let error: std::io::Error = PathError::TrailingSeparator.into();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn name_limits() {
+    for (kind, max) in [
+        (EntryKind::Basis, BASIS_NAME_LEN),
+        (EntryKind::Dict, DICT_NAME_LEN),
+        (EntryKind::Key, KEY_NAME_LEN),
+    ] {
+        assert_eq!(validate_name(kind, &"x".repeat(max)), Ok(()));
+        assert_eq!(
+            validate_name(kind, &"x".repeat(max + 1)),
+            Err(PathError::NameTooLong(kind))
+        );
+        // The limit is in bytes, not characters
+        assert_eq!(
+            validate_name(kind, &"é".repeat(max / 2 + 1)),
+            Err(PathError::NameTooLong(kind))
+        );
+        assert_eq!(validate_name(kind, ""), Err(PathError::EmptyName(kind)));
+        assert_eq!(
+            validate_name(kind, "a\0b"),
+            Err(PathError::InvalidCharacter(kind))
+        );
+        assert_eq!(
+            validate_name(kind, "tab\there"),
+            Err(PathError::InvalidCharacter(kind))
+        );
+        assert_eq!(validate_name(kind, " "), Ok(()));
+    }
+}
+
+#[test]
+fn name_separators() {
+    assert_eq!(
+        validate_name(EntryKind::Dict, "wlan.networks:recent"),
+        Ok(())
+    );
+    assert_eq!(
+        validate_name(EntryKind::Dict, "wlan.networks:"),
+        Err(PathError::EmptyComponent)
+    );
+    assert_eq!(
+        validate_name(EntryKind::Dict, "a::b"),
+        Err(PathError::EmptyComponent)
+    );
+    assert_eq!(
+        validate_name(EntryKind::Basis, ":.System"),
+        Err(PathError::ContainsSeparator(EntryKind::Basis))
+    );
+    assert_eq!(
+        validate_name(EntryKind::Key, "Home:Wifi"),
+        Err(PathError::ContainsSeparator(EntryKind::Key))
+    );
+}
+
+#[test]
+fn key_path_reports_component() {
+    assert_eq!(validate_key_path(None, "sys.rtc", "tz_offset"), Ok(()));
+    assert_eq!(
+        validate_key_path(Some(""), "sys.rtc", "tz_offset"),
+        Err(PathError::EmptyName(EntryKind::Basis))
+    );
+    assert_eq!(
+        validate_key_path(None, &"d".repeat(4096), "tz_offset"),
+        Err(PathError::NameTooLong(EntryKind::Dict))
+    );
+    assert_eq!(
+        validate_key_path(None, "sys.rtc", ""),
+        Err(PathError::EmptyName(EntryKind::Key))
+    );
+    assert_eq!(
+        PathError::NameTooLong(EntryKind::Key).to_string(),
+        "key name is longer than 95 bytes"
+    );
+}
+
+#[test]
+fn validate_path() {
+    assert_eq!(parse(":.System:wlan.networks:recent").validate(), Ok(()));
+    assert_eq!(parse("").validate(), Ok(()));
+    assert_eq!(parse(":").validate(), Ok(()));
+    let long_basis = format!(":{}:dict", "b".repeat(BASIS_NAME_LEN + 1));
+    assert_eq!(
+        parse(&long_basis).validate(),
+        Err(PathError::NameTooLong(EntryKind::Basis))
+    );
+    assert_eq!(
+        parse("dict\u{7f}").validate(),
+        Err(PathError::InvalidCharacter(EntryKind::Dict))
+    );
+}
+
+const CAFE_NFC: &str = "caf\u{e9}";
+const CAFE_NFD: &str = "cafe\u{301}";
+
+#[test]
+fn default_policy_is_nfc() {
+    let policy = NamePolicy::default();
+    assert_eq!(policy.apply(CAFE_NFD), CAFE_NFC);
+    assert!(matches!(
+        policy.apply(CAFE_NFC),
+        std::borrow::Cow::Borrowed(_)
+    ));
+    assert_eq!(
+        policy.apply("one:\u{1f978}:\u{26ea}"),
+        "one:\u{1f978}:\u{26ea}"
+    );
+    assert_eq!(policy.apply("Wifi"), "Wifi");
+}
+
+#[test]
+fn other_policies() {
+    assert_eq!(NamePolicy::verbatim().apply(CAFE_NFD), CAFE_NFD);
+    assert_eq!(
+        NamePolicy::new()
+            .normalization(Normalization::Nfkc)
+            .apply("\u{fb01}le"),
+        "file"
+    );
+    assert_eq!(NamePolicy::new().apply("\u{fb01}le"), "\u{fb01}le");
+    let folded = NamePolicy::new().case(CasePolicy::Fold);
+    assert_eq!(folded.apply("Home Wifi:CAF\u{c9}"), "home wifi:caf\u{e9}");
+    assert!(matches!(
+        folded.apply("lower"),
+        std::borrow::Cow::Borrowed(_)
+    ));
+}
+
+#[test]
+fn policy_validates_after_applying() {
+    // The decomposed name is six bytes long, and the composed name is five
+    let name = CAFE_NFD.repeat(KEY_NAME_LEN / 6 + 1);
+    assert!(validate_name(EntryKind::Key, &name).is_err());
+    assert!(NamePolicy::new()
+        .apply_and_validate(EntryKind::Key, &name)
+        .is_ok());
+}
+
+#[test]
+fn collisions() {
+    let names = [CAFE_NFC, "Cafe", CAFE_NFD, "cafe", CAFE_NFC];
+    assert_eq!(
+        find_collisions(&NamePolicy::new(), names),
+        [(
+            CAFE_NFC.to_owned(),
+            vec![CAFE_NFC.to_owned(), CAFE_NFD.to_owned()]
+        )]
+    );
+    assert_eq!(
+        find_collisions(&NamePolicy::new().case(CasePolicy::Fold), names),
+        [
+            (
+                "cafe".to_owned(),
+                vec!["Cafe".to_owned(), "cafe".to_owned()]
+            ),
+            (
+                CAFE_NFC.to_owned(),
+                vec![CAFE_NFC.to_owned(), CAFE_NFD.to_owned()]
+            ),
+        ]
+    );
+    assert!(find_collisions(&NamePolicy::verbatim(), names).is_empty());
+}
@@ -0,0 +1,810 @@
+use std::borrow::Cow;
+use std::io;
+
+use crate::capabilities::{Capabilities, Features};
+use crate::dict::EntryKind;
+use crate::glob::Glob;
+use crate::senres::{self, Senres, SenresMut};
+use crate::walk::Walk;
+use crate::{basis, dict, key, path, services, transport, union, xous_error, Opcodes};
+
+/// A connection to the PDDB server. All calls are made directly using
+/// the PDDB message protocol, and errors are reported as `std::io::Error`
+/// so they can be bubbled up alongside regular file operations.
+pub struct Pddb {
+    cid: xous::CID,
+    policy: path::NamePolicy,
+    capabilities: Option<Capabilities>,
+}
+
+impl Pddb {
+    /// Look up the PDDB server in the name server and connect to it, then
+    /// ask the server what it supports.
+    pub fn connect() -> io::Result<Self> {
+        let cid = services::connect(crate::SERVER_NAME_PDDB).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "unable to connect to the PDDB")
+        })?;
+        let mut pddb = Pddb::from_connection(cid);
+        pddb.negotiate();
+        Ok(pddb)
+    }
+
+    /// Use an existing connection to the PDDB server. Nothing is known about
+    /// what the server supports until `negotiate()` is called.
+    pub fn from_connection(cid: xous::CID) -> Self {
+        Pddb {
+            cid,
+            policy: path::NamePolicy::default(),
+            capabilities: None,
+        }
+    }
+
+    /// Ask the server which message versions and features it supports.
+    /// Returns `None` if the server is too old to say, in which case only the
+    /// messages the firmware understands are sent.
+    pub fn negotiate(&mut self) -> Option<&Capabilities> {
+        self.capabilities = Capabilities::query(self.cid);
+        self.capabilities.as_ref()
+    }
+
+    /// What the server said it supports, if it was asked and could answer.
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    /// Returns `true` only if the server said it supports `feature`.
+    fn supports(&self, feature: Features) -> bool {
+        self.capabilities.is_some_and(|c| c.supports(feature))
+    }
+
+    /// A connection that uses names exactly as they are given.
+    pub(crate) fn verbatim(&self) -> Pddb {
+        Pddb {
+            cid: self.cid,
+            policy: path::NamePolicy::verbatim(),
+            capabilities: self.capabilities,
+        }
+    }
+
+    /// Use `policy` for the names passed to this connection. Names are
+    /// normalized to NFC by default.
+    pub fn with_name_policy(mut self, policy: path::NamePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn set_name_policy(&mut self, policy: path::NamePolicy) {
+        self.policy = policy;
+    }
+
+    pub fn name_policy(&self) -> path::NamePolicy {
+        self.policy
+    }
+
+    /// The connection ID used to talk to the server.
+    pub fn cid(&self) -> xous::CID {
+        self.cid
+    }
+
+    /// Returns `true` if the PDDB has been mounted.
+    pub fn is_mounted(&self) -> io::Result<bool> {
+        transport::send_message(
+            self.cid,
+            xous::Message::new_blocking_scalar(Opcodes::IsMounted as usize, 0, 0, 0, 0),
+        )
+        .map(|v| matches!(v, xous::Result::Scalar2(0, _)))
+        .map_err(xous_error)
+    }
+
+    /// Ask the PDDB to mount itself. Returns `true` if the PDDB was mounted
+    /// by the time the call returned. The PDDB can take a while to come up
+    /// after boot, so this may need to be called several times.
+    pub fn try_mount(&self) -> io::Result<bool> {
+        transport::send_message(
+            self.cid,
+            xous::Message::new_blocking_scalar(Opcodes::TryMount as usize, 0, 0, 0, 0),
+        )
+        .map(|v| matches!(v, xous::Result::Scalar2(0, _)))
+        .map_err(xous_error)
+    }
+
+    /// Call `try_mount()` until the PDDB is mounted. Returns the number of
+    /// unsuccessful attempts that were made before the mount succeeded.
+    pub fn wait_mounted(&self) -> io::Result<usize> {
+        let mut attempts = 0;
+        while !self.try_mount()? {
+            attempts += 1;
+        }
+        Ok(attempts)
+    }
+
+    /// Kick off the periodic scrub. This is what runs every interval, to a first order.
+    pub fn scrub(&self) -> io::Result<()> {
+        transport::send_message(
+            self.cid,
+            xous::Message::new_scalar(Opcodes::PeriodicScrub as usize, 0, 0, 0, 0),
+        )
+        .map(|_| ())
+        .map_err(xous_error)
+    }
+
+    /// List every basis that is currently mounted.
+    pub fn list_bases(&self) -> io::Result<basis::BasisList> {
+        basis::BasisList::new(self.cid)
+    }
+
+    /// List every basis the server knows about, including whether it is
+    /// unlocked and where it sits in the union.
+    ///
+    /// This and the other methods that manage bases return `Unsupported`
+    /// if the server doesn't report `Features::BASIS_MANAGEMENT`.
+    pub fn basis_info(&self) -> io::Result<basis::BasisInfoList> {
+        if !self.supports(Features::BASIS_MANAGEMENT) {
+            return Err(unsupported("describe bases"));
+        }
+        basis::BasisInfoList::new(self.cid)
+    }
+
+    /// Create a new basis protected by `password`. The basis is left locked,
+    /// and must be unlocked with `unlock_basis()` before it can be used.
+    pub fn create_basis(&self, name: &str, password: &str) -> io::Result<()> {
+        if !self.supports(Features::BASIS_MANAGEMENT) {
+            return Err(unsupported("create bases"));
+        }
+        let name = self.name(EntryKind::Basis, name)?;
+        let name = &*name;
+        self.request(Opcodes::CreateBasis, *b"CrbQ", "create basis", |writer| {
+            writer.append(name);
+            writer.append(password);
+        })
+        .map(drop)
+    }
+
+    /// Unlock a basis, making it the highest-priority basis in the union.
+    /// By design the PDDB cannot tell a wrong password from a basis that
+    /// does not exist, so both are reported as `PermissionDenied`.
+    pub fn unlock_basis(&self, name: &str, password: &str) -> io::Result<()> {
+        if !self.supports(Features::BASIS_MANAGEMENT) {
+            return Err(unsupported("unlock bases"));
+        }
+        let name = self.name(EntryKind::Basis, name)?;
+        let name = &*name;
+        self.request(Opcodes::UnlockBasis, *b"UnbQ", "unlock basis", |writer| {
+            writer.append(name);
+            writer.append(password);
+        })
+        .map(drop)
+    }
+
+    /// Lock a basis, removing it from the union. Keys that are open in
+    /// the basis can no longer be used. The system basis cannot be locked.
+    pub fn lock_basis(&self, name: &str) -> io::Result<()> {
+        if !self.supports(Features::BASIS_MANAGEMENT) {
+            return Err(unsupported("lock bases"));
+        }
+        let name = self.name(EntryKind::Basis, name)?;
+        let name = &*name;
+        self.request(Opcodes::LockBasis, *b"LkbQ", "lock basis", |writer| {
+            writer.append(name);
+        })
+        .map(drop)
+    }
+
+    /// Delete a basis and everything in it. The basis must be unlocked.
+    /// The system basis cannot be deleted.
+    pub fn delete_basis(&self, name: &str) -> io::Result<()> {
+        if !self.supports(Features::BASIS_MANAGEMENT) {
+            return Err(unsupported("delete bases"));
+        }
+        let name = self.name(EntryKind::Basis, name)?;
+        let name = &*name;
+        self.request(Opcodes::DeleteBasis, *b"DlbQ", "delete basis", |writer| {
+            writer.append(name);
+        })
+        .map(drop)
+    }
+
+    /// List the unlocked bases in union priority order, starting with the
+    /// basis that is consulted first.
+    pub fn basis_order(&self) -> io::Result<Vec<String>> {
+        if !self.supports(Features::BASIS_MANAGEMENT) {
+            return Err(unsupported("get the basis order"));
+        }
+        let reply = self.request(Opcodes::BasisOrder, *b"BorQ", "get basis order", |_| {})?;
+        let reader = reply.reader(*b"BorR").ok_or_else(invalid_response)?;
+        let count = reader.try_get_from::<u32>().or(Err(invalid_response()))?;
+        (0..count)
+            .map(|_| reader.try_get_from::<String>().or(Err(invalid_response())))
+            .collect()
+    }
+
+    /// List the unlocked bases, in union priority order if the server can
+    /// report it.
+    pub(crate) fn unlocked_bases(&self) -> io::Result<Vec<String>> {
+        if self.supports(Features::BASIS_MANAGEMENT) {
+            self.basis_order()
+        } else {
+            Ok(self.list_bases()?.iter().map(str::to_owned).collect())
+        }
+    }
+
+    /// Change the union priority order. `order` lists every unlocked basis,
+    /// starting with the one that should be consulted first. The system
+    /// basis must always come last.
+    pub fn set_basis_order(&self, order: &[&str]) -> io::Result<()> {
+        if !self.supports(Features::BASIS_MANAGEMENT) {
+            return Err(unsupported("set the basis order"));
+        }
+        let order = order
+            .iter()
+            .map(|name| self.name(EntryKind::Basis, name))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.request(
+            Opcodes::SetBasisOrder,
+            *b"SboQ",
+            "set basis order",
+            |writer| {
+                writer.append(order.len() as u32);
+                for name in &order {
+                    writer.append(&**name);
+                }
+            },
+        )
+        .map(drop)
+    }
+
+    /// List the contents of a path. See the `path` module for a description
+    /// of how paths are constructed.
+    pub fn list_path(&self, path: &str) -> io::Result<dict::PathList> {
+        dict::PathList::request(
+            self.cid,
+            &self.policy.apply(path),
+            self.supports(Features::PATH_FLAGS),
+        )
+    }
+
+    /// Find every basis, dict and key matching `pattern`. See the `glob`
+    /// module for the pattern syntax. The name policy is applied to the
+    /// pattern, but not to the names it is matched against.
+    pub fn glob(&self, pattern: &str) -> Glob {
+        let raw = self.verbatim();
+        Glob::new(raw, &self.policy.apply(pattern))
+    }
+
+    /// Recursively list everything below `root`. See `Walk` for the options.
+    pub fn walk(&self, root: &str) -> io::Result<Walk> {
+        let root = path::PddbPathBuf::parse(self.policy.apply(root))?;
+        let raw = self.verbatim();
+        Ok(Walk::new(raw, root))
+    }
+
+    /// List all dicts in the given basis, or in the union basis if `basis` is `None`.
+    /// Nested dicts are listed by their full names, such as `a:b:c`.
+    ///
+    /// Servers that report `Features::PAGED_DICT_LIST` are asked for a page
+    /// at a time. Others send a single page holding at most 255 names, so
+    /// if that page is full this returns an error rather than a list that
+    /// may be missing dicts.
+    pub fn list_dicts(&self, basis: Option<&str>) -> io::Result<Vec<String>> {
+        let basis = self.basis_name(basis)?;
+        let basis = basis.as_deref();
+        if self.supports(Features::PAGED_DICT_LIST) {
+            let mut names = vec![];
+            let mut cursor: Option<String> = None;
+            loop {
+                let page = self.list_dicts_page(basis, cursor.as_deref())?;
+                cursor = page.next_cursor().map(str::to_owned);
+                names.extend(page.into_names());
+                if cursor.is_none() {
+                    return Ok(names);
+                }
+            }
+        }
+
+        let dicts = dict::ListDictRequest::new(basis)?.invoke(self.cid)?;
+        if packed_list_may_be_full(&dicts, path::DICT_NAME_LEN) {
+            return Err(io::Error::other(
+                "unable to list dicts: there are more than the server can send",
+            ));
+        }
+        // The server lists nothing for a basis that isn't there, so check
+        // whether that's what happened.
+        if let Some(basis) = basis.filter(|_| dicts.is_empty()) {
+            if !self.list_bases()?.iter().any(|name| name == basis) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("unable to list dicts: no unlocked basis named {}", basis),
+                ));
+            }
+        }
+        Ok(dicts.into_names())
+    }
+
+    /// List a single page of the dicts in `basis`, with `LsdQ`.
+    fn list_dicts_page(
+        &self,
+        basis: Option<&str>,
+        after: Option<&str>,
+    ) -> io::Result<dict::DictList> {
+        let mut request = senres::Stack::<4096>::new();
+        {
+            let mut writer = request
+                .writer(*b"LsdQ")
+                .ok_or_else(|| io::Error::other("unable to create writer"))?;
+            writer.append(basis);
+            writer.append(after);
+        }
+        request
+            .lend_mut(self.cid, Opcodes::ListDictStd as usize)
+            .or(Err(io::Error::other("unable to list dicts")))?;
+
+        if let Some(reader) = request.reader(*b"LsdE") {
+            let code = key::PddbRetcode::from(
+                reader.try_get_from::<u8>().or(Err(invalid_response()))? as usize,
+            );
+            return Err(io::Error::new(
+                code.into(),
+                format!("unable to list dicts: {}", code.describe()),
+            ));
+        }
+        let reader = request.reader(*b"LsdR").ok_or_else(invalid_response)?;
+        dict::DictList::read_from(&reader)
+    }
+
+    /// Create a dict. If `basis` is `None` the dict is created in the
+    /// most-recently-added basis. A nested dict such as `a:b:c` may be
+    /// created without creating `a` or `a:b` first. Returns `AlreadyExists`
+    /// if the dict is already in that basis.
+    ///
+    /// The firmware doesn't report that, so on servers that don't answer
+    /// the capability query the basis is listed first. This takes more
+    /// than one message and isn't atomic.
+    pub fn create_dict(&self, basis: Option<&str>, dict: &str) -> io::Result<()> {
+        let basis = self.basis_name(basis)?;
+        let basis = basis.as_deref();
+        let dict = self.name(EntryKind::Dict, dict)?;
+        let dict = &*dict;
+        if self.capabilities.is_none() {
+            let target = match basis {
+                Some(basis) => basis.to_owned(),
+                None => self
+                    .list_bases()?
+                    .iter()
+                    .last()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no unlocked basis"))?
+                    .to_owned(),
+            };
+            if self
+                .verbatim()
+                .list_dicts(Some(&target))?
+                .iter()
+                .any(|name| name == dict)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "dict already exists",
+                ));
+            }
+        }
+        self.request(Opcodes::CreateDictStd, *b"CrdQ", "create dict", |writer| {
+            writer.append(basis);
+            writer.append(dict);
+        })
+        .map(drop)
+    }
+
+    /// Delete a dict. A dict that contains keys or nested dicts is only
+    /// deleted if `recursive` is set, in which case everything inside it
+    /// is deleted as well. If `basis` is `None`, the dict is deleted from
+    /// the most-recently-added basis that contains it.
+    pub fn delete_dict(&self, basis: Option<&str>, dict: &str, recursive: bool) -> io::Result<()> {
+        let basis = self.basis_name(basis)?;
+        let basis = basis.as_deref();
+        let dict = self.name(EntryKind::Dict, dict)?;
+        let dict = &*dict;
+        self.request(Opcodes::DeleteDictStd, *b"DldQ", "delete dict", |writer| {
+            writer.append(basis);
+            writer.append(dict);
+            writer.append(recursive);
+        })
+        .map(drop)
+    }
+
+    /// Rename a dict, along with any dicts nested inside it. The dict
+    /// stays in the basis it lives in. Returns `Unsupported` if the server
+    /// doesn't report `Features::RENAME_DICT`.
+    pub fn rename_dict(&self, basis: Option<&str>, dict: &str, to_dict: &str) -> io::Result<()> {
+        if !self.supports(Features::RENAME_DICT) {
+            return Err(unsupported("rename dicts"));
+        }
+        let basis = self.basis_name(basis)?;
+        let basis = basis.as_deref();
+        let dict = self.name(EntryKind::Dict, dict)?;
+        let dict = &*dict;
+        let to_dict = self.name(EntryKind::Dict, to_dict)?;
+        let to_dict = &*to_dict;
+        self.request(Opcodes::RenameDict, *b"RndQ", "rename dict", |writer| {
+            writer.append(basis);
+            writer.append(dict);
+            writer.append(to_dict);
+        })
+        .map(drop)
+    }
+
+    /// List all keys in `dict`, either in the given basis or in the union basis
+    /// if `basis` is `None`. Large dicts are fetched a page at a time.
+    pub fn list_keys(&self, basis: Option<&str>, dict: &str) -> io::Result<key::KeyList> {
+        let mut names = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = self.list_keys_page(basis, dict, cursor.as_deref())?;
+            cursor = page.next_cursor().map(str::to_owned);
+            names.extend(page.into_names());
+            if cursor.is_none() {
+                return Ok(key::KeyList::from_names(names));
+            }
+        }
+    }
+
+    /// List a single page of the keys in `dict`. Pass `None` as `after` to
+    /// get the first page, and `KeyList::next_cursor()` from the previous
+    /// page to get the ones after it.
+    ///
+    /// Servers that don't support paging send every key that fits in the
+    /// original list format on the first page, and nothing after it.
+    pub fn list_keys_page(
+        &self,
+        basis: Option<&str>,
+        dict: &str,
+        after: Option<&str>,
+    ) -> io::Result<key::KeyList> {
+        let basis = self.basis_name(basis)?;
+        let basis = basis.as_deref();
+        let dict = self.name(EntryKind::Dict, dict)?;
+        let dict = &*dict;
+
+        if !self.supports(Features::PAGED_KEY_LIST) {
+            return Self::list_keys_packed(self.cid, basis, dict, after);
+        }
+
+        let mut request = senres::Stack::<4096>::new();
+        {
+            let mut writer = request
+                .writer(*b"LskQ")
+                .ok_or_else(|| io::Error::other("unable to create writer"))?;
+            writer.append(basis);
+            writer.append(dict);
+            writer.append(after);
+        }
+        request
+            .lend_mut(self.cid, Opcodes::ListKeyStd as usize)
+            .or(Err(io::Error::other("unable to list keys")))?;
+
+        if let Some(reader) = request.reader(*b"LskE") {
+            let code = key::PddbRetcode::from(
+                reader.try_get_from::<u8>().or(Err(invalid_response()))? as usize,
+            );
+            return Err(io::Error::new(
+                code.into(),
+                format!("unable to list keys: {}", code.describe()),
+            ));
+        }
+        if let Some(reader) = request.reader(*b"LskR") {
+            return key::KeyList::read_from(&reader);
+        }
+
+        // The server didn't understand the request
+        Self::list_keys_packed(self.cid, basis, dict, after)
+    }
+
+    /// List keys using the original format, which has no way to ask for
+    /// a later page.
+    fn list_keys_packed(
+        cid: xous::CID,
+        basis: Option<&str>,
+        dict: &str,
+        after: Option<&str>,
+    ) -> io::Result<key::KeyList> {
+        if after.is_some() {
+            return Ok(key::KeyList::from_names(vec![]));
+        }
+        key::ListKeyRequest::new(basis, dict)?.invoke(cid)
+    }
+
+    /// Open an existing key for reading and writing.
+    pub fn open_key(&self, basis: Option<&str>, dict: &str, key: &str) -> io::Result<key::Key> {
+        let (basis, dict, key) = self.key_names(basis, dict, key)?;
+        key::Key::open_negotiated(
+            self.cid,
+            self.capabilities.as_ref(),
+            basis.as_deref(),
+            &dict,
+            &key,
+            key::KeyOptions::new().read(true).write(true),
+        )
+    }
+
+    /// Get information about the key, dict or basis at `path`. When a
+    /// path names both a key and a dict, the key is described. Servers that
+    /// don't report `Features::METADATA` only give the kind of entry, and
+    /// the other fields are left empty.
+    pub fn stat(&self, path: &str) -> io::Result<key::Metadata> {
+        let path = self.policy.apply(path);
+        crate::path::PddbPath::parse(&path)?.validate()?;
+        let reply = self.request(Opcodes::StatPathStd, *b"StaQ", "stat path", |writer| {
+            writer.append(&*path)
+        })?;
+        let reader = reply.reader(*b"StaR").ok_or_else(invalid_response)?;
+        let kind = match reader.try_get_from::<u8>() {
+            Ok(0) => dict::EntryKind::Basis,
+            Ok(1) => dict::EntryKind::Dict,
+            Ok(2) => dict::EntryKind::Key,
+            _ => return Err(invalid_response()),
+        };
+        if self.supports(Features::METADATA) {
+            key::Metadata::read_from(kind, &reader)
+        } else {
+            Ok(key::Metadata::kind_only(kind))
+        }
+    }
+
+    /// Delete a key. If `basis` is `None`, the key is deleted from the
+    /// basis it would be read from.
+    pub fn delete_key(&self, basis: Option<&str>, dict: &str, key: &str) -> io::Result<()> {
+        let (basis, dict, key) = self.key_names(basis, dict, key)?;
+        let (basis, dict, key) = (basis.as_deref(), &*dict, &*key);
+        self.request(Opcodes::DeleteKeyStd, *b"DlkQ", "delete key", |writer| {
+            writer.append(basis);
+            writer.append(dict);
+            writer.append(key);
+        })
+        .map(drop)
+    }
+
+    /// Work out which basis a read, write or create of a key would use,
+    /// without opening the key. This checks each basis in the union in turn,
+    /// so it returns `Unsupported` if the server doesn't report
+    /// `Features::BASIS_MANAGEMENT`.
+    pub fn resolve_key(
+        &self,
+        basis: Option<&str>,
+        dict: &str,
+        key: &str,
+    ) -> io::Result<union::Resolution> {
+        let (basis, dict, key) = self.key_names(basis, dict, key)?;
+        let (basis, dict, key) = (basis.as_deref(), &*dict, &*key);
+        let resolver = union::UnionResolver::new(self.basis_order()?);
+        if let Some(basis) = basis {
+            if !resolver.order().iter().any(|b| b == basis) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "basis is locked or does not exist",
+                ));
+            }
+        }
+
+        let mut contains = vec![];
+        for name in resolver.order() {
+            if basis.is_some_and(|b| b != name) {
+                continue;
+            }
+            match self.stat(&format!(":{}:{}:{}", name, dict, key)) {
+                Ok(metadata) if metadata.is_key() => contains.push(name.as_str()),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(resolver.resolve(basis, |b| contains.contains(&b)))
+    }
+
+    /// Move a key to a new name, which may be in a different dict. If
+    /// `to_basis` is `None` the key stays in the basis it lives in, otherwise
+    /// it is moved to `to_basis`. The target dict must already exist in
+    /// one of the unlocked bases, and the target key must not exist.
+    /// Returns `Unsupported` if the server doesn't report
+    /// `Features::RENAME_COPY_KEY`.
+    pub fn rename_key(
+        &self,
+        basis: Option<&str>,
+        dict: &str,
+        key: &str,
+        to_basis: Option<&str>,
+        to_dict: &str,
+        to_key: &str,
+    ) -> io::Result<()> {
+        let (basis, dict, key) = self.key_names(basis, dict, key)?;
+        let (basis, dict, key) = (basis.as_deref(), &*dict, &*key);
+        let (to_basis, to_dict, to_key) = self.key_names(to_basis, to_dict, to_key)?;
+        let (to_basis, to_dict, to_key) = (to_basis.as_deref(), &*to_dict, &*to_key);
+        if !self.supports(Features::RENAME_COPY_KEY) {
+            return Err(unsupported("rename keys"));
+        }
+        self.request(Opcodes::RenameKey, *b"RnkQ", "rename key", |writer| {
+            writer.append(basis);
+            writer.append(dict);
+            writer.append(key);
+            writer.append(to_basis);
+            writer.append(to_dict);
+            writer.append(to_key);
+        })
+        .map(drop)
+    }
+
+    /// Copy a key to a new name. The rules for the target are the same as
+    /// for `rename_key()`.
+    pub fn copy_key(
+        &self,
+        basis: Option<&str>,
+        dict: &str,
+        key: &str,
+        to_basis: Option<&str>,
+        to_dict: &str,
+        to_key: &str,
+    ) -> io::Result<()> {
+        let (basis, dict, key) = self.key_names(basis, dict, key)?;
+        let (basis, dict, key) = (basis.as_deref(), &*dict, &*key);
+        let (to_basis, to_dict, to_key) = self.key_names(to_basis, to_dict, to_key)?;
+        let (to_basis, to_dict, to_key) = (to_basis.as_deref(), &*to_dict, &*to_key);
+        if !self.supports(Features::RENAME_COPY_KEY) {
+            return Err(unsupported("copy keys"));
+        }
+        self.request(Opcodes::CopyKey, *b"CpkQ", "copy key", |writer| {
+            writer.append(basis);
+            writer.append(dict);
+            writer.append(key);
+            writer.append(to_basis);
+            writer.append(to_dict);
+            writer.append(to_key);
+        })
+        .map(drop)
+    }
+
+    /// Find names that are distinct in the PDDB but become the same under
+    /// `policy`, such as keys that only differ in their Unicode normalization.
+    /// Bases are compared with each other, dicts with the other dicts in
+    /// their basis, and keys with the other keys in their dict. Only
+    /// unlocked bases are checked.
+    pub fn name_collisions(
+        &self,
+        policy: &path::NamePolicy,
+    ) -> io::Result<Vec<path::NameCollision>> {
+        // Names must be looked up exactly as they are stored
+        let raw = self.verbatim();
+        let collision = |kind, basis: Option<&str>, dict: Option<&str>, (normalized, names)| {
+            path::NameCollision {
+                kind,
+                basis: basis.map(str::to_owned),
+                dict: dict.map(str::to_owned),
+                normalized,
+                names,
+            }
+        };
+
+        let bases = raw.unlocked_bases()?;
+        let mut collisions: Vec<_> =
+            path::find_collisions(policy, bases.iter().map(String::as_str))
+                .into_iter()
+                .map(|c| collision(EntryKind::Basis, None, None, c))
+                .collect();
+        for basis in &bases {
+            let dicts = raw.list_dicts(Some(basis))?;
+            collisions.extend(
+                path::find_collisions(policy, dicts.iter().map(String::as_str))
+                    .into_iter()
+                    .map(|c| collision(EntryKind::Dict, Some(basis), None, c)),
+            );
+            for dict in &dicts {
+                let keys = raw.list_keys(Some(basis), dict)?;
+                collisions.extend(
+                    path::find_collisions(policy, keys.iter())
+                        .into_iter()
+                        .map(|c| collision(EntryKind::Key, Some(basis), Some(dict), c)),
+                );
+            }
+        }
+        Ok(collisions)
+    }
+
+    /// Apply the name policy to `name`, and check that the result can be stored.
+    fn name<'a>(&self, kind: EntryKind, name: &'a str) -> io::Result<Cow<'a, str>> {
+        Ok(self.policy.apply_and_validate(kind, name)?)
+    }
+
+    fn basis_name<'a>(&self, basis: Option<&'a str>) -> io::Result<Option<Cow<'a, str>>> {
+        basis.map(|b| self.name(EntryKind::Basis, b)).transpose()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn key_names<'a>(
+        &self,
+        basis: Option<&'a str>,
+        dict: &'a str,
+        key: &'a str,
+    ) -> io::Result<(Option<Cow<'a, str>>, Cow<'a, str>, Cow<'a, str>)> {
+        Ok((
+            self.basis_name(basis)?,
+            self.name(EntryKind::Dict, dict)?,
+            self.name(EntryKind::Key, key)?,
+        ))
+    }
+
+    /// Send a request tagged with `fourcc`. The server replies using the
+    /// same fourcc ending in `R` on success, or ending in `E` followed by
+    /// a `PddbRetcode` on failure. The firmware returns requests that only
+    /// have a status without rewriting them when they succeed, so anything
+    /// other than an `E` reply is success, and callers that expect data
+    /// check for the `R` reply themselves.
+    fn request(
+        &self,
+        opcode: Opcodes,
+        fourcc: [u8; 4],
+        what: &str,
+        write: impl FnOnce(&mut senres::Writer<'_, senres::Stack>),
+    ) -> io::Result<senres::Stack> {
+        let mut request = senres::Stack::<4096>::new();
+
+        {
+            let mut writer = request
+                .writer(fourcc)
+                .ok_or_else(|| io::Error::other("unable to create writer"))?;
+            write(&mut writer);
+        }
+
+        request
+            .lend_mut(self.cid, opcode as usize)
+            .or(Err(io::Error::other(format!("unable to {}", what))))?;
+
+        let [a, b, c, _] = fourcc;
+        if let Some(reader) = request.reader([a, b, c, b'E']) {
+            let code = key::PddbRetcode::from(
+                reader.try_get_from::<u8>().or(Err(invalid_response()))? as usize,
+            );
+            return Err(io::Error::new(
+                code.into(),
+                format!("unable to {}: {}", what, code.describe()),
+            ));
+        }
+        Ok(request)
+    }
+
+    /// Open a key using the specified options.
+    pub fn open_key_with(
+        &self,
+        basis: Option<&str>,
+        dict: &str,
+        key: &str,
+        opts: &key::KeyOptions,
+    ) -> io::Result<key::Key> {
+        let (basis, dict, key) = self.key_names(basis, dict, key)?;
+        key::Key::open_negotiated(
+            self.cid,
+            self.capabilities.as_ref(),
+            basis.as_deref(),
+            &dict,
+            &key,
+            opts,
+        )
+    }
+}
+
+/// Whether a list in the original packed layout may have been cut short.
+/// Its count is a single byte and it has to fit in one page, so a server
+/// that runs out of room just stops. The list may be complete, but
+/// there's no telling once it holds 255 names, or once another name of
+/// `max_name_len` bytes wouldn't have fit.
+fn packed_list_may_be_full(list: &key::KeyList, max_name_len: usize) -> bool {
+    let used = 5 + list.iter().map(|name| 1 + name.len()).sum::<usize>();
+    list.len() >= u8::MAX as usize || used + 1 + max_name_len > 4096
+}
+
+fn invalid_response() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid response from server")
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("the server cannot {}", what),
+    )
+}
@@ -1,4 +1,5 @@
 #![allow(unused)]
+#![allow(clippy::result_unit_err, clippy::len_without_is_empty)]
 use core::cell::Cell;
 use core::convert::TryInto;
 
@@ -180,7 +181,7 @@ pub trait Senres {
         true
     }
 
-    fn reader(&self, fourcc: [u8; 4]) -> Option<Reader<Self>>
+    fn reader(&self, fourcc: [u8; 4]) -> Option<Reader<'_, Self>>
     where
         Self: core::marker::Sized,
     {
@@ -199,8 +200,16 @@ pub trait Senres {
     }
 
     #[cfg(not(target_os = "xous"))]
-    fn lend(&self, _connection: u32, _opcode: usize) -> Result<(), ()> {
-        Ok(())
+    fn lend(&self, connection: u32, opcode: usize) -> Result<(), ()> {
+        let memory_range =
+            unsafe { xous::MemoryRange::new(self.as_ptr() as usize, self.len()).or(Err(()))? };
+        match crate::transport::send_message(
+            connection,
+            xous::Message::new_lend(opcode, memory_range, None, None),
+        ) {
+            Ok(xous::Result::MemoryReturned(_, _)) => Ok(()),
+            _ => Err(()),
+        }
     }
 
     #[cfg(target_os = "xous")]
@@ -239,7 +248,7 @@ pub trait Senres {
 pub trait SenresMut: Senres {
     fn as_mut_slice(&mut self) -> &mut [u8];
     fn as_mut_ptr(&mut self) -> *mut u8;
-    fn writer(&mut self, fourcc: [u8; 4]) -> Option<Writer<Self>>
+    fn writer(&mut self, fourcc: [u8; 4]) -> Option<Writer<'_, Self>>
     where
         Self: core::marker::Sized,
     {
@@ -255,8 +264,19 @@ pub trait SenresMut: Senres {
         Some(writer)
     }
     #[cfg(not(target_os = "xous"))]
-    fn lend_mut(&mut self, _connection: u32, _opcode: usize) -> Result<(usize, usize), ()> {
-        Ok((0, 0))
+    fn lend_mut(&mut self, connection: u32, opcode: usize) -> Result<(usize, usize), ()> {
+        let memory_range =
+            unsafe { xous::MemoryRange::new(self.as_mut_ptr() as usize, self.len()).or(Err(()))? };
+        match crate::transport::send_message(
+            connection,
+            xous::Message::new_lend_mut(opcode, memory_range, None, None),
+        ) {
+            Ok(xous::Result::MemoryReturned(offset, valid)) => Ok((
+                offset.map(|v| v.get()).unwrap_or_default(),
+                valid.map(|v| v.get()).unwrap_or_default(),
+            )),
+            _ => Err(()),
+        }
     }
     #[cfg(target_os = "xous")]
     fn lend_mut(&mut self, connection: u32, opcode: usize) -> Result<(usize, usize), ()> {
@@ -294,7 +314,6 @@ pub trait SenresMut: Senres {
     }
 }
 
-
 pub struct Writer<'a, Backing: SenresMut> {
     backing: &'a mut Backing,
     offset: usize,
@@ -402,7 +421,7 @@ impl<'a, Backing: Senres> Reader<'a, Backing> {
         T::try_get_from(self)
     }
 
-    pub fn try_get_ref_from<T: RecDesRef<'a, Backing> + ?Sized>(&'a self) -> Result<&T, ()> {
+    pub fn try_get_ref_from<T: RecDesRef<'a, Backing> + ?Sized>(&'a self) -> Result<&'a T, ()> {
         T::try_get_ref_from(self)
     }
 
@@ -554,7 +573,6 @@ impl<T: RecDes<Backing>, Backing: Senres, const N: usize> RecDes<Backing> for [T
         // Using &mut as an assertion of unique "ownership"
         let ptr = &mut output as *mut _ as *mut [T; N];
         let res = unsafe { ptr.read() };
-        core::mem::forget(output);
         Ok(res)
     }
 }
@@ -612,10 +630,7 @@ impl<'a, Backing: Senres> RecDesRef<'a, Backing> for str {
         }
         core::str::from_utf8(&senres.backing.as_slice()[offset..offset + len])
             .or(Err(()))
-            .map(|e| {
-                senres.offset.set(offset + len);
-                e
-            })
+            .inspect(|_| senres.offset.set(offset + len))
     }
 }
 
@@ -40,7 +40,7 @@ mod ns {
 
             // Set the string length to the length of the passed-in String,
             // or the maximum possible length. Which ever is smaller.
-            cr.len = 64usize.min(name.as_bytes().len()) as u32;
+            cr.len = 64usize.min(name.len()) as u32;
 
             // If the string is not valid, set its length to 0.
             if core::str::from_utf8(&cr.name[0..cr.len as usize]).is_err() {
@@ -57,7 +57,7 @@ mod ns {
 
     pub fn connect(name: &str) -> Option<xous::CID> {
         let mut request = ConnectRequest::new(name)?;
-        let ns_cid = super::nameserver();
+        let ns_cid = super::nameserver()?;
         let memory_range = unsafe {
             xous::MemoryRange::new(
                 &mut request as *mut ConnectRequest as usize,
@@ -74,7 +74,7 @@ mod ns {
                 xous::MemoryAddress::new(request.len as usize),
             ),
         )
-        .expect("unable to perform lookup");
+        .ok()?;
         if let xous::Result::MemoryReturned(_, _) = response {
             let response_ptr = &request as *const ConnectRequest as *const u32;
             let result = unsafe { response_ptr.read() };
@@ -105,17 +105,19 @@ mod ns {
     }
 }
 
-pub(crate) fn connect(name: &str) -> Option<xous::CID> {
+/// Look up a server by name and connect to it. Returns `None` if the
+/// server could not be found.
+pub fn connect(name: &str) -> Option<xous::CID> {
     ns::connect(name)
 }
 
-pub(crate) fn nameserver() -> xous::CID {
+pub(crate) fn nameserver() -> Option<xous::CID> {
     let cid = NAMESERVER_CID.load(Ordering::Relaxed);
     if cid != 0 {
-        return cid;
+        return Some(cid);
     }
 
-    let cid = xous::connect(xous::SID::from_bytes(b"xous-name-server").unwrap()).unwrap();
+    let cid = xous::connect(xous::SID::from_bytes(b"xous-name-server")?).ok()?;
     NAMESERVER_CID.store(cid, Ordering::Relaxed);
-    cid
+    Some(cid)
 }
@@ -0,0 +1,84 @@
+//! Message delivery. On Xous, messages are handed to the kernel. On every
+//! other platform, messages for connections handed out by `register()` are
+//! delivered to a server within this process, which allows the client to be
+//! driven by an emulator such as the one in the `mock` module. All other
+//! messages go to the hosted kernel as usual.
+
+#[cfg(target_os = "xous")]
+pub(crate) fn send_message(
+    connection: xous::CID,
+    message: xous::Message,
+) -> Result<xous::Result, xous::Error> {
+    xous::send_message(connection, message)
+}
+
+#[cfg(not(target_os = "xous"))]
+pub(crate) use host::send_message;
+
+#[cfg(not(target_os = "xous"))]
+pub use host::{register, unregister, Server};
+
+#[cfg(not(target_os = "xous"))]
+mod host {
+    use std::sync::{Arc, Mutex};
+
+    /// A server that lives in the current process. Memory messages point
+    /// directly at the caller's buffer, so the server may modify it in
+    /// place exactly as it would on Xous.
+    pub trait Server: Send {
+        /// Handle a single message. For `MutableBorrow` messages the
+        /// `offset` and `valid` fields of the message are returned to the
+        /// caller, and the return value is ignored.
+        fn handle(&mut self, message: &mut xous::Message) -> Result<xous::Result, xous::Error>;
+    }
+
+    type Registered = Arc<Mutex<Box<dyn Server>>>;
+
+    /// Connection IDs for in-process servers start here, well clear of
+    /// anything the hosted kernel will hand out.
+    const FIRST_CID: xous::CID = 0x8000_0000;
+
+    /// Servers indexed by connection ID. Connection IDs are never reused, so
+    /// a stale CID will never reach a newer server.
+    static SERVERS: Mutex<Vec<Option<Registered>>> = Mutex::new(Vec::new());
+
+    /// Register a server, returning the connection ID that can be used to
+    /// send it messages.
+    pub fn register(server: Box<dyn Server>) -> xous::CID {
+        let mut servers = SERVERS.lock().unwrap();
+        servers.push(Some(Arc::new(Mutex::new(server))));
+        FIRST_CID + (servers.len() as xous::CID - 1)
+    }
+
+    /// Remove a server. Any further messages sent to `cid` will fail.
+    pub fn unregister(cid: xous::CID) {
+        let mut servers = SERVERS.lock().unwrap();
+        if let Some(entry) = servers.get_mut(cid.wrapping_sub(FIRST_CID) as usize) {
+            *entry = None;
+        }
+    }
+
+    pub(crate) fn send_message(
+        connection: xous::CID,
+        mut message: xous::Message,
+    ) -> Result<xous::Result, xous::Error> {
+        if connection < FIRST_CID {
+            return xous::send_message(connection, message);
+        }
+        let server = SERVERS
+            .lock()
+            .unwrap()
+            .get((connection - FIRST_CID) as usize)
+            .cloned()
+            .flatten()
+            .ok_or(xous::Error::ServerNotFound)?;
+        let result = server.lock().unwrap().handle(&mut message)?;
+        match message {
+            xous::Message::MutableBorrow(memory) | xous::Message::Borrow(memory) => {
+                Ok(xous::Result::MemoryReturned(memory.offset, memory.valid))
+            }
+            xous::Message::Move(_) | xous::Message::Scalar(_) => Ok(xous::Result::Ok),
+            xous::Message::BlockingScalar(_) => Ok(result),
+        }
+    }
+}
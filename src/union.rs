@@ -0,0 +1,83 @@
+//! Resolution of keys in the union basis.
+//!
+//! These are the rules described in the `path` module:
+//!
+//! * Reads come from the union, with the most-recently-added basis taking precedence.
+//! * Writes go to the most-recently-added basis that contains the key.
+//! * If the key does not exist and it is being created, it is created in the
+//!   most-recently-added basis.
+//!
+//! When a basis is named explicitly, only that basis is considered.
+//!
+//! "Most-recently-added" really means "highest priority", since the union order
+//! can be changed with `Pddb::set_basis_order()`.
+
+#[cfg(test)]
+mod tests;
+
+/// Resolves keys against a fixed union order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnionResolver {
+    order: Vec<String>,
+}
+
+impl UnionResolver {
+    /// Create a resolver for the given union order, starting with the basis
+    /// that is consulted first. This is the order returned by `Pddb::basis_order()`.
+    pub fn new<S: Into<String>>(order: impl IntoIterator<Item = S>) -> Self {
+        UnionResolver {
+            order: order.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// The union order, starting with the basis that is consulted first.
+    pub fn order(&self) -> &[String] {
+        &self.order
+    }
+
+    /// Work out where an operation on a key would land. `basis` restricts
+    /// the search to a single basis, and `contains` reports whether a
+    /// given basis already has the key.
+    pub fn resolve(&self, basis: Option<&str>, contains: impl Fn(&str) -> bool) -> Resolution {
+        let candidates = self
+            .order
+            .iter()
+            .filter(|b| basis.is_none() || basis == Some(b.as_str()));
+        let existing = candidates.clone().find(|b| contains(b)).cloned();
+        let create = existing
+            .clone()
+            .or_else(|| candidates.clone().next().cloned());
+        Resolution { existing, create }
+    }
+}
+
+/// The result of resolving a key, as returned by `UnionResolver::resolve()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolution {
+    existing: Option<String>,
+    create: Option<String>,
+}
+
+impl Resolution {
+    /// The basis a read would come from, or `None` if the key does not exist.
+    pub fn read(&self) -> Option<&str> {
+        self.existing.as_deref()
+    }
+
+    /// The basis a write would go to, or `None` if the key does not exist.
+    /// This is always the basis that a read comes from.
+    pub fn write(&self) -> Option<&str> {
+        self.existing.as_deref()
+    }
+
+    /// The basis a write would go to if the key is created when missing.
+    /// This is `None` only when there is no basis to create it in.
+    pub fn create(&self) -> Option<&str> {
+        self.create.as_deref()
+    }
+
+    /// Returns `true` if the key exists in one of the bases considered.
+    pub fn exists(&self) -> bool {
+        self.existing.is_some()
+    }
+}
@@ -0,0 +1,48 @@
+use super::UnionResolver;
+
+fn resolver() -> UnionResolver {
+    UnionResolver::new(["work", "home", ".System"])
+}
+
+#[test]
+fn read_prefers_highest_priority() {
+    let resolution = resolver().resolve(None, |b| b == "home" || b == ".System");
+    assert_eq!(resolution.read(), Some("home"));
+    assert_eq!(resolution.write(), Some("home"));
+    assert_eq!(resolution.create(), Some("home"));
+    assert!(resolution.exists());
+}
+
+#[test]
+fn missing_key_is_created_in_highest_priority() {
+    let resolution = resolver().resolve(None, |_| false);
+    assert_eq!(resolution.read(), None);
+    assert_eq!(resolution.write(), None);
+    assert_eq!(resolution.create(), Some("work"));
+    assert!(!resolution.exists());
+}
+
+#[test]
+fn named_basis() {
+    let resolution = resolver().resolve(Some(".System"), |b| b == "work");
+    assert_eq!(resolution.read(), None);
+    assert_eq!(resolution.create(), Some(".System"));
+
+    let resolution = resolver().resolve(Some("home"), |b| b == "home" || b == "work");
+    assert_eq!(resolution.read(), Some("home"));
+    assert_eq!(resolution.create(), Some("home"));
+}
+
+#[test]
+fn unknown_basis() {
+    let resolution = resolver().resolve(Some("missing"), |_| true);
+    assert_eq!(resolution.read(), None);
+    assert_eq!(resolution.create(), None);
+}
+
+#[test]
+fn empty_union() {
+    let resolution = UnionResolver::new(Vec::<String>::new()).resolve(None, |_| true);
+    assert_eq!(resolution.read(), None);
+    assert_eq!(resolution.create(), None);
+}
@@ -0,0 +1,212 @@
+//! Recursively listing the contents of the PDDB.
+
+#[cfg(test)]
+mod tests;
+
+use std::cmp::Ordering;
+use std::io;
+
+use crate::path::{PddbPath, PddbPathBuf};
+use crate::Pddb;
+
+/// What a walked entry is. A name in a dict may refer to both a nested
+/// dict and a key at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkKind {
+    Basis,
+    Dict,
+    Key,
+    DictAndKey,
+}
+
+/// An entry returned by `Walk`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkEntry {
+    path: PddbPathBuf,
+    name: String,
+    depth: usize,
+    kind: WalkKind,
+    size: Option<u64>,
+}
+
+impl WalkEntry {
+    /// The full path of this entry, starting with the root of the walk.
+    pub fn path(&self) -> &PddbPath {
+        &self.path
+    }
+
+    /// The name of this entry within its parent.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How far below the root of the walk this entry is. The direct
+    /// children of the root have a depth of 1.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn kind(&self) -> WalkKind {
+        self.kind
+    }
+
+    /// The length of the key, if the server reported it.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    pub fn is_basis(&self) -> bool {
+        self.kind == WalkKind::Basis
+    }
+
+    pub fn is_dict(&self) -> bool {
+        matches!(self.kind, WalkKind::Dict | WalkKind::DictAndKey)
+    }
+
+    pub fn is_key(&self) -> bool {
+        matches!(self.kind, WalkKind::Key | WalkKind::DictAndKey)
+    }
+}
+
+type Filter = Box<dyn FnMut(&WalkEntry) -> bool>;
+type Sorter = Box<dyn FnMut(&WalkEntry, &WalkEntry) -> Ordering>;
+
+/// A depth-first iterator over everything below a path, as returned by
+/// `Pddb::walk()`. The root itself is not returned. Each dict is listed
+/// only once the iterator reaches it, and errors listing a dict are
+/// returned in place of its contents.
+pub struct Walk {
+    pddb: Pddb,
+    root: Option<PddbPathBuf>,
+    max_depth: usize,
+    follow_bases: bool,
+    filter: Option<Filter>,
+    sorter: Option<Sorter>,
+    /// Entries that have been listed but not yet returned, one list per level
+    stack: Vec<std::vec::IntoIter<WalkEntry>>,
+    /// The entry that was returned last, which may need to be descended into
+    descend: Option<WalkEntry>,
+}
+
+impl Walk {
+    /// Start a walk at `root`. Names are listed exactly as they are stored,
+    /// so `pddb` should not apply a name policy.
+    pub(crate) fn new(pddb: Pddb, root: PddbPathBuf) -> Self {
+        Walk {
+            pddb,
+            root: Some(root),
+            max_depth: usize::MAX,
+            follow_bases: true,
+            filter: None,
+            sorter: None,
+            stack: vec![],
+            descend: None,
+        }
+    }
+
+    /// Don't return entries more than `depth` levels below the root. A
+    /// depth of 1 returns only the contents of the root.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Whether to walk into each basis when the root is `:`. If this is
+    /// `false`, walking `:` only lists the bases. Defaults to `true`.
+    pub fn follow_bases(mut self, follow: bool) -> Self {
+        self.follow_bases = follow;
+        self
+    }
+
+    /// Only return entries for which `filter` returns `true`. Dicts that
+    /// are filtered out are not descended into.
+    pub fn filter_entry(mut self, filter: impl FnMut(&WalkEntry) -> bool + 'static) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Sort the contents of each dict with `compare`. Entries are
+    /// otherwise returned in the order the server lists them.
+    pub fn sort_by(
+        mut self,
+        compare: impl FnMut(&WalkEntry, &WalkEntry) -> Ordering + 'static,
+    ) -> Self {
+        self.sorter = Some(Box::new(compare));
+        self
+    }
+
+    /// Sort the contents of each dict by name.
+    pub fn sort_by_name(self) -> Self {
+        self.sort_by(|a, b| a.name.cmp(&b.name))
+    }
+
+    /// List the contents of `path`.
+    fn list(&mut self, path: &PddbPath, depth: usize) -> io::Result<()> {
+        let mut entries: Vec<WalkEntry> = vec![];
+        for entry in self.pddb.list_path(path.as_str())?.iter() {
+            let kind = if entry.is_basis() {
+                WalkKind::Basis
+            } else if entry.is_dict() && entry.is_key() {
+                WalkKind::DictAndKey
+            } else if entry.is_dict() {
+                WalkKind::Dict
+            } else {
+                WalkKind::Key
+            };
+            let child = path.join(entry.name()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid name in listing")
+            })?;
+            entries.push(WalkEntry {
+                path: child,
+                name: entry.name().to_owned(),
+                depth,
+                kind,
+                size: entry.size(),
+            });
+        }
+        if let Some(sorter) = &mut self.sorter {
+            entries.sort_by(|a, b| sorter(a, b));
+        }
+        self.stack.push(entries.into_iter());
+        Ok(())
+    }
+}
+
+impl Iterator for Walk {
+    type Item = io::Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            if self.max_depth > 0 {
+                if let Err(e) = self.list(&root, 1) {
+                    return Some(Err(e));
+                }
+            }
+        }
+        if let Some(entry) = self.descend.take() {
+            let descend = entry.depth < self.max_depth
+                && (entry.is_dict() || (entry.is_basis() && self.follow_bases));
+            if descend {
+                if let Err(e) = self.list(&entry.path, entry.depth + 1) {
+                    return Some(Err(e));
+                }
+            }
+        }
+        loop {
+            let entry = match self.stack.last_mut()?.next() {
+                Some(entry) => entry,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            if let Some(filter) = &mut self.filter {
+                if !filter(&entry) {
+                    continue;
+                }
+            }
+            self.descend = Some(entry.clone());
+            return Some(Ok(entry));
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use super::{WalkEntry, WalkKind};
+use crate::mock::{MockPddb, SYSTEM_BASIS};
+
+fn populated() -> MockPddb {
+    let mock = MockPddb::new();
+    mock.insert_key(None, "sys.rtc", "tz_offset", &[1, 2, 3, 4]);
+    mock.insert_key(None, "wlan.networks", "Home Wifi", b"hunter2");
+    mock.insert_key(None, "wlan.networks", "recent", b"Home Wifi");
+    mock.insert_key(None, "wlan.networks:recent", "Cafe", b"latte");
+    mock
+}
+
+fn summary(walk: super::Walk) -> Vec<(String, usize, WalkKind)> {
+    walk.map(|entry| {
+        let entry: WalkEntry = entry.unwrap();
+        (entry.path().to_string(), entry.depth(), entry.kind())
+    })
+    .collect()
+}
+
+#[test]
+fn walk_union() {
+    let mock = populated();
+    let pddb = mock.connect();
+    assert_eq!(
+        summary(pddb.walk("").unwrap().sort_by_name()),
+        [
+            ("sys.rtc".to_owned(), 1, WalkKind::Dict),
+            ("sys.rtc:tz_offset".to_owned(), 2, WalkKind::Key),
+            ("wlan.networks".to_owned(), 1, WalkKind::Dict),
+            ("wlan.networks:Home Wifi".to_owned(), 2, WalkKind::Key),
+            ("wlan.networks:recent".to_owned(), 2, WalkKind::DictAndKey),
+            ("wlan.networks:recent:Cafe".to_owned(), 3, WalkKind::Key),
+        ]
+    );
+}
+
+#[test]
+fn walk_dict() {
+    let mock = populated();
+    let pddb = mock.connect();
+    let entries: Vec<_> = pddb
+        .walk("wlan.networks")
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(entries.len(), 3);
+    let recent = entries.iter().find(|e| e.name() == "recent").unwrap();
+    assert!(recent.is_dict());
+    assert!(recent.is_key());
+    assert!(!recent.is_basis());
+}
+
+#[test]
+fn walk_bases() {
+    let mock = populated();
+    mock.add_basis("secret");
+    mock.insert_key(Some("secret"), "vault", "bank", b"1234");
+    let pddb = mock.connect();
+
+    assert_eq!(
+        summary(pddb.walk(":").unwrap().follow_bases(false).sort_by_name()),
+        [
+            (":.System".to_owned(), 1, WalkKind::Basis),
+            (":secret".to_owned(), 1, WalkKind::Basis),
+        ]
+    );
+    assert_eq!(
+        summary(pddb.walk(":").unwrap().max_depth(3).sort_by_name())
+            .into_iter()
+            .filter(|(path, ..)| path.starts_with(":secret"))
+            .collect::<Vec<_>>(),
+        [
+            (":secret".to_owned(), 1, WalkKind::Basis),
+            (":secret:vault".to_owned(), 2, WalkKind::Dict),
+            (":secret:vault:bank".to_owned(), 3, WalkKind::Key),
+        ]
+    );
+    assert_eq!(
+        summary(pddb.walk(&format!(":{}", SYSTEM_BASIS)).unwrap()).len(),
+        6
+    );
+}
+
+#[test]
+fn max_depth() {
+    let mock = populated();
+    let pddb = mock.connect();
+    assert_eq!(pddb.walk("").unwrap().max_depth(1).count(), 2);
+    assert_eq!(pddb.walk("").unwrap().max_depth(2).count(), 5);
+    assert_eq!(pddb.walk("").unwrap().max_depth(0).count(), 0);
+
+    // Nothing below the maximum depth is listed
+    let before = mock.message_count();
+    pddb.walk("").unwrap().max_depth(1).for_each(drop);
+    assert_eq!(mock.message_count() - before, 1);
+}
+
+#[test]
+fn filter_prunes_dicts() {
+    let mock = populated();
+    let pddb = mock.connect();
+    let paths: Vec<_> = pddb
+        .walk("")
+        .unwrap()
+        .sort_by_name()
+        .filter_entry(|e| e.name() != "recent")
+        .map(|e| e.unwrap().path().to_string())
+        .collect();
+    assert_eq!(
+        paths,
+        [
+            "sys.rtc",
+            "sys.rtc:tz_offset",
+            "wlan.networks",
+            "wlan.networks:Home Wifi"
+        ]
+    );
+}
+
+#[test]
+fn errors_are_returned() {
+    let mock = populated();
+    let pddb = mock.connect();
+    let mut walk = pddb.walk("nonexistent").unwrap();
+    assert_eq!(
+        walk.next().unwrap().unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+    assert!(walk.next().is_none());
+    assert!(pddb.walk(":::").is_err());
+}